@@ -0,0 +1,63 @@
+#![no_main]
+
+use embedded_io::blocking::{Read, Write};
+use libfuzzer_sys::fuzz_target;
+use openssl::{hash::MessageDigest, pkey::PKey, sha::sha256, sign::Signer};
+use rand::rngs::ThreadRng;
+use sdbtree::BKeyTree;
+use storage::{mem::MemoryStorage, Storage};
+
+const KEY: [u8; 32] = [0; 32];
+
+/// Returns the HMAC-SHA256 of `parts` under `key`.
+fn hmac(key: &[u8], parts: &[&[u8]]) -> Vec<u8> {
+    let key = PKey::hmac(key).unwrap();
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).unwrap();
+    for part in parts {
+        signer.update(part).unwrap();
+    }
+    signer.sign_to_vec().unwrap()
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Persist a valid tree so that the root node decodes, then swap its metadata for the input.
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage(MemoryStorage::new()).unwrap();
+    tree.persist(KEY).unwrap();
+
+    let root_id = tree.root_id();
+    let mut storage = tree.into_storage().ok().unwrap();
+
+    // The root starts with a header of a magic number, a format version, the metadata ID, the
+    // metadata's hash and a MAC of the rest of the header.
+    let mut root = vec![0; storage.size(&root_id).unwrap() as usize];
+    storage
+        .read_handle(&root_id)
+        .unwrap()
+        .read_exact(&mut root)
+        .unwrap();
+    let meta_id = u64::from_le_bytes(root[16..24].try_into().unwrap());
+
+    // The metadata is authenticated, so the input is signed as the tree would sign it, and the
+    // header is pointed at its hash and signed again, to get past all three to the decoding.
+    let mac_key = hmac(&KEY, &[b"sdbtree meta".as_slice()]);
+    let mut meta = data.to_vec();
+    meta.extend(hmac(&mac_key, &[&meta_id.to_le_bytes(), data]));
+    root[24..56].copy_from_slice(&sha256(&meta));
+    let mac_key = hmac(&KEY, &[b"sdbtree root".as_slice()]);
+    let mac = hmac(&mac_key, &[&root_id.to_le_bytes(), &root[..56]]);
+    root[56..88].copy_from_slice(&mac);
+
+    for (id, raw) in [(meta_id, &meta), (root_id, &root)] {
+        storage.replace_handle(&id).unwrap().write_all(raw).unwrap();
+        storage.commit_handle(&id).unwrap();
+    }
+
+    // Decoding arbitrary bytes may fail, but must never panic, nor may using what was decoded.
+    if let Ok(mut tree) =
+        BKeyTree::<ThreadRng, MemoryStorage>::reload_with_storage(root_id, storage, KEY)
+    {
+        let _ = tree.verify();
+        let _ = tree.stats_with_storage();
+    }
+});
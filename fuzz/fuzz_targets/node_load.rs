@@ -0,0 +1,16 @@
+#![no_main]
+
+use crypter::openssl::Aes256Ctr;
+use embedded_io::blocking::Write;
+use libfuzzer_sys::fuzz_target;
+use sdbtree::node::Node;
+use storage::{mem::MemoryStorage, Storage};
+
+fuzz_target!(|data: &[u8]| {
+    let mut storage = MemoryStorage::new();
+    let id = storage.alloc_id().unwrap();
+    storage.write_handle(&id).unwrap().write_all(data).unwrap();
+
+    // Decoding arbitrary bytes may fail, but must never panic.
+    let _ = Node::<32>::load::<Aes256Ctr, _>(id, [0; 32], &mut storage);
+});
@@ -0,0 +1,598 @@
+//! An async counterpart of [`BKeyTree`](crate::BKeyTree), for use from async runtimes.
+
+use crate::{
+    counter::{check_epoch, BoxedCounter, TrustedCounter},
+    error::Error,
+    generation::Generations,
+    node::{Child, Node},
+    utils, BKeyTreeMeta, BlockId, Hash, Key, NodeId, RootHeader, AES256CTR_KEY_SZ, DEFAULT_DEGREE,
+};
+use crypter::{openssl::Aes256Ctr, Crypter};
+use openssl::sha::sha256;
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    mem,
+};
+use storage::{
+    asynch::{dir::AsyncDirectoryStorage, AsyncStorage},
+    dir,
+};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// Like [`BKeyTree`](crate::BKeyTree), but over [`AsyncStorage`], awaiting child loads and
+/// writes instead of blocking on them. It persists to the same format, so either tree can
+/// reload what the other persisted.
+///
+/// Its futures are `Send` so long as the RNG is, which is why it defaults to [`OsRng`] rather
+/// than a thread-local one. A trusted counter is checked and advanced as by the blocking tree,
+/// blocking on it in turn.
+pub struct AsyncBKeyTree<
+    R = OsRng,
+    S = AsyncDirectoryStorage,
+    C = Aes256Ctr,
+    const KEY_SZ: usize = AES256CTR_KEY_SZ,
+> {
+    len: usize,
+    degree: usize,
+    updated: HashSet<NodeId>,
+    updated_blocks: HashSet<BlockId>,
+    in_flight_blocks: HashMap<BlockId, Key<KEY_SZ>>,
+    root: Node<KEY_SZ>,
+    meta_id: u64,
+    // The hash of the metadata as last persisted or reloaded, which the root's header holds.
+    meta_hash: Hash,
+    meta_persisted: bool,
+    // Objects the last persisted tree refers to, but the next one won't.
+    stale: Vec<NodeId>,
+    // The number of persists so far, and the counter that keeps it from going back.
+    epoch: u64,
+    counter: Option<BoxedCounter>,
+    storage: S,
+    rng: R,
+    pd: PhantomData<C>,
+}
+
+impl AsyncBKeyTree<OsRng, AsyncDirectoryStorage, Aes256Ctr, AES256CTR_KEY_SZ> {
+    pub async fn new(path: impl AsRef<str>) -> Result<Self, Error<dir::Error>> {
+        Self::with_degree(path, DEFAULT_DEGREE).await
+    }
+
+    pub async fn reload(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::reload_with_storage(root_id, AsyncDirectoryStorage::new(path.as_ref())?, key).await
+    }
+
+    /// See [`AsyncBKeyTree::reload_with_storage_and_counter`].
+    pub async fn reload_with_counter(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::reload_with_storage_and_counter(
+            root_id,
+            AsyncDirectoryStorage::new(path.as_ref())?,
+            key,
+            counter,
+        )
+        .await
+    }
+
+    pub async fn with_degree(
+        path: impl AsRef<str>,
+        degree: usize,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::with_storage_and_degree(AsyncDirectoryStorage::new(path.as_ref())?, degree).await
+    }
+}
+
+impl<R, S, C, const KEY_SZ: usize> AsyncBKeyTree<R, S, C, KEY_SZ>
+where
+    R: RngCore + CryptoRng + Default + Send,
+    S: AsyncStorage<Id = u64>,
+    C: Crypter,
+{
+    pub async fn with_storage(storage: S) -> Result<Self, Error<S::Error>> {
+        Self::with_storage_and_degree(storage, DEFAULT_DEGREE).await
+    }
+
+    pub async fn with_storage_and_degree(
+        mut storage: S,
+        degree: usize,
+    ) -> Result<Self, Error<S::Error>> {
+        Ok(Self {
+            len: 0,
+            degree,
+            updated: HashSet::new(),
+            updated_blocks: HashSet::new(),
+            in_flight_blocks: HashMap::new(),
+            root: Node::new(storage.alloc_id().await?),
+            meta_id: storage.alloc_id().await?,
+            meta_hash: Hash::default(),
+            meta_persisted: false,
+            stale: vec![],
+            epoch: 0,
+            counter: None,
+            storage,
+            rng: R::default(),
+            pd: PhantomData,
+        })
+    }
+
+    /// Reloads the tree persisted with root `id` under `key`.
+    pub async fn reload_with_storage(
+        id: NodeId,
+        storage: S,
+        key: Key<KEY_SZ>,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::reload_checked(id, storage, key, None).await
+    }
+
+    /// Reloads the tree persisted with root `id` under `key`, checking it against `counter` as
+    /// [`BKeyTree::reload_with_storage_and_counter`](crate::BKeyTree::reload_with_storage_and_counter)
+    /// does.
+    pub async fn reload_with_storage_and_counter(
+        id: NodeId,
+        storage: S,
+        key: Key<KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::reload_checked(id, storage, key, Some(Box::new(counter))).await
+    }
+
+    async fn reload_checked(
+        id: NodeId,
+        mut storage: S,
+        key: Key<KEY_SZ>,
+        mut counter: Option<BoxedCounter>,
+    ) -> Result<Self, Error<S::Error>> {
+        // Load the root node.
+        let (root, header) = Self::load_root(id, key, &mut storage).await?;
+
+        // Load and check the metadata.
+        let meta = Self::load_meta(&header, id, &root, key, &mut storage).await?;
+        if let Some(counter) = &mut counter {
+            check_epoch::<S::Error>(&mut **counter, meta.epoch)?;
+        }
+
+        Ok(Self {
+            len: meta.len,
+            degree: meta.degree,
+            updated: meta.updated,
+            updated_blocks: meta.updated_blocks,
+            in_flight_blocks: meta.in_flight_blocks,
+            root,
+            meta_id: header.meta_id,
+            meta_hash: header.meta_hash,
+            meta_persisted: true,
+            stale: vec![],
+            epoch: meta.epoch,
+            counter,
+            rng: R::default(),
+            storage,
+            pd: PhantomData,
+        })
+    }
+
+    /// Reads object `id` in whole.
+    async fn read_object(id: u64, storage: &mut S) -> Result<Vec<u8>, Error<S::Error>> {
+        let mut raw = vec![];
+        storage
+            .read_handle(&id)
+            .await?
+            .read_to_end(&mut raw)
+            .await
+            .map_err(|_| Error::Read)?;
+        Ok(raw)
+    }
+
+    /// Replaces the contents of object `id` with `raw`.
+    async fn write_object(id: u64, raw: &[u8], storage: &mut S) -> Result<(), Error<S::Error>> {
+        {
+            let mut writer = storage.replace_handle(&id).await?;
+            writer.write_all(raw).await.map_err(|_| Error::Write)?;
+            writer.flush().await.map_err(|_| Error::Write)?;
+        }
+
+        Ok(storage.commit_handle(&id).await?)
+    }
+
+    /// Loads the root node, which is stored after a header tagging the format and holding the
+    /// ID and hash of the metadata object.
+    async fn load_root(
+        id: NodeId,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<(Node<KEY_SZ>, RootHeader), Error<S::Error>> {
+        let raw = Self::read_object(id, storage).await?;
+        let (header, mut raw) = raw
+            .split_at_checked(RootHeader::SIZE as usize)
+            .ok_or(Error::Format(id))?;
+        let header = RootHeader::decode(id, header, key)?;
+        let root = Node::decode::<C, S::Error>(id, key, &mut raw)?;
+        Ok((root, header))
+    }
+
+    async fn persist_root(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        let mut raw = RootHeader {
+            meta_id: self.meta_id,
+            meta_hash: self.meta_hash,
+        }
+        .encode(self.root.id, key)?;
+        raw.extend(self.root.encode::<C, S::Error>(key)?);
+
+        Self::write_object(self.root.id, &raw, &mut self.storage).await?;
+        self.root.persisted = true;
+        self.root.dirty = false;
+        self.root.stored_children = self.root.child_ids();
+
+        Ok(())
+    }
+
+    /// Removes the objects that only the tree before the last persist referred to.
+    async fn remove_stale(&mut self) -> Result<(), Error<S::Error>> {
+        while let Some(id) = self.stale.pop() {
+            self.storage.remove_id(&id).await?;
+            self.storage.dealloc_id(id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Loads the metadata the header of root `root_id` points to, which has to hash to what the
+    /// header holds for it and belong to that root, along with the `root` node itself.
+    async fn load_meta(
+        header: &RootHeader,
+        root_id: NodeId,
+        root: &Node<KEY_SZ>,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<BKeyTreeMeta<KEY_SZ>, Error<S::Error>> {
+        let raw = Self::read_object(header.meta_id, storage).await?;
+        if sha256(&raw) != header.meta_hash {
+            return Err(Error::HashMismatch(header.meta_id));
+        }
+        let meta = BKeyTreeMeta::decode::<C, S::Error>(header.meta_id, &raw, key)?;
+        meta.check_root(root_id, root)?;
+
+        // Without the reference counts, removing stale objects could pull nodes out from under
+        // the tree's forks.
+        if meta.refs.is_some() {
+            return Err(Error::Forked);
+        }
+
+        // Nor does the tree hand what it leaves behind over to a generation.
+        if meta.generations.kept > 0 || !meta.generations.list.is_empty() {
+            return Err(Error::KeepsGenerations);
+        }
+
+        Ok(meta)
+    }
+
+    async fn persist_meta(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        // Like the nodes, the metadata moves to a fresh object rather than overwriting the one
+        // the last persisted root refers to.
+        if self.meta_persisted {
+            let old_id = mem::replace(&mut self.meta_id, self.storage.alloc_id().await?);
+            self.stale.push(old_id);
+        }
+
+        let raw = BKeyTreeMeta::encode::<C, S::Error>(
+            self.root.id,
+            &self.root.hash(),
+            self.len,
+            self.degree,
+            &self.updated,
+            &self.updated_blocks,
+            &self.in_flight_blocks,
+            None,
+            &Generations::default(),
+            self.epoch,
+            self.meta_id,
+            key,
+        )?;
+        Self::write_object(self.meta_id, &raw, &mut self.storage).await?;
+        self.meta_hash = sha256(&raw);
+        self.meta_persisted = true;
+
+        Ok(())
+    }
+
+    pub async fn load(&mut self, id: NodeId, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        // Load the root node.
+        let (root, header) = Self::load_root(id, key, &mut self.storage).await?;
+
+        // Load and check the metadata.
+        let meta = Self::load_meta(&header, id, &root, key, &mut self.storage).await?;
+        if let Some(counter) = &mut self.counter {
+            check_epoch::<S::Error>(&mut **counter, meta.epoch)?;
+        }
+
+        // Update state after the fallible operations.
+        self.root = root;
+        self.meta_id = header.meta_id;
+        self.meta_hash = header.meta_hash;
+        self.meta_persisted = true;
+        self.stale.clear();
+        self.len = meta.len;
+        self.degree = meta.degree;
+        self.updated = meta.updated;
+        self.updated_blocks = meta.updated_blocks;
+        self.in_flight_blocks = meta.in_flight_blocks;
+        self.epoch = meta.epoch;
+
+        Ok(())
+    }
+
+    /// Persists the tree under `key`, in the same crash-safe order as
+    /// [`BKeyTree::persist`](crate::BKeyTree::persist).
+    pub async fn persist(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        // Persist the nodes below the root.
+        self.root
+            .persist_children_async::<C, S>(&mut self.storage, &mut self.stale, &mut self.updated)
+            .await?;
+
+        // Persist the metadata.
+        self.epoch += 1;
+        self.persist_meta(key).await?;
+
+        // The root is what makes the rest reachable, so everything else has to be durable first.
+        self.storage.sync().await?;
+
+        // Persist the root node.
+        self.persist_root(key).await?;
+        self.storage.sync_id(&self.root.id).await?;
+
+        // Only now that the root is durable is the counter advanced.
+        if let Some(counter) = &mut self.counter {
+            counter.advance(self.epoch).map_err(Error::Counter)?;
+        }
+
+        self.remove_stale().await
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn root_id(&self) -> u64 {
+        self.root.id
+    }
+
+    pub fn degree(&self) -> usize {
+        self.degree
+    }
+
+    /// Returns the number of times the tree has been persisted, counting from its creation.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// See [`BKeyTree::set_trusted_counter`](crate::BKeyTree::set_trusted_counter).
+    pub fn set_trusted_counter(
+        &mut self,
+        mut counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<(), Error<S::Error>> {
+        if self.meta_persisted {
+            check_epoch::<S::Error>(&mut counter, self.epoch)?;
+        } else {
+            self.epoch = self.epoch.max(counter.read().map_err(Error::Counter)?);
+        }
+
+        self.counter = Some(Box::new(counter));
+        Ok(())
+    }
+
+    /// Consumes the tree, returning its storage.
+    pub fn into_storage(self) -> S {
+        self.storage
+    }
+
+    pub async fn contains(&mut self, k: &BlockId) -> Result<bool, Error<S::Error>> {
+        Ok(self.get(k).await?.is_some())
+    }
+
+    pub async fn get(&mut self, k: &BlockId) -> Result<Option<&Key<KEY_SZ>>, Error<S::Error>> {
+        Ok(self
+            .root
+            .get_async::<C, S>(k, &mut self.storage)
+            .await?
+            .map(|(idx, node)| &node.vals[idx]))
+    }
+
+    /// Inserts a key without marking any of the nodes touched on the way down as updated.
+    pub async fn insert(
+        &mut self,
+        k: BlockId,
+        v: Key<KEY_SZ>,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.insert_inner(k, v, false).await
+    }
+
+    /// Inserts a key while marking any of the nodes touched on the way down as updated.
+    pub async fn insert_for_update(
+        &mut self,
+        k: BlockId,
+        v: Key<KEY_SZ>,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.insert_inner(k, v, true).await
+    }
+
+    async fn insert_inner(
+        &mut self,
+        k: BlockId,
+        v: Key<KEY_SZ>,
+        for_update: bool,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        if self.root.is_full(self.degree) {
+            let mut new_root = Node::new(self.storage.alloc_id().await?);
+            let new_root_key = utils::generate_key(&mut self.rng);
+
+            if for_update {
+                self.updated.insert(self.root.id);
+                self.updated.insert(new_root.id);
+            }
+
+            mem::swap(&mut self.root, &mut new_root);
+
+            self.root.children.push(Child::Loaded(new_root));
+            self.root.children_keys.push(new_root_key);
+            self.root.children_hashes.push(Hash::default());
+
+            let right_id = self.storage.alloc_id().await?;
+            self.root.split_child(
+                0,
+                self.degree,
+                right_id,
+                for_update,
+                &mut self.rng,
+                &mut self.updated,
+            );
+        }
+
+        let res = self
+            .root
+            .insert_nonfull_async::<C, R, S>(
+                k,
+                v,
+                self.degree,
+                &mut self.storage,
+                for_update,
+                &mut self.rng,
+                &mut self.updated,
+            )
+            .await?;
+
+        if res.is_none() {
+            self.len += 1;
+        }
+
+        Ok(res)
+    }
+
+    pub async fn remove(&mut self, k: &BlockId) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        Ok(self.remove_entry(k).await?.map(|(_, val)| val))
+    }
+
+    pub async fn remove_entry(
+        &mut self,
+        k: &BlockId,
+    ) -> Result<Option<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
+        // We do this to make it easier to mark updated nodes when removing.
+        if !self.contains(k).await? {
+            return Ok(None);
+        }
+
+        let mut merged = vec![];
+        let entry = self
+            .root
+            .remove_async::<C, S>(
+                k,
+                self.degree,
+                &mut self.storage,
+                &mut merged,
+                &mut self.updated,
+            )
+            .await?;
+
+        // The last persisted tree may still refer to a node merged away, so its object is only
+        // removed once a root that doesn't is persisted.
+        for node in merged {
+            if node.persisted {
+                self.stale.push(node.id);
+            } else {
+                self.storage.dealloc_id(node.id).await?;
+            }
+        }
+
+        if let Some(entry) = entry {
+            if !self.root.is_leaf() && self.root.is_empty() {
+                // The last child takes over the root's object, so that the root keeps its ID and
+                // the child's object, which the last persisted tree may refer to, is left alone.
+                let mut root = self.root.children.pop().unwrap().as_option_owned().unwrap();
+                let old_id = mem::replace(&mut root.id, self.root.id);
+                let child_persisted = mem::replace(&mut root.persisted, self.root.persisted);
+                self.root = root;
+
+                if self.updated.remove(&old_id) {
+                    self.updated.insert(self.root.id);
+                }
+                if child_persisted {
+                    self.stale.push(old_id);
+                } else {
+                    self.storage.dealloc_id(old_id).await?;
+                }
+            }
+            self.len -= 1;
+            Ok(Some(entry))
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// See [`KeyManagementScheme::derive`](kms::KeyManagementScheme::derive).
+    pub async fn derive(&mut self, block_id: BlockId) -> Result<Key<KEY_SZ>, Error<S::Error>> {
+        if let Some(key) = self.get(&block_id).await? {
+            return Ok(*key);
+        }
+
+        if let Some(key) = self.in_flight_blocks.get(&block_id) {
+            return Ok(*key);
+        }
+
+        let key = utils::generate_key(&mut self.rng);
+        self.in_flight_blocks.insert(block_id, key);
+
+        Ok(key)
+    }
+
+    /// See [`KeyManagementScheme::update`](kms::KeyManagementScheme::update).
+    pub async fn update(&mut self, block_id: BlockId) -> Result<Key<KEY_SZ>, Error<S::Error>> {
+        let key = self.derive(block_id).await?;
+        self.updated_blocks.insert(block_id);
+        Ok(key)
+    }
+
+    /// See [`KeyManagementScheme::commit`](kms::KeyManagementScheme::commit). Unlike there,
+    /// failures are returned rather than panicking.
+    pub async fn commit(&mut self) -> Result<Vec<BlockId>, Error<S::Error>> {
+        // Add any in-flight blocks that haven't been updated.
+        let inflight_blocks = self
+            .in_flight_blocks
+            .iter()
+            .filter_map(|(k, v)| (!self.updated_blocks.contains(k)).then_some((*k, *v)))
+            .collect::<Vec<_>>();
+
+        for (block, key) in inflight_blocks.into_iter() {
+            self.insert_for_update(block, key).await?;
+        }
+
+        // The nodes holding updated blocks, and those on the paths to them, may not be loaded if
+        // we were reloaded since they were updated, so bring them all in before re-keying any.
+        let updated_blocks = self.updated_blocks.iter().copied().collect::<Vec<_>>();
+        for block in updated_blocks {
+            self.get(&block).await?;
+        }
+        self.root
+            .load_updated_async::<C, S>(&mut self.storage, &self.updated)
+            .await?;
+
+        // This will commit our changes, changing keys as necesssary to updated nodes as blocks.
+        self.root
+            .commit(&mut self.rng, &self.updated, &self.updated_blocks);
+
+        // Clear out our cached updates.
+        self.updated.clear();
+        self.in_flight_blocks.clear();
+        Ok(self.updated_blocks.drain().collect())
+    }
+}
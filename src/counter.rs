@@ -0,0 +1,85 @@
+//! Trusted monotonic counters, which a tree's epoch is checked against on reload so that a
+//! tree whose objects were replaced with ones from an earlier persist is refused.
+
+use crate::error::Error;
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    mem,
+    path::{Path, PathBuf},
+};
+
+/// A counter that only goes up, kept somewhere an attacker with access to the tree's storage
+/// can't roll back, such as a TPM's NV counter or a remote service.
+pub trait TrustedCounter {
+    /// Returns the counter's value.
+    fn read(&mut self) -> io::Result<u64>;
+
+    /// Raises the counter to `value`. A value at or below the counter's leaves it as it is.
+    fn advance(&mut self, value: u64) -> io::Result<()>;
+}
+
+/// A counter a tree holds on to, to check what it loads against and advance as it persists.
+pub type BoxedCounter = Box<dyn TrustedCounter + Send + Sync>;
+
+/// Refuses a tree at `epoch` if it's behind `counter`. Otherwise, brings the counter up to the
+/// tree, which may be ahead if a crash cut off the persist that should have advanced it.
+pub(crate) fn check_epoch<E>(counter: &mut dyn TrustedCounter, epoch: u64) -> Result<(), Error<E>> {
+    let trusted = counter.read().map_err(Error::Counter)?;
+    if epoch < trusted {
+        return Err(Error::Stale(epoch, trusted));
+    }
+
+    counter.advance(epoch).map_err(Error::Counter)
+}
+
+/// A [`TrustedCounter`] kept in a local file, for testing and for deployments without anything
+/// better. It's only as trustworthy as the file, so it should live apart from the tree's storage.
+pub struct FileCounter {
+    path: PathBuf,
+}
+
+impl FileCounter {
+    /// Returns a counter kept at `path`. A missing file reads as zero.
+    pub fn new(path: impl AsRef<Path>) -> Self {
+        Self {
+            path: path.as_ref().to_owned(),
+        }
+    }
+}
+
+impl TrustedCounter for FileCounter {
+    fn read(&mut self) -> io::Result<u64> {
+        let raw = match fs::read(&self.path) {
+            Ok(raw) => raw,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+
+        let raw = <[u8; mem::size_of::<u64>()]>::try_from(raw.as_slice())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "malformed counter file"))?;
+        Ok(u64::from_le_bytes(raw))
+    }
+
+    fn advance(&mut self, value: u64) -> io::Result<()> {
+        if value <= self.read()? {
+            return Ok(());
+        }
+
+        // The new value is written aside and renamed into place, so that a crash can't leave
+        // the counter torn. The rename is only durable once the directory is synced.
+        let tmp = self.path.with_extension("tmp");
+        {
+            let mut file = File::create(&tmp)?;
+            file.write_all(&value.to_le_bytes())?;
+            file.sync_all()?;
+        }
+        fs::rename(&tmp, &self.path)?;
+
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()
+    }
+}
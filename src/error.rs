@@ -23,6 +23,45 @@ pub enum Error<E> {
     #[error("decryption error")]
     Decrypt,
 
+    #[error("object {0} isn't the root of a tree in a supported format")]
+    Format(u64),
+
+    #[error("invariant violated in node {0}: {1}")]
+    Invariant(u64, &'static str),
+
+    #[error("node {0} doesn't match the hash its parent holds for it")]
+    HashMismatch(u64),
+
+    #[error("object {0} doesn't match its authentication code")]
+    MacMismatch(u64),
+
+    #[error("root {0} doesn't match the metadata it points to")]
+    RootMismatch(u64),
+
+    #[error("tree shares its storage with forks")]
+    Forked,
+
+    #[error("tree has changes that aren't persisted")]
+    Unpersisted,
+
+    #[error("tree keeps earlier generations")]
+    KeepsGenerations,
+
+    #[error("block {0} has a key in flight")]
+    InFlight(u64),
+
+    #[error("no generation {0}")]
+    NoGeneration(usize),
+
+    #[error("tree at epoch {0} is behind the trusted counter at {1}")]
+    Stale(u64, u64),
+
+    #[error("trusted counter error")]
+    Counter(#[source] std::io::Error),
+
+    #[error("storage holds back syncs, so a trusted counter can't be kept over it")]
+    DeferredSyncs,
+
     #[error(transparent)]
     Storage(#[from] E),
 
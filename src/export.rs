@@ -0,0 +1,227 @@
+use crate::{
+    node::{Child, Node},
+    NodeId,
+};
+use std::{collections::HashSet, fmt::Write};
+
+/// Formats that the tree structure can be exported to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExportFormat {
+    /// A Graphviz digraph.
+    Dot,
+    /// A JSON object with a flat list of nodes.
+    Json,
+}
+
+/// A node as seen during an export, which may or may not be resident in memory.
+struct Entry<'a, const KEY_SZ: usize> {
+    id: NodeId,
+    depth: usize,
+    node: Option<&'a Node<KEY_SZ>>,
+}
+
+pub(crate) fn export<const KEY_SZ: usize>(
+    root: &Node<KEY_SZ>,
+    degree: usize,
+    updated: &HashSet<NodeId>,
+    format: ExportFormat,
+    reveal_keys: bool,
+) -> String {
+    // Collect the nodes in pre-order without loading anything.
+    let mut entries = vec![];
+    let mut stack = vec![Entry {
+        id: root.id,
+        depth: 0,
+        node: Some(root),
+    }];
+
+    while let Some(entry) = stack.pop() {
+        if let Some(node) = entry.node {
+            for child in node.children.iter().rev() {
+                stack.push(match child {
+                    Child::Loaded(child) => Entry {
+                        id: child.id,
+                        depth: entry.depth + 1,
+                        node: Some(child),
+                    },
+                    Child::Unloaded(id) => Entry {
+                        id: *id,
+                        depth: entry.depth + 1,
+                        node: None,
+                    },
+                });
+            }
+        }
+        entries.push(entry);
+    }
+
+    let capacity = 2 * degree - 1;
+    match format {
+        ExportFormat::Dot => to_dot(root.id, &entries, capacity, updated, reveal_keys),
+        ExportFormat::Json => to_json(root.id, &entries, capacity, updated, reveal_keys),
+    }
+}
+
+fn to_dot<const KEY_SZ: usize>(
+    root_id: NodeId,
+    entries: &[Entry<'_, KEY_SZ>],
+    capacity: usize,
+    updated: &HashSet<NodeId>,
+    reveal_keys: bool,
+) -> String {
+    let mut out = String::new();
+
+    let _ = writeln!(out, "digraph bkeytree {{");
+    let _ = writeln!(out, "  node [shape=record, fontname=monospace];");
+
+    for entry in entries {
+        let mut attrs = vec![];
+
+        let label = match entry.node {
+            Some(node) => {
+                let mut label = format!(
+                    "{{node {} (depth {})|fill {}/{}|blocks {}",
+                    entry.id,
+                    entry.depth,
+                    node.len(),
+                    capacity,
+                    block_range(node)
+                );
+                if reveal_keys {
+                    for (block, key) in node.keys.iter().zip(&node.vals) {
+                        let _ = write!(label, "|{block}: {}", to_hex(key));
+                    }
+                }
+                label.push('}');
+                label
+            }
+            None => {
+                attrs.push("style=dashed".to_string());
+                format!("{{node {} (depth {})|unloaded}}", entry.id, entry.depth)
+            }
+        };
+        attrs.insert(0, format!("label=\"{label}\""));
+
+        if updated.contains(&entry.id) {
+            attrs.push("color=red".to_string());
+        }
+        if entry.id == root_id {
+            attrs.push("penwidth=2".to_string());
+        }
+
+        let _ = writeln!(out, "  n{} [{}];", entry.id, attrs.join(", "));
+
+        if let Some(node) = entry.node {
+            for (i, child) in node.children.iter().enumerate() {
+                let child_id = child_id(child);
+                if reveal_keys {
+                    let _ = writeln!(
+                        out,
+                        "  n{} -> n{} [label=\"{}\"];",
+                        entry.id,
+                        child_id,
+                        to_hex(&node.children_keys[i])
+                    );
+                } else {
+                    let _ = writeln!(out, "  n{} -> n{};", entry.id, child_id);
+                }
+            }
+        }
+    }
+
+    let _ = writeln!(out, "}}");
+
+    out
+}
+
+fn to_json<const KEY_SZ: usize>(
+    root_id: NodeId,
+    entries: &[Entry<'_, KEY_SZ>],
+    capacity: usize,
+    updated: &HashSet<NodeId>,
+    reveal_keys: bool,
+) -> String {
+    let nodes = entries
+        .iter()
+        .map(|entry| {
+            let mut fields = vec![
+                format!("\"id\":{}", entry.id),
+                format!("\"depth\":{}", entry.depth),
+                format!("\"updated\":{}", updated.contains(&entry.id)),
+            ];
+
+            match entry.node {
+                Some(node) => {
+                    fields.push("\"state\":\"loaded\"".to_string());
+                    fields.push(format!("\"len\":{}", node.len()));
+                    fields.push(format!("\"capacity\":{capacity}"));
+                    fields.push(format!(
+                        "\"fill\":{:.3}",
+                        node.len() as f64 / capacity as f64
+                    ));
+                    fields.push(format!(
+                        "\"range\":{}",
+                        match (node.keys.first(), node.keys.last()) {
+                            (Some(min), Some(max)) => format!("[{min},{max}]"),
+                            _ => "null".to_string(),
+                        }
+                    ));
+                    fields.push(format!(
+                        "\"blocks\":{}",
+                        json_list(&node.keys, u64::to_string)
+                    ));
+                    fields.push(format!(
+                        "\"children\":{}",
+                        json_list(&node.children, |child| child_id(child).to_string())
+                    ));
+                    if reveal_keys {
+                        fields.push(format!(
+                            "\"keys\":{}",
+                            json_list(&node.vals, |key| format!("\"{}\"", to_hex(key)))
+                        ));
+                        fields.push(format!(
+                            "\"children_keys\":{}",
+                            json_list(&node.children_keys, |key| format!("\"{}\"", to_hex(key)))
+                        ));
+                    }
+                }
+                None => fields.push("\"state\":\"unloaded\"".to_string()),
+            }
+
+            format!("{{{}}}", fields.join(","))
+        })
+        .collect::<Vec<_>>();
+
+    format!(
+        "{{\"root\":{},\"redacted\":{},\"nodes\":[{}]}}",
+        root_id,
+        !reveal_keys,
+        nodes.join(",")
+    )
+}
+
+fn child_id<const KEY_SZ: usize>(child: &Child<KEY_SZ>) -> NodeId {
+    match child {
+        Child::Loaded(node) => node.id,
+        Child::Unloaded(id) => *id,
+    }
+}
+
+fn block_range<const KEY_SZ: usize>(node: &Node<KEY_SZ>) -> String {
+    match (node.keys.first(), node.keys.last()) {
+        (Some(min), Some(max)) => format!("{min}..={max}"),
+        _ => "none".to_string(),
+    }
+}
+
+fn json_list<T>(items: &[T], f: impl Fn(&T) -> String) -> String {
+    format!("[{}]", items.iter().map(f).collect::<Vec<_>>().join(","))
+}
+
+/// Formats `bytes` as lowercase hex, as keys are shown in exports.
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
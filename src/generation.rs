@@ -0,0 +1,59 @@
+//! Earlier persisted versions of a tree, kept for rollback. See
+//! [`BKeyTree::set_kept_generations`](crate::BKeyTree::set_kept_generations).
+
+use crate::{error::Error, NodeId};
+use std::collections::VecDeque;
+
+/// A tree as it was persisted before being superseded.
+pub(crate) struct Generation {
+    // A copy of the root object, which the tree overwrites in place.
+    pub(crate) root_id: NodeId,
+    // The objects this generation refers to that later ones don't, including its metadata.
+    pub(crate) objects: Vec<NodeId>,
+}
+
+impl Generation {
+    /// Returns every object that only this generation refers to.
+    pub(crate) fn ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        [self.root_id]
+            .into_iter()
+            .chain(self.objects.iter().copied())
+    }
+}
+
+/// The generations a tree keeps, oldest first, along with how many it's meant to keep.
+#[derive(Default)]
+pub(crate) struct Generations {
+    pub(crate) kept: usize,
+    pub(crate) list: VecDeque<Generation>,
+}
+
+impl Generations {
+    pub(crate) fn decode<E>(raw: &[u8]) -> Result<Self, Error<E>> {
+        let (kept, list): (u64, Vec<(NodeId, Vec<NodeId>)>) =
+            bincode::deserialize(raw).map_err(|_| Error::Deserialization)?;
+
+        Ok(Self {
+            kept: usize::try_from(kept).map_err(|_| Error::Deserialization)?,
+            list: list
+                .into_iter()
+                .map(|(root_id, objects)| Generation { root_id, objects })
+                .collect(),
+        })
+    }
+
+    pub(crate) fn encode<E>(&self) -> Result<Vec<u8>, Error<E>> {
+        let list = self
+            .list
+            .iter()
+            .map(|generation| (generation.root_id, &generation.objects))
+            .collect::<Vec<_>>();
+
+        bincode::serialize(&(self.kept as u64, list)).map_err(|_| Error::Serialization)
+    }
+
+    /// Returns every object that only the generations refer to.
+    pub(crate) fn ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.list.iter().flat_map(Generation::ids)
+    }
+}
@@ -0,0 +1,143 @@
+//! Reading trees persisted before roots were tagged with their format, so that they can be
+//! migrated. See [`BKeyTree::migrate_with_storage`](crate::BKeyTree::migrate_with_storage).
+//!
+//! Nodes were then stored as four encrypted fields, without the hashes of their children, and
+//! every persist appended the ID of the metadata object to the root.
+
+use crate::{
+    error::Error,
+    node::{Child, Node},
+    utils, BlockId, Hash, Key, NodeId,
+};
+use crypter::Crypter;
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+use storage::Storage;
+
+// No tree with fewer than 2^64 blocks is deeper than this, so a deeper one must loop back on
+// itself.
+const MAX_DEPTH: usize = 64;
+
+pub(crate) struct LegacyMeta<const KEY_SZ: usize> {
+    pub(crate) meta_id: u64,
+    pub(crate) len: usize,
+    pub(crate) degree: usize,
+    pub(crate) updated: HashSet<NodeId>,
+    pub(crate) updated_blocks: HashSet<BlockId>,
+    pub(crate) in_flight_blocks: HashMap<BlockId, Key<KEY_SZ>>,
+}
+
+/// Reads in the whole tree with root `id`, along with its metadata. The nodes are marked as
+/// persisted and dirty, so that persisting the tree moves each of them to a fresh object.
+pub(crate) fn load<C, S, const KEY_SZ: usize>(
+    id: NodeId,
+    key: Key<KEY_SZ>,
+    storage: &mut S,
+) -> Result<(Node<KEY_SZ>, LegacyMeta<KEY_SZ>), Error<S::Error>>
+where
+    C: Crypter,
+    S: Storage<Id = u64>,
+{
+    let root = load_node::<C, S, KEY_SZ>(id, key, 0, storage)?;
+    let meta = load_meta::<S, KEY_SZ>(id, storage)?;
+    Ok((root, meta))
+}
+
+fn load_node<C, S, const KEY_SZ: usize>(
+    id: NodeId,
+    key: Key<KEY_SZ>,
+    depth: usize,
+    storage: &mut S,
+) -> Result<Node<KEY_SZ>, Error<S::Error>>
+where
+    C: Crypter,
+    S: Storage<Id = u64>,
+{
+    if depth > MAX_DEPTH {
+        return Err(Error::Invariant(id, "tree too deep"));
+    }
+
+    let (keys_raw, vals_raw, children_raw, children_keys_raw) = {
+        let mut reader = storage.read_handle(&id)?;
+        (
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?,
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?,
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?,
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?,
+        )
+    };
+
+    // The hashes of the children are filled in as they're persisted.
+    let children = utils::deserialize_ids(&children_raw)?.len();
+    let children_hashes_raw = utils::serialize_keys(&vec![Hash::default(); children]);
+    let mut node = Node::from_fields(
+        id,
+        &keys_raw,
+        &vals_raw,
+        &children_raw,
+        &children_keys_raw,
+        &children_hashes_raw,
+    )?;
+    node.dirty = true;
+
+    for (child, child_key) in node.children.iter_mut().zip(&node.children_keys) {
+        if let Child::Unloaded(child_id) = *child {
+            *child = Child::Loaded(load_node::<C, S, KEY_SZ>(
+                child_id,
+                *child_key,
+                depth + 1,
+                storage,
+            )?);
+        }
+    }
+
+    Ok(node)
+}
+
+fn load_meta<S, const KEY_SZ: usize>(
+    root_id: NodeId,
+    storage: &mut S,
+) -> Result<LegacyMeta<KEY_SZ>, Error<S::Error>>
+where
+    S: Storage<Id = u64>,
+{
+    // The last ID appended to the root is the current one.
+    let size = storage.size(&root_id)?;
+    let raw = utils::read_bytes::<S>(&mut storage.read_handle(&root_id)?, size)?;
+    let mut tail = &raw[raw.len().saturating_sub(mem::size_of::<u64>())..];
+    let meta_id = utils::take_u64(&mut tail)?;
+
+    let size = storage.size(&meta_id)?;
+    let raw = utils::read_bytes::<S>(&mut storage.read_handle(&meta_id)?, size)?;
+    let mut raw = raw.as_slice();
+
+    let len = utils::take_u64(&mut raw)?;
+    let degree = utils::take_u64(&mut raw)?;
+
+    let updated_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+    let updated = bincode::deserialize(updated_raw).map_err(|_| Error::Deserialization)?;
+
+    let updated_blocks_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+    let updated_blocks =
+        bincode::deserialize(updated_blocks_raw).map_err(|_| Error::Deserialization)?;
+
+    let in_flight_blocks_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+    let in_flight_blocks = utils::deserialize_keys_map(in_flight_blocks_raw)?;
+
+    let len = usize::try_from(len).map_err(|_| Error::Deserialization)?;
+    let degree = usize::try_from(degree)
+        .ok()
+        .filter(|degree| (1..=usize::MAX / 2).contains(degree))
+        .ok_or(Error::Deserialization)?;
+
+    Ok(LegacyMeta {
+        meta_id,
+        len,
+        degree,
+        updated,
+        updated_blocks,
+        in_flight_blocks,
+    })
+}
@@ -1,33 +1,57 @@
+#[cfg(feature = "async")]
+pub mod asynch;
+pub mod counter;
 pub mod error;
+pub mod export;
+mod generation;
+mod legacy;
 pub mod node;
+pub mod proof;
+mod refs;
+pub mod shared;
+pub mod snapshot;
+pub mod stats;
 #[cfg(test)]
 mod test;
 mod utils;
 
 pub use storage; // For re-export
 
+use counter::{check_epoch, BoxedCounter, TrustedCounter};
 use crypter::{openssl::Aes256Ctr, Crypter};
-use embedded_io::{blocking::Seek, SeekFrom};
+use embedded_io::blocking::Write;
 use error::Error;
+use export::ExportFormat;
+use generation::{Generation, Generations};
 use kms::KeyManagementScheme;
 use node::{Child, Node};
+use openssl::sha::sha256;
+use proof::Proof;
 use rand::{rngs::ThreadRng, CryptoRng, RngCore};
+use refs::Refs;
+use snapshot::{Pins, Snapshot};
+use stats::Stats;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{HashMap, HashSet, VecDeque},
     marker::PhantomData,
     mem,
+    ops::RangeBounds,
+    sync::Arc,
 };
 use storage::{
     dir::{self, DirectoryStorage},
     Storage,
 };
+use utils::{Locked, Lru};
 
 const DEFAULT_DEGREE: usize = 2;
+const DEFAULT_LOOKUP_CACHE_CAPACITY: usize = 1024;
 const AES256CTR_KEY_SZ: usize = 32;
 
 pub(crate) type Key<const N: usize> = [u8; N];
 pub(crate) type BlockId = u64;
 pub(crate) type NodeId = u64;
+pub(crate) type Hash = [u8; 32];
 
 pub struct BKeyTree<
     R = ThreadRng,
@@ -42,18 +66,270 @@ pub struct BKeyTree<
     in_flight_blocks: HashMap<BlockId, Key<KEY_SZ>>,
     root: Node<KEY_SZ>,
     meta_id: u64,
-    storage: S,
+    // The hash of the metadata as last persisted or reloaded, which the root's header holds.
+    meta_hash: Hash,
+    meta_persisted: bool,
+    // Objects the last persisted tree refers to, but the next one won't.
+    stale: Vec<NodeId>,
+    // The objects each persist left behind, with the version they were left behind by. They're
+    // kept while a snapshot of that version or an earlier one is alive.
+    retired: VecDeque<(u64, Vec<NodeId>)>,
+    // The number of roots persisted or reloaded so far, and the last one with its length, the
+    // hash of its metadata and its epoch.
+    version: u64,
+    last_persisted: Option<(Arc<Node<KEY_SZ>>, usize, Hash, u64)>,
+    pins: Arc<Pins<KEY_SZ>>,
+    // Earlier persisted versions of the tree, kept for rollback.
+    generations: Generations,
+    // Objects to shred rather than just remove once nothing refers to them.
+    shredding: HashSet<NodeId>,
+    // The number of persists so far, and the counter that keeps it from going back.
+    epoch: u64,
+    counter: Option<BoxedCounter>,
+    // Why the last commit through `KeyManagementScheme::commit()` failed, which it can't return.
+    commit_error: Option<Box<dyn std::error::Error + Send + Sync>>,
+    storage: Arc<Locked<S>>,
+    // The reference counts shared with the tree's forks, once it's been forked.
+    refs: Option<Arc<Locked<Refs>>>,
+    // The children of the objects the tree let go of since it last persisted, once it's been
+    // forked, whose counts are pushed down if a fork still refers to those objects.
+    origins: HashMap<NodeId, Vec<NodeId>>,
+    // Nodes read in by lookups through a shared reference, which can't add them to the tree.
+    lookup_cache: Locked<Lru<NodeId, Arc<Node<KEY_SZ>>>>,
     rng: R,
     pd: PhantomData<C>,
 }
 
 struct BKeyTreeMeta<const KEY_SZ: usize = AES256CTR_KEY_SZ> {
-    meta_id: u64,
+    // The root the metadata belongs to, and the hash of that root's node, which everything
+    // below it is checked against in turn.
+    root_id: NodeId,
+    root_hash: Hash,
     len: usize,
     degree: usize,
     updated: HashSet<NodeId>,
     updated_blocks: HashSet<BlockId>,
     in_flight_blocks: HashMap<BlockId, Key<KEY_SZ>>,
+    // The object holding the reference counts, with the key they're authenticated under.
+    refs: Option<(u64, Hash)>,
+    generations: Generations,
+    epoch: u64,
+}
+
+/// The header at the start of the root object. It tags the format the tree is persisted in, so
+/// that a root in any other format is refused rather than misread, and points to the metadata
+/// along with its hash, so that the root commits to the metadata too. It ends with a MAC of the
+/// rest and the root's ID, so that it can't be pointed at other metadata or moved to another
+/// root.
+struct RootHeader {
+    meta_id: u64,
+    meta_hash: Hash,
+}
+
+impl RootHeader {
+    const MAGIC: u64 = u64::from_le_bytes(*b"sdbtree\0");
+    const VERSION: u64 = 4;
+    const SIZE: u64 = (3 * mem::size_of::<u64>() + 2 * mem::size_of::<Hash>()) as u64;
+
+    /// Returns the key headers are authenticated under, derived from `key` as the metadata's is.
+    fn mac_key<E, const KEY_SZ: usize>(key: Key<KEY_SZ>) -> Result<Hash, Error<E>> {
+        utils::mac(&key, &[b"sdbtree root".as_slice()])
+    }
+
+    fn decode<E, const KEY_SZ: usize>(
+        root_id: NodeId,
+        raw: &[u8],
+        key: Key<KEY_SZ>,
+    ) -> Result<Self, Error<E>> {
+        let (fields, mac) = raw
+            .split_last_chunk::<{ mem::size_of::<Hash>() }>()
+            .ok_or(Error::Format(root_id))?;
+
+        let mut raw = fields;
+        let tagged = utils::take_u64::<E>(&mut raw).ok() == Some(Self::MAGIC)
+            && utils::take_u64::<E>(&mut raw).ok() == Some(Self::VERSION);
+        if !tagged {
+            return Err(Error::Format(root_id));
+        }
+
+        if !utils::verify_mac(&Self::mac_key(key)?, &[&root_id.to_le_bytes(), fields], mac)? {
+            return Err(Error::MacMismatch(root_id));
+        }
+
+        let meta_id = utils::take_u64(&mut raw)?;
+        let meta_hash = raw
+            .first_chunk::<{ mem::size_of::<Hash>() }>()
+            .copied()
+            .ok_or(Error::Format(root_id))?;
+
+        Ok(Self { meta_id, meta_hash })
+    }
+
+    fn encode<E, const KEY_SZ: usize>(
+        &self,
+        root_id: NodeId,
+        key: Key<KEY_SZ>,
+    ) -> Result<Vec<u8>, Error<E>> {
+        let mut raw = [Self::MAGIC, Self::VERSION, self.meta_id]
+            .into_iter()
+            .flat_map(u64::to_le_bytes)
+            .collect::<Vec<_>>();
+        raw.extend(self.meta_hash);
+
+        let mac = utils::mac(&Self::mac_key(key)?, &[&root_id.to_le_bytes(), &raw])?;
+        raw.extend(mac);
+        Ok(raw)
+    }
+}
+
+impl<const KEY_SZ: usize> BKeyTreeMeta<KEY_SZ> {
+    /// Returns the key the metadata is authenticated under, derived from `key` so that the same
+    /// key isn't used both to encrypt and to authenticate.
+    fn mac_key<E>(key: Key<KEY_SZ>) -> Result<Hash, Error<E>> {
+        utils::mac(&key, &[b"sdbtree meta".as_slice()])
+    }
+
+    /// Returns the key the metadata's secrets are encrypted under. The root node is encrypted
+    /// under `key` itself, so using it here too would reuse its keystream.
+    fn secrets_key<E>(key: Key<KEY_SZ>) -> Result<Key<KEY_SZ>, Error<E>> {
+        utils::derive_key(&key, b"sdbtree meta secrets")
+    }
+
+    /// Refuses metadata that belongs to a root other than `root_id`, or a `root` node other than
+    /// the one persisted along with it.
+    fn check_root<E>(&self, root_id: NodeId, root: &Node<KEY_SZ>) -> Result<(), Error<E>> {
+        if self.root_id != root_id {
+            return Err(Error::RootMismatch(root_id));
+        }
+        root.check_hash(&self.root_hash)
+    }
+
+    fn decode<C, E>(meta_id: u64, raw: &[u8], key: Key<KEY_SZ>) -> Result<Self, Error<E>>
+    where
+        C: Crypter,
+    {
+        // The metadata ends with a MAC of the rest, which ties it to the object it's in.
+        let (mut raw, mac) = raw
+            .split_last_chunk::<{ mem::size_of::<Hash>() }>()
+            .ok_or(Error::Deserialization)?;
+        if !utils::verify_mac(&Self::mac_key(key)?, &[&meta_id.to_le_bytes(), raw], mac)? {
+            return Err(Error::MacMismatch(meta_id));
+        }
+
+        let root_id = utils::take_u64(&mut raw)?;
+        let root_hash = utils::take_hash(&mut raw)?;
+        let len = utils::take_u64(&mut raw)?;
+        let degree = utils::take_u64(&mut raw)?;
+
+        let updated_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+        let updated = bincode::deserialize(updated_raw).map_err(|_| Error::Deserialization)?;
+
+        let updated_blocks_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+        let updated_blocks =
+            bincode::deserialize(updated_blocks_raw).map_err(|_| Error::Deserialization)?;
+
+        let in_flight_blocks_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+        let in_flight_blocks = utils::deserialize_keys_map(in_flight_blocks_raw)?;
+
+        let refs_id_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+        let refs_id = match utils::deserialize_ids(refs_id_raw)?[..] {
+            [] => None,
+            [id] => Some(id),
+            _ => return Err(Error::Deserialization),
+        };
+
+        let generations_raw = utils::take_length_prefixed_bytes_clear(&mut raw)?;
+        let generations = Generations::decode(generations_raw)?;
+
+        // The epoch is encrypted, as is the key the reference counts are authenticated under.
+        // Encryption alone doesn't keep the epoch from being bumped to pass off an old tree as
+        // current, but the MAC does.
+        let secrets_raw =
+            utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(&mut raw, Self::secrets_key(key)?)?;
+        let mut secrets = secrets_raw.as_slice();
+        let epoch = utils::take_u64(&mut secrets)?;
+        let refs = match (refs_id, <Hash>::try_from(secrets)) {
+            (Some(id), Ok(refs_key)) => Some((id, refs_key)),
+            (None, _) if secrets.is_empty() => None,
+            _ => return Err(Error::Deserialization),
+        };
+
+        // A degree of zero, or one too large to compute the node capacity from, can't have come
+        // from a tree we persisted.
+        let len = usize::try_from(len).map_err(|_| Error::Deserialization)?;
+        let degree = usize::try_from(degree)
+            .ok()
+            .filter(|degree| (1..=usize::MAX / 2).contains(degree))
+            .ok_or(Error::Deserialization)?;
+
+        Ok(Self {
+            root_id,
+            root_hash,
+            len,
+            degree,
+            updated,
+            updated_blocks,
+            in_flight_blocks,
+            refs,
+            generations,
+            epoch,
+        })
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn encode<C, E>(
+        root_id: NodeId,
+        root_hash: &Hash,
+        len: usize,
+        degree: usize,
+        updated: &HashSet<NodeId>,
+        updated_blocks: &HashSet<BlockId>,
+        in_flight_blocks: &HashMap<BlockId, Key<KEY_SZ>>,
+        refs: Option<(u64, Hash)>,
+        generations: &Generations,
+        epoch: u64,
+        meta_id: u64,
+        key: Key<KEY_SZ>,
+    ) -> Result<Vec<u8>, Error<E>>
+    where
+        C: Crypter,
+    {
+        let mut raw = vec![];
+
+        raw.extend(root_id.to_le_bytes());
+        raw.extend(root_hash);
+        raw.extend((len as u64).to_le_bytes());
+        raw.extend((degree as u64).to_le_bytes());
+
+        let updated_raw = bincode::serialize(updated).map_err(|_| Error::Serialization)?;
+        utils::push_length_prefixed_bytes_clear(&mut raw, &updated_raw);
+
+        let updated_blocks_raw =
+            bincode::serialize(updated_blocks).map_err(|_| Error::Serialization)?;
+        utils::push_length_prefixed_bytes_clear(&mut raw, &updated_blocks_raw);
+
+        let in_flight_blocks_raw = utils::serialize_keys_map(in_flight_blocks);
+        utils::push_length_prefixed_bytes_clear(&mut raw, &in_flight_blocks_raw);
+
+        let refs_id_raw = utils::serialize_ids(refs.map(|(id, _)| id).as_slice());
+        utils::push_length_prefixed_bytes_clear(&mut raw, &refs_id_raw);
+
+        let generations_raw = generations.encode()?;
+        utils::push_length_prefixed_bytes_clear(&mut raw, &generations_raw);
+
+        let mut secrets_raw = epoch.to_le_bytes().to_vec();
+        secrets_raw.extend(refs.iter().flat_map(|(_, refs_key)| refs_key));
+        utils::push_length_prefixed_bytes::<C, E, KEY_SZ>(
+            &mut raw,
+            &secrets_raw,
+            Self::secrets_key(key)?,
+        )?;
+
+        let mac = utils::mac(&Self::mac_key(key)?, &[&meta_id.to_le_bytes(), &raw])?;
+        raw.extend(mac);
+
+        Ok(raw)
+    }
 }
 
 impl BKeyTree<ThreadRng, DirectoryStorage, Aes256Ctr, AES256CTR_KEY_SZ> {
@@ -69,9 +345,63 @@ impl BKeyTree<ThreadRng, DirectoryStorage, Aes256Ctr, AES256CTR_KEY_SZ> {
         Self::reload_with_storage(root_id, DirectoryStorage::new(path.as_ref())?, key)
     }
 
+    /// See [`BKeyTree::reload_with_storage_and_counter`].
+    pub fn reload_with_counter(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::reload_with_storage_and_counter(
+            root_id,
+            DirectoryStorage::new(path.as_ref())?,
+            key,
+            counter,
+        )
+    }
+
+    /// Reloads a tree for inspection only. Other read-only openers may share the directory, but
+    /// anything that would write to storage fails.
+    pub fn reload_read_only(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::reload_with_storage(
+            root_id,
+            DirectoryStorage::open_read_only(path.as_ref())?,
+            key,
+        )
+    }
+
+    /// Reloads a tree for inspection only, as [`BKeyTree::reload_read_only`] does, checking it
+    /// against `counter` as [`BKeyTree::reload_with_storage_and_counter`] does.
+    pub fn reload_read_only_with_counter(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::reload_with_storage_and_counter(
+            root_id,
+            DirectoryStorage::open_read_only(path.as_ref())?,
+            key,
+            counter,
+        )
+    }
+
     pub fn with_degree(path: impl AsRef<str>, degree: usize) -> Result<Self, Error<dir::Error>> {
         Self::with_storage_and_degree(DirectoryStorage::new(path.as_ref())?, degree)
     }
+
+    /// See [`BKeyTree::migrate_with_storage`].
+    pub fn migrate(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+    ) -> Result<Self, Error<dir::Error>> {
+        Self::migrate_with_storage(root_id, DirectoryStorage::new(path.as_ref())?, key)
+    }
 }
 
 impl<R, S, C, const KEY_SZ: usize> BKeyTree<R, S, C, KEY_SZ>
@@ -93,22 +423,67 @@ where
             in_flight_blocks: HashMap::new(),
             root: Node::new(storage.alloc_id()?),
             meta_id: storage.alloc_id()?,
-            storage,
+            meta_hash: Hash::default(),
+            meta_persisted: false,
+            stale: vec![],
+            retired: VecDeque::new(),
+            version: 0,
+            last_persisted: None,
+            pins: Arc::default(),
+            generations: Generations::default(),
+            shredding: HashSet::new(),
+            epoch: 0,
+            counter: None,
+            commit_error: None,
+            storage: Arc::new(Locked::new(storage)),
+            refs: None,
+            origins: HashMap::new(),
+            lookup_cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
             rng: R::default(),
             pd: PhantomData,
         })
     }
 
+    /// Reloads the tree persisted with root `id` under `key`.
     pub fn reload_with_storage(
+        id: NodeId,
+        storage: S,
+        key: Key<KEY_SZ>,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::reload_checked(id, storage, key, None)
+    }
+
+    /// Reloads the tree persisted with root `id` under `key`, refusing it if its epoch is behind
+    /// `counter`, as it is when its objects have been replaced with ones from an earlier persist.
+    /// The tree then advances `counter` every time it's persisted.
+    pub fn reload_with_storage_and_counter(
+        id: NodeId,
+        storage: S,
+        key: Key<KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<S::Error>> {
+        Self::reload_checked(id, storage, key, Some(Box::new(counter)))
+    }
+
+    fn reload_checked(
         id: NodeId,
         mut storage: S,
         key: Key<KEY_SZ>,
+        mut counter: Option<BoxedCounter>,
     ) -> Result<Self, Error<S::Error>> {
         // Load the root node.
-        let root = Node::load::<C, S>(id, key, &mut storage)?;
+        let (root, header) = Self::load_root(id, id, key, &mut storage)?;
 
-        // Load the metadata.
-        let meta = Self::load_meta(root.id, &mut storage)?;
+        // Load and check the metadata, then the reference counts if the tree's been forked.
+        let meta = Self::load_meta(&header, id, &root, key, &mut storage)?;
+        if let Some(counter) = &mut counter {
+            Self::check_syncs(&storage)?;
+            check_epoch::<S::Error>(&mut **counter, meta.epoch)?;
+        }
+        let refs = meta
+            .refs
+            .map(|(refs_id, refs_key)| Refs::load::<S>(refs_id, refs_key, &mut storage))
+            .transpose()?;
 
         Ok(Self {
             len: meta.len,
@@ -116,108 +491,436 @@ where
             updated: meta.updated,
             updated_blocks: meta.updated_blocks,
             in_flight_blocks: meta.in_flight_blocks,
+            last_persisted: Some((
+                Arc::new(root.unloaded_copy()),
+                meta.len,
+                header.meta_hash,
+                meta.epoch,
+            )),
             root,
-            meta_id: meta.meta_id,
+            meta_id: header.meta_id,
+            meta_hash: header.meta_hash,
+            meta_persisted: true,
+            stale: vec![],
+            retired: VecDeque::new(),
+            version: 0,
+            pins: Arc::default(),
+            generations: meta.generations,
+            shredding: HashSet::new(),
+            epoch: meta.epoch,
+            counter,
+            commit_error: None,
             rng: R::default(),
-            storage,
+            storage: Arc::new(Locked::new(storage)),
+            refs: refs.map(|refs| Arc::new(Locked::new(refs))),
+            origins: HashMap::new(),
+            lookup_cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
             pd: PhantomData,
         })
     }
 
-    fn load_meta(root_id: u64, storage: &mut S) -> Result<BKeyTreeMeta<KEY_SZ>, Error<S::Error>>
-    where
-        S: Storage<Id = u64>,
-    {
-        let meta_id = {
-            let mut reader = storage.read_handle(&root_id)?;
-            reader
-                .seek(SeekFrom::End(-1 * mem::size_of::<u64>() as i64))
-                .map_err(|_| Error::Seek)?;
-            utils::read_u64::<S>(&mut reader)?
+    /// Migrates a tree persisted with root `id` under `key` from before roots were tagged with
+    /// their format, returning it persisted in the current format under the same key. The whole
+    /// tree is read in and moved to fresh objects, and the root, which keeps its ID, is replaced
+    /// last, so a crash leaves the old tree as it was.
+    pub fn migrate_with_storage(
+        id: NodeId,
+        mut storage: S,
+        key: Key<KEY_SZ>,
+    ) -> Result<Self, Error<S::Error>> {
+        let (root, meta) = legacy::load::<C, S, KEY_SZ>(id, key, &mut storage)?;
+
+        let mut tree = Self {
+            len: meta.len,
+            degree: meta.degree,
+            updated: meta.updated,
+            updated_blocks: meta.updated_blocks,
+            in_flight_blocks: meta.in_flight_blocks,
+            root,
+            meta_id: meta.meta_id,
+            meta_hash: Hash::default(),
+            meta_persisted: true,
+            stale: vec![],
+            retired: VecDeque::new(),
+            version: 0,
+            last_persisted: None,
+            pins: Arc::default(),
+            generations: Generations::default(),
+            shredding: HashSet::new(),
+            epoch: 0,
+            counter: None,
+            commit_error: None,
+            storage: Arc::new(Locked::new(storage)),
+            refs: None,
+            origins: HashMap::new(),
+            lookup_cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
+            rng: R::default(),
+            pd: PhantomData,
         };
+        tree.persist(key)?;
 
-        let mut reader = storage.read_handle(&meta_id)?;
+        Ok(tree)
+    }
 
-        let len = utils::read_u64::<S>(&mut reader)?;
-        let degree = utils::read_u64::<S>(&mut reader)?;
+    /// Loads the root node, which is stored after a header tagging the format and holding the
+    /// ID and hash of the metadata object. The header is authenticated as that of root
+    /// `root_id`, which is `id` itself unless the object is a generation's copy of the root.
+    fn load_root(
+        id: NodeId,
+        root_id: NodeId,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<(Node<KEY_SZ>, RootHeader), Error<S::Error>> {
+        if storage.size(&id)? < RootHeader::SIZE {
+            return Err(Error::Format(id));
+        }
 
-        let updated_raw = utils::read_length_prefixed_bytes_clear::<S>(&mut reader)?;
-        let updated = bincode::deserialize(&updated_raw).map_err(|_| Error::Deserialization)?;
+        let mut reader = storage.read_handle(&id)?;
+        let header = utils::read_bytes::<S>(&mut reader, RootHeader::SIZE)?;
+        let header = RootHeader::decode(root_id, &header, key)?;
+        let root = Node::read_from::<C, S>(id, key, &mut reader)?;
+        Ok((root, header))
+    }
 
-        let updated_blocks_raw = utils::read_length_prefixed_bytes_clear::<S>(&mut reader)?;
-        let updated_blocks =
-            bincode::deserialize(&updated_blocks_raw).map_err(|_| Error::Deserialization)?;
+    fn persist_root(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        let header = RootHeader {
+            meta_id: self.meta_id,
+            meta_hash: self.meta_hash,
+        };
 
-        let in_flight_blocks_raw = utils::read_length_prefixed_bytes_clear::<S>(&mut reader)?;
-        let in_flight_blocks = utils::deserialize_keys_map::<KEY_SZ>(&in_flight_blocks_raw);
+        let mut storage = self.storage.lock();
+        {
+            let mut writer = storage.replace_handle(&self.root.id)?;
+            writer
+                .write_all(&header.encode(self.root.id, key)?)
+                .map_err(|_| Error::Write)?;
+            self.root.write_to::<C, S>(key, &mut writer)?;
+        }
 
-        Ok(BKeyTreeMeta {
-            meta_id,
-            len: len as usize,
-            degree: degree as usize,
-            updated,
-            updated_blocks,
-            in_flight_blocks,
-        })
+        storage.commit_handle(&self.root.id)?;
+        self.root.persisted = true;
+        self.root.dirty = false;
+        self.root.stored_children = self.root.child_ids();
+
+        Ok(())
+    }
+
+    /// Retires the objects that only the tree before the last persist referred to, and removes
+    /// the retired objects that no live snapshot or fork may refer to.
+    fn remove_stale(&mut self) -> Result<(), Error<S::Error>> {
+        self.retired
+            .push_back((self.version, mem::take(&mut self.stale)));
+        self.version += 1;
+        self.last_persisted = Some((
+            Arc::new(self.root.unloaded_copy()),
+            self.len,
+            self.meta_hash,
+            self.epoch,
+        ));
+
+        // An object retired by a version may be referred to by that version and any before it.
+        let oldest_pinned = self.pins.lock().keys().next().copied();
+        let mut storage = self.storage.lock();
+        while let Some((version, ids)) = self.retired.front_mut() {
+            if oldest_pinned.is_some_and(|pinned| pinned <= *version) {
+                break;
+            }
+
+            // Forks may still refer to an object the tree is done with, in which case only the
+            // tree's reference is dropped. Otherwise, an ID is dropped before its object is
+            // removed so that a failure can't lead to it being deallocated twice. At worst, the
+            // object is left for `gc()`.
+            match ids.pop() {
+                Some(id) if !self.release(&id) => {}
+                Some(id) => {
+                    if self.shredding.remove(&id) {
+                        Self::shred(&mut storage, &id)?;
+                    } else {
+                        storage.remove_id(&id)?;
+                    }
+                    storage.dealloc_id(id)?;
+                }
+                None => {
+                    self.retired.pop_front();
+                }
+            }
+        }
+        drop(storage);
+
+        // Freed IDs can be handed out again, so cached nodes may no longer match storage.
+        self.lookup_cache.get_mut().clear();
+
+        Ok(())
     }
 
-    fn persist_meta(&mut self) -> Result<(), Error<S::Error>>
+    /// Drops the tree's reference to object `id`, returning whether no fork refers to it either.
+    fn release(&self, id: &NodeId) -> bool {
+        self.refs
+            .as_ref()
+            .is_none_or(|refs| refs.lock().release(id))
+    }
+
+    /// Returns the children of the objects of the loaded nodes that have any, if the tree's been
+    /// forked, to note down for the objects persisting moves them from.
+    fn loaded_origins(&self) -> HashMap<NodeId, Vec<NodeId>> {
+        let mut origins = HashMap::new();
+        if self.refs.is_some() {
+            self.root.walk_loaded(&mut |node| {
+                if node.persisted && !node.stored_children.is_empty() {
+                    origins.insert(node.id, node.stored_children.clone());
+                }
+            });
+        }
+        origins
+    }
+
+    /// Notes down the children of the objects in `stale` that `origins` has them for.
+    fn note_origins(&mut self, mut origins: HashMap<NodeId, Vec<NodeId>>) {
+        for id in &self.stale {
+            if let Some(children) = origins.remove(id) {
+                self.origins.insert(*id, children);
+            }
+        }
+    }
+
+    /// Counts the objects the tree is about to refer to, if it's been forked, and persists the
+    /// counts. This happens before the root is written, so no fork refers to an uncounted object.
+    ///
+    /// Where a fork still refers to an object the tree let go of, the object replacing it is
+    /// counted as one more parent of its children first. A parent's count is pushed down before
+    /// its children's are looked at, since that can leave them shared too. Objects no fork refers
+    /// to pass their references on to their replacements as they are.
+    fn persist_refs(&mut self) -> Result<(), Error<S::Error>> {
+        let Some(refs) = &self.refs else {
+            return Ok(());
+        };
+        let mut storage = self.storage.lock();
+        let mut refs = refs.lock();
+
+        loop {
+            let shared = self
+                .origins
+                .keys()
+                .copied()
+                .filter(|id| refs.is_shared(id))
+                .collect::<Vec<_>>();
+            if shared.is_empty() {
+                break;
+            }
+            for id in shared {
+                for child in self.origins.remove(&id).unwrap() {
+                    refs.share(child);
+                }
+            }
+        }
+        self.origins.clear();
+
+        refs.track(self.root.id);
+        refs.track(self.meta_id);
+        for generation in &self.generations.list {
+            refs.track(generation.root_id);
+        }
+        self.root.walk_loaded(&mut |node| {
+            if node.persisted {
+                refs.track(node.id);
+            }
+        });
+
+        refs.persist::<S>(&mut storage)
+    }
+
+    /// Loads the metadata the header of root `root_id` points to, which has to hash to what the
+    /// header holds for it and belong to that root. The `root` node read along with the header
+    /// has to hash to what the metadata holds for it, which is checked here, before the hashes
+    /// it holds for its children are relied on.
+    fn load_meta(
+        header: &RootHeader,
+        root_id: NodeId,
+        root: &Node<KEY_SZ>,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<BKeyTreeMeta<KEY_SZ>, Error<S::Error>>
     where
         S: Storage<Id = u64>,
     {
-        {
-            let mut writer = self.storage.write_handle(&self.root.id)?;
-            writer.seek(SeekFrom::End(0)).map_err(|_| Error::Seek)?;
-            utils::write_u64::<S>(&mut writer, self.meta_id)?;
+        let size = storage.size(&header.meta_id)?;
+        let mut reader = storage.read_handle(&header.meta_id)?;
+        let raw = utils::read_bytes::<S>(&mut reader, size)?;
+        if sha256(&raw) != header.meta_hash {
+            return Err(Error::HashMismatch(header.meta_id));
         }
 
-        let mut writer = self.storage.write_handle(&self.meta_id)?;
+        let meta = BKeyTreeMeta::decode::<C, S::Error>(header.meta_id, &raw, key)?;
+        meta.check_root(root_id, root)?;
+        Ok(meta)
+    }
 
-        utils::write_u64::<S>(&mut writer, self.len as u64)?;
-        utils::write_u64::<S>(&mut writer, self.degree as u64)?;
+    /// Persists the metadata, first making the tree being superseded a generation if it's to
+    /// keep `keep` of them.
+    fn persist_meta(&mut self, keep: usize, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>>
+    where
+        S: Storage<Id = u64>,
+    {
+        // Like the nodes, the metadata moves to a fresh object rather than overwriting the one
+        // the last persisted root refers to.
+        if self.meta_persisted {
+            let old_id = mem::replace(&mut self.meta_id, self.storage.lock().alloc_id()?);
+            self.stale.push(old_id);
+        }
 
-        let updated_raw = bincode::serialize(&self.updated).map_err(|_| Error::Serialization)?;
-        utils::write_length_prefixed_bytes_clear::<S>(&mut writer, &updated_raw)?;
+        // The root is copied out before it's overwritten, and the generation takes over the
+        // objects that only it will refer to. The oldest generations past `keep` are dropped.
+        if keep > 0 && self.root.persisted {
+            let root_id = self.copy_object(&self.root.id)?;
+            self.generations.list.push_back(Generation {
+                root_id,
+                objects: mem::take(&mut self.stale),
+            });
+        }
+        while self.generations.list.len() > keep {
+            let generation = self.generations.list.pop_front().unwrap();
+            self.stale.extend(generation.ids());
+        }
 
-        let updated_blocks_raw =
-            bincode::serialize(&self.updated_blocks).map_err(|_| Error::Serialization)?;
-        utils::write_length_prefixed_bytes_clear::<S>(&mut writer, &updated_blocks_raw)?;
+        let raw = BKeyTreeMeta::encode::<C, S::Error>(
+            self.root.id,
+            &self.root.hash(),
+            self.len,
+            self.degree,
+            &self.updated,
+            &self.updated_blocks,
+            &self.in_flight_blocks,
+            self.refs.as_ref().map(|refs| {
+                let refs = refs.lock();
+                (refs.id, refs.key)
+            }),
+            &self.generations,
+            self.epoch,
+            self.meta_id,
+            key,
+        )?;
+        self.storage
+            .lock()
+            .replace_handle(&self.meta_id)?
+            .write_all(&raw)
+            .map_err(|_| Error::Write)?;
 
-        let in_flight_blocks_raw = utils::serialize_keys_map(&self.in_flight_blocks);
-        utils::write_length_prefixed_bytes_clear::<S>(&mut writer, &in_flight_blocks_raw)?;
+        self.storage.lock().commit_handle(&self.meta_id)?;
+        self.meta_hash = sha256(&raw);
+        self.meta_persisted = true;
 
         Ok(())
     }
 
     pub fn load(&mut self, id: NodeId, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
         // Load the root node.
-        let root = Node::load::<C, S>(id, key, &mut self.storage)?;
+        let (root, header) = Self::load_root(id, id, key, &mut self.storage.lock())?;
 
-        // Load the metadata.
-        let meta = Self::load_meta(root.id, &mut self.storage)?;
+        // Load the metadata. Forks loaded alongside the tree already share its reference counts,
+        // which are never behind the ones in storage.
+        let meta = Self::load_meta(&header, id, &root, key, &mut self.storage.lock())?;
+        if let Some(counter) = &mut self.counter {
+            check_epoch::<S::Error>(&mut **counter, meta.epoch)?;
+        }
+        let refs = match meta.refs {
+            Some((refs_id, refs_key)) if self.refs.is_none() => Some(Refs::load::<S>(
+                refs_id,
+                refs_key,
+                &mut self.storage.lock(),
+            )?),
+            _ => None,
+        };
 
         // Update state after the fallible operations.
+        if let Some(refs) = refs {
+            self.refs = Some(Arc::new(Locked::new(refs)));
+        }
         self.root = root;
-        self.meta_id = meta.meta_id;
+        self.meta_id = header.meta_id;
+        self.meta_hash = header.meta_hash;
+        self.meta_persisted = true;
+        self.stale.clear();
+        self.origins.clear();
+        self.lookup_cache.get_mut().clear();
+        self.version += 1;
+        self.last_persisted = Some((
+            Arc::new(self.root.unloaded_copy()),
+            meta.len,
+            header.meta_hash,
+            meta.epoch,
+        ));
         self.len = meta.len;
         self.degree = meta.degree;
         self.updated = meta.updated;
         self.updated_blocks = meta.updated_blocks;
         self.in_flight_blocks = meta.in_flight_blocks;
+        self.generations = meta.generations;
+        self.epoch = meta.epoch;
 
         Ok(())
     }
 
-    pub fn persist(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
-        // Persist the root node.
-        self.root.persist::<C, S>(key, &mut self.storage)?;
+    /// Refuses to keep a trusted counter over storage that holds back syncs, since a persist
+    /// would advance it past what's durable.
+    fn check_syncs(storage: &S) -> Result<(), Error<S::Error>> {
+        if storage.defers_syncs() {
+            return Err(Error::DeferredSyncs);
+        }
+        Ok(())
+    }
+
+    /// Advances the trusted counter, if there is one, to the epoch just persisted. This happens
+    /// only once the root is durable, so the counter is never ahead of the tree in storage.
+    fn advance_counter(&mut self) -> Result<(), Error<S::Error>> {
+        match &mut self.counter {
+            Some(counter) => counter.advance(self.epoch).map_err(Error::Counter),
+            None => Ok(()),
+        }
+    }
 
-        // Persist the metadata.
-        self.persist_meta()?;
+    /// Persists the tree under `key`. Everything but the root is written to fresh objects, so
+    /// the tree last persisted stays intact until the root is replaced, and a crash at any point
+    /// leaves one tree or the other. The objects only the old tree used are removed afterwards.
+    pub fn persist(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        self.persist_keeping(key, self.generations.kept)
+    }
 
-        Ok(())
+    /// Persists the tree under `key`, keeping `keep` generations.
+    fn persist_keeping(&mut self, key: Key<KEY_SZ>, keep: usize) -> Result<(), Error<S::Error>> {
+        // Persist the nodes below the root, noting what the objects they move from referred to
+        // even if that fails partway.
+        let origins = self.loaded_origins();
+        let res = self.root.persist_children::<C, S>(
+            &mut self.storage.lock(),
+            &mut self.stale,
+            &mut self.updated,
+        );
+        self.note_origins(origins);
+        res?;
+
+        // Persist the metadata, and the reference counts if the tree's been forked.
+        self.epoch += 1;
+        self.persist_meta(keep, key)?;
+        self.persist_refs()?;
+
+        // The root is what makes the rest reachable, so everything else has to be durable first.
+        self.storage.lock().sync()?;
+
+        // Persist the root node. Committing its replacement is atomic, but only durable once
+        // it's synced; storage that commits durably, like `DirectoryStorage`, has nothing left
+        // to do here.
+        self.persist_root(key)?;
+        self.storage.lock().sync_id(&self.root.id)?;
+        self.advance_counter()?;
+
+        self.remove_stale()
     }
 
+    /// Persists the nodes on the path to `block` under `key`, along with the root and the
+    /// metadata, returning whether the block is in the tree. Nothing is written if it isn't, or
+    /// if nothing on the way to it has changed. Nodes that were never persisted, like those a
+    /// split leaves off the path, can't be left out, so the whole tree is persisted if there are
+    /// any.
     pub fn persist_block(
         &mut self,
         block: &BlockId,
@@ -228,16 +931,49 @@ where
             self.insert(*block, block_key)?;
         }
 
-        // Persist the block, persisting any nodes along the way.
-        let res = self
+        match self
             .root
-            .persist_block::<C, S>(block, key, &mut self.storage)?;
+            .changed_on_path::<C, S>(block, &mut self.storage.lock(), &self.updated)?
+        {
+            None => return Ok(false),
+            Some(false) => return Ok(true),
+            Some(true) => {}
+        }
 
-        // Persist the metadata if we persisted the block.
-        if res {
-            self.persist_meta()?;
+        let root_id = self.root.id;
+        let mut unpersisted = false;
+        self.root
+            .walk_loaded(&mut |node| unpersisted |= node.id != root_id && !node.persisted);
+        if unpersisted {
+            self.persist(key)?;
+            return Ok(true);
         }
 
+        // Persist the block, persisting any nodes along the way, as in `persist_keeping()`.
+        let origins = self.loaded_origins();
+        let res = self.root.persist_block::<C, S>(
+            block,
+            &mut self.storage.lock(),
+            &mut self.stale,
+            &mut self.updated,
+        );
+        self.note_origins(origins);
+        let res = res?;
+
+        // Persist the metadata, which records the new IDs of any updated nodes that moved.
+        self.epoch += 1;
+        self.persist_meta(self.generations.kept, key)?;
+        self.persist_refs()?;
+
+        // The root is what makes the rest reachable, so everything else has to be durable first.
+        self.storage.lock().sync()?;
+
+        // Persist the root node, making it durable as in `persist_keeping()`.
+        self.persist_root(key)?;
+        self.storage.lock().sync_id(&self.root.id)?;
+        self.advance_counter()?;
+        self.remove_stale()?;
+
         Ok(res)
     }
 
@@ -253,28 +989,640 @@ where
         self.root.id
     }
 
-    pub fn contains(&mut self, k: &BlockId) -> Result<bool, Error<S::Error>> {
-        Ok(self.get(k)?.is_some())
+    pub fn degree(&self) -> usize {
+        self.degree
+    }
+
+    /// Returns the hash of the root as last persisted or reloaded, or `None` if the tree's been
+    /// neither. Every node holds the hashes of its children, which are checked as they're read
+    /// in, the authenticated metadata holds that of the root, which is checked before any of
+    /// them, and the root's header holds that of the metadata, so this commits to the whole tree
+    /// along with its metadata and epoch, and can be anchored outside of storage to attest to
+    /// it. Where nodes are stored and which keys encrypt them don't enter into it.
+    pub fn root_hash(&self) -> Option<Hash> {
+        self.last_persisted
+            .as_ref()
+            .map(|(root, _, meta_hash, epoch)| proof::tree_hash(&root.hash(), meta_hash, *epoch))
+    }
+
+    /// Returns a proof of whether `block` is in the tree as it was last persisted or reloaded,
+    /// at the epoch it was persisted at, which checks out against [`BKeyTree::root_hash`], or
+    /// `None` if the tree's been neither. A block removed since is still proven present until
+    /// the next persist.
+    ///
+    /// A proof only speaks for the tree's nodes, so the tree refuses to give one while its key
+    /// may be held elsewhere. It fails with [`Error::InFlight`] while the block's key is in
+    /// flight, with [`Error::KeepsGenerations`] while the tree keeps any earlier generations, even
+    /// after [`BKeyTree::set_kept_generations`] has lowered the limit to zero and until the next
+    /// persist drops them, and with [`Error::Forked`] once the tree has been forked or reloaded
+    /// alongside a fork, for good, since the reference counts stay with it.
+    ///
+    /// A proof holds the block IDs on its path and hashes of the keys and children there, but
+    /// none of the keys. Each field of a node is encrypted under a key of its own, so what those
+    /// give away of the stored nodes' keystreams doesn't decrypt the keys either.
+    pub fn prove(&self, block: &BlockId) -> Result<Option<Proof>, Error<S::Error>> {
+        if self.in_flight_blocks.contains_key(block) {
+            return Err(Error::InFlight(*block));
+        }
+        if !self.generations.list.is_empty() {
+            return Err(Error::KeepsGenerations);
+        }
+        if self.refs.is_some() {
+            return Err(Error::Forked);
+        }
+
+        let Some((root, _, meta_hash, epoch)) = &self.last_persisted else {
+            return Ok(None);
+        };
+
+        let mut path = vec![];
+        root.prove_into(
+            block,
+            &|id, key, hash| self.cached_node(id, key, hash),
+            &mut path,
+        )?;
+
+        Ok(Some(Proof::new(*block, *epoch, *meta_hash, path)))
+    }
+
+    /// Returns the number of times the tree has been persisted, counting from its creation.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Has the tree advance `counter` every time it's persisted, and check trees it loads against
+    /// it. A tree that's been persisted or reloaded is refused if it's behind the counter, while
+    /// a new one starts from the counter's value. Storage that holds back syncs is refused too.
+    pub fn set_trusted_counter(
+        &mut self,
+        mut counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<(), Error<S::Error>> {
+        Self::check_syncs(&self.storage.lock())?;
+        if self.meta_persisted {
+            check_epoch::<S::Error>(&mut counter, self.epoch)?;
+        } else {
+            self.epoch = self.epoch.max(counter.read().map_err(Error::Counter)?);
+        }
+
+        self.counter = Some(Box::new(counter));
+        Ok(())
+    }
+
+    /// Consumes the tree, returning its storage. The tree is given back instead if a snapshot or
+    /// a fork of it is still alive, since they share the storage.
+    pub fn into_storage(mut self) -> Result<S, Self> {
+        match Arc::try_unwrap(self.storage) {
+            Ok(storage) => Ok(storage.into_inner()),
+            Err(storage) => {
+                self.storage = storage;
+                Err(self)
+            }
+        }
+    }
+
+    /// Returns a read-only view of the tree as it was last persisted or reloaded, or `None` if
+    /// it's been neither. Nothing done to the tree afterwards affects the view, since the
+    /// objects it refers to are kept in storage until it's dropped.
+    pub fn snapshot(&self) -> Option<Snapshot<S, C, KEY_SZ>> {
+        let (root, len, ..) = self.last_persisted.clone()?;
+        Some(Snapshot::new(
+            self.version,
+            root,
+            len,
+            self.storage.clone(),
+            self.pins.clone(),
+        ))
+    }
+
+    /// Persists the tree under `key` and returns a fork of it, persisted under `key` as well.
+    /// The two start out sharing every node below their roots, and each moves a shared node to a
+    /// fresh object once it changes it, as with any persisted node. Objects are reference counted
+    /// in storage, so neither tree removes or shreds one that the other still refers to.
+    ///
+    /// No node is copied or read: only the counts of the root's children go up, and those of the
+    /// nodes below are pushed down as the two trees diverge.
+    pub fn fork(&mut self, key: Key<KEY_SZ>) -> Result<Self, Error<S::Error>> {
+        // The tree has to point to the counts before anything else refers to its nodes.
+        if self.refs.is_none() {
+            let refs_id = self.storage.lock().alloc_id()?;
+            let mut refs_key = [0; 32];
+            self.rng.fill_bytes(&mut refs_key);
+            self.refs = Some(Arc::new(Locked::new(Refs::new(refs_id, refs_key))));
+        }
+        self.persist(key)?;
+
+        let (root_id, meta_id) = {
+            let mut storage = self.storage.lock();
+            (storage.alloc_id()?, storage.alloc_id()?)
+        };
+        let mut fork = Self {
+            len: self.len,
+            degree: self.degree,
+            updated: self.updated.clone(),
+            updated_blocks: self.updated_blocks.clone(),
+            in_flight_blocks: self.in_flight_blocks.clone(),
+            root: Node {
+                id: root_id,
+                persisted: false,
+                ..self.root.unloaded_copy()
+            },
+            meta_id,
+            meta_hash: Hash::default(),
+            meta_persisted: false,
+            stale: vec![],
+            retired: VecDeque::new(),
+            version: 0,
+            last_persisted: None,
+            pins: Arc::default(),
+            generations: Generations {
+                kept: self.generations.kept,
+                ..Generations::default()
+            },
+            shredding: HashSet::new(),
+            epoch: self.epoch,
+            counter: None,
+            commit_error: None,
+            storage: self.storage.clone(),
+            refs: self.refs.clone(),
+            origins: HashMap::new(),
+            lookup_cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
+            rng: R::default(),
+            pd: PhantomData,
+        };
+
+        // The root's children now have the fork's root as another parent. The counts reach
+        // storage when the fork is persisted, before its root does.
+        if let Some(refs) = &self.refs {
+            let mut refs = refs.lock();
+            for id in self.root.child_ids() {
+                refs.share(id);
+            }
+        }
+
+        fork.persist(key)?;
+
+        Ok(fork)
+    }
+
+    /// Reloads a fork of the tree, persisted with root `id` under `key`, alongside the tree. The
+    /// two share storage and reference counts just as they did after [`BKeyTree::fork`].
+    pub fn reload_fork(&self, id: NodeId, key: Key<KEY_SZ>) -> Result<Self, Error<S::Error>> {
+        self.reload_fork_checked(id, key, None)
+    }
+
+    /// Reloads a fork of the tree as [`BKeyTree::reload_fork`] does, checking its epoch against
+    /// `counter` as [`BKeyTree::reload_with_storage_and_counter`] does.
+    pub fn reload_fork_with_counter(
+        &self,
+        id: NodeId,
+        key: Key<KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<S::Error>> {
+        self.reload_fork_checked(id, key, Some(Box::new(counter)))
+    }
+
+    fn reload_fork_checked(
+        &self,
+        id: NodeId,
+        key: Key<KEY_SZ>,
+        mut counter: Option<BoxedCounter>,
+    ) -> Result<Self, Error<S::Error>> {
+        let mut storage = self.storage.lock();
+        let (root, header) = Self::load_root(id, id, key, &mut storage)?;
+        let meta = Self::load_meta(&header, id, &root, key, &mut storage)?;
+        if let Some(counter) = &mut counter {
+            Self::check_syncs(&storage)?;
+            check_epoch::<S::Error>(&mut **counter, meta.epoch)?;
+        }
+        let refs = match (&self.refs, meta.refs) {
+            (Some(refs), _) => Some(refs.clone()),
+            (None, Some((refs_id, refs_key))) => Some(Arc::new(Locked::new(Refs::load::<S>(
+                refs_id,
+                refs_key,
+                &mut storage,
+            )?))),
+            (None, None) => None,
+        };
+        drop(storage);
+
+        Ok(Self {
+            len: meta.len,
+            degree: meta.degree,
+            updated: meta.updated,
+            updated_blocks: meta.updated_blocks,
+            in_flight_blocks: meta.in_flight_blocks,
+            last_persisted: Some((
+                Arc::new(root.unloaded_copy()),
+                meta.len,
+                header.meta_hash,
+                meta.epoch,
+            )),
+            root,
+            meta_id: header.meta_id,
+            meta_hash: header.meta_hash,
+            meta_persisted: true,
+            stale: vec![],
+            retired: VecDeque::new(),
+            version: 0,
+            pins: Arc::default(),
+            generations: meta.generations,
+            shredding: HashSet::new(),
+            epoch: meta.epoch,
+            counter,
+            commit_error: None,
+            rng: R::default(),
+            storage: self.storage.clone(),
+            refs,
+            origins: HashMap::new(),
+            lookup_cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
+            pd: PhantomData,
+        })
+    }
+
+    /// Bounds the cache that lookups through a shared reference read nodes into to `nodes`
+    /// nodes, dropping the least recently used ones beyond that. Each cached node is held whole,
+    /// keys and all, so the cache takes up to about `nodes` times the size of a full node, which
+    /// grows with the degree. It defaults to 1024 nodes; 0 turns it off, so that every lookup
+    /// that leaves the loaded part of the tree reads from storage.
+    pub fn set_lookup_cache_capacity(&mut self, nodes: usize) {
+        self.lookup_cache.get_mut().set_capacity(nodes);
+    }
+
+    /// Has each persist keep the tree it supersedes as a generation, up to `n` of them, instead
+    /// of removing what only that tree refers to. The setting is persisted with the tree, and
+    /// lowering it drops the oldest generations at the next persist. Generations are dropped
+    /// like any other stale object; see [`BKeyTree::prune_generations`] to shred them instead.
+    pub fn set_kept_generations(&mut self, n: usize) {
+        self.generations.kept = n;
+    }
+
+    /// Returns the IDs of the copies of the roots of the kept generations, most recent first.
+    /// Generation `n` in [`BKeyTree::reload_generation`] is the `n`th of these.
+    pub fn list_generations(&self) -> Vec<NodeId> {
+        self.generations
+            .list
+            .iter()
+            .rev()
+            .map(|generation| generation.root_id)
+            .collect()
+    }
+
+    /// Rolls the tree back to generation `n`, counting from the most recent, which was persisted
+    /// under `key`. The whole generation is read in and moved to fresh objects, so the
+    /// generations stay as they are, and the rollback takes effect at the next persist. With
+    /// generations kept, the tree rolled back from becomes one itself at that persist.
+    pub fn reload_generation(&mut self, n: usize, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        let root_id = self
+            .generations
+            .list
+            .iter()
+            .rev()
+            .nth(n)
+            .ok_or(Error::NoGeneration(n))?
+            .root_id;
+
+        let mut storage = self.storage.lock();
+        // The copy is authenticated as the root it was copied from, which keeps its ID.
+        let (mut root, header) = Self::load_root(root_id, self.root.id, key, &mut storage)?;
+        let meta = Self::load_meta(&header, self.root.id, &root, key, &mut storage)?;
+        let mut moved = HashMap::new();
+        root.detach::<C, S>(&mut storage, &mut moved)?;
+
+        // Whatever the last persisted tree refers to below its root is left behind.
+        let mut stale = vec![];
+        if let Some((last, ..)) = &self.last_persisted {
+            last.walk::<C, S, _>(&mut storage, &mut |node, depth, _| {
+                if depth > 0 {
+                    stale.push(node.id);
+                }
+            })?;
+        }
+        drop(storage);
+
+        // Update state after the fallible operations. The root keeps its object.
+        root.id = self.root.id;
+        root.persisted = self.root.persisted;
+        self.root = root;
+        self.stale = stale;
+        self.lookup_cache.get_mut().clear();
+        self.len = meta.len;
+        self.degree = meta.degree;
+        self.updated = meta
+            .updated
+            .into_iter()
+            .map(|id| moved.get(&id).copied().unwrap_or(id))
+            .collect();
+        self.updated_blocks = meta.updated_blocks;
+        self.in_flight_blocks = meta.in_flight_blocks;
+
+        Ok(())
+    }
+
+    /// Drops every kept generation and persists the tree under `key`. Unlike generations dropped
+    /// at an ordinary persist, the objects only they refer to are overwritten with zeros before
+    /// they're removed, so none of the keys they held can be recovered from storage.
+    pub fn prune_generations(&mut self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        self.shredding.extend(self.generations.ids());
+        self.persist_keeping(key, 0)
+    }
+
+    /// Returns every block and its key, in block order.
+    pub fn entries(&mut self) -> Result<Vec<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
+        let mut entries = Vec::with_capacity(self.len);
+
+        self.root
+            .walk::<C, S, _>(&mut self.storage.lock(), &mut |node, _, _| {
+                entries.extend(node.keys.iter().copied().zip(node.vals.iter().copied()))
+            })?;
+
+        entries.sort_unstable_by_key(|(block, _)| *block);
+
+        Ok(entries)
+    }
+
+    /// Checks that the tree satisfies the B-tree invariants and that its length is accurate.
+    pub fn verify(&mut self) -> Result<(), Error<S::Error>> {
+        let len = self.root.verify::<C, S>(
+            (None, None),
+            0,
+            self.degree,
+            &mut None,
+            &mut self.storage.lock(),
+        )?;
+
+        if len != self.len {
+            return Err(Error::Invariant(
+                self.root.id,
+                "length doesn't match entries",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Returns the IDs of the root, the metadata object and every node below the root, along
+    /// with those of every node a live snapshot refers to, every retired object, every object
+    /// a fork may refer to and every object kept for a generation.
+    fn reachable(&mut self) -> Result<HashSet<u64>, Error<S::Error>> {
+        let mut reachable = HashSet::from([self.root.id, self.meta_id]);
+        reachable.extend(self.generations.ids());
+        if let Some(refs) = &self.refs {
+            let refs = refs.lock();
+            reachable.insert(refs.id);
+            reachable.extend(refs.ids());
+        }
+        let pinned = self
+            .pins
+            .lock()
+            .values()
+            .map(|(_, root)| root.clone())
+            .collect::<Vec<_>>();
+        let mut storage = self.storage.lock();
+
+        for root in [&self.root]
+            .into_iter()
+            .chain(pinned.iter().map(|root| &**root))
+        {
+            root.walk::<C, S, _>(&mut storage, &mut |node, _, _| {
+                reachable.insert(node.id);
+            })?;
+        }
+        reachable.extend(self.retired.iter().flat_map(|(_, ids)| ids.iter().copied()));
+
+        Ok(reachable)
+    }
+
+    /// Returns the IDs of the objects in storage that neither the tree, a live snapshot, a fork
+    /// nor a kept generation can reach, in ID order. This is relative to the in-memory tree, so
+    /// it fails with [`Error::Unpersisted`] if the tree has let go of objects that the last
+    /// persisted root still refers to.
+    pub fn orphans(&mut self) -> Result<Vec<u64>, Error<S::Error>> {
+        if !self.stale.is_empty() {
+            return Err(Error::Unpersisted);
+        }
+
+        let reachable = self.reachable()?;
+        let mut orphans = self.storage.lock().ids()?;
+        orphans.retain(|id| !reachable.contains(id));
+        Ok(orphans)
+    }
+
+    /// Removes every object in storage that the tree can't reach, overwriting each with zeros
+    /// first, deallocates their IDs and returns them. Like `orphans()`, this fails with
+    /// [`Error::Unpersisted`] rather than remove nodes that the last persisted root still refers
+    /// to, so persist the tree first.
+    pub fn gc(&mut self) -> Result<Vec<u64>, Error<S::Error>> {
+        let orphans = self.orphans()?;
+
+        let mut storage = self.storage.lock();
+        for id in &orphans {
+            Self::shred(&mut storage, id)?;
+        }
+        storage.sync()?;
+
+        // The tree only deallocates an ID once its object is removed, so an orphan's ID is
+        // normally still allocated. Storage may not know of IDs allocated before it was opened,
+        // though, or may already have one back if a crash restored its object, in which case
+        // there's nothing to reclaim.
+        for &id in &orphans {
+            let _ = storage.dealloc_id(id);
+        }
+
+        Ok(orphans)
+    }
+
+    /// Copies object `id` to a fresh object, returning the new object's ID.
+    fn copy_object(&self, id: &u64) -> Result<u64, Error<S::Error>> {
+        let mut storage = self.storage.lock();
+        let size = storage.size(id)?;
+        let raw = {
+            let mut reader = storage.read_handle(id)?;
+            utils::read_bytes::<S>(&mut reader, size)?
+        };
+
+        let copy_id = storage.alloc_id()?;
+        storage
+            .replace_handle(&copy_id)?
+            .write_all(&raw)
+            .map_err(|_| Error::Write)?;
+        storage.commit_handle(&copy_id)?;
+
+        Ok(copy_id)
+    }
+
+    /// Overwrites object `id` with zeros and then removes it.
+    fn shred(storage: &mut S, id: &u64) -> Result<(), Error<S::Error>> {
+        const ZEROS: [u8; 4096] = [0; 4096];
+
+        let mut remaining = storage.size(id)?;
+        {
+            let mut writer = storage.write_handle(id)?;
+            while remaining > 0 {
+                let n = remaining.min(ZEROS.len() as u64) as usize;
+                writer.write_all(&ZEROS[..n]).map_err(|_| Error::Write)?;
+                remaining -= n as u64;
+            }
+        }
+        storage.sync_id(id)?;
+
+        Ok(storage.remove_id(id)?)
+    }
+
+    /// Gathers statistics about the tree. Every node is visited, but unloaded nodes are only read
+    /// in for the visit and stay unloaded afterwards. Storage isn't scanned, so the object
+    /// counts are left out; see [`BKeyTree::stats_with_storage`].
+    pub fn stats(&mut self) -> Result<Stats, Error<S::Error>> {
+        let capacity = (2 * self.degree - 1) as f64;
+        let mut stats = Stats {
+            len: self.len,
+            degree: self.degree,
+            height: 0,
+            nodes: 0,
+            leaves: 0,
+            avg_fill: 0.0,
+            min_fill: 1.0,
+            loaded: 0,
+            unloaded: 0,
+            updated: self.updated.len(),
+            updated_blocks: self.updated_blocks.len(),
+            in_flight_blocks: self.in_flight_blocks.len(),
+            objects: None,
+            bytes: None,
+        };
+        let mut total_fill = 0.0;
+
+        self.root
+            .walk::<C, S, _>(&mut self.storage.lock(), &mut |node, depth, loaded| {
+                let fill = node.len() as f64 / capacity;
+
+                stats.height = stats.height.max(depth + 1);
+                stats.nodes += 1;
+                total_fill += fill;
+
+                if node.is_leaf() {
+                    stats.leaves += 1;
+                }
+
+                if loaded {
+                    stats.loaded += 1;
+                } else {
+                    stats.unloaded += 1;
+                }
+
+                // The root is exempt from minimum occupancy, so it would only skew this.
+                if depth > 0 {
+                    stats.min_fill = stats.min_fill.min(fill);
+                }
+            })?;
+
+        stats.avg_fill = total_fill / stats.nodes as f64;
+        if stats.nodes == 1 {
+            stats.min_fill = stats.avg_fill;
+        }
+
+        Ok(stats)
+    }
+
+    /// Like [`BKeyTree::stats`], but also counts every object in storage and its size, which
+    /// takes a pass over all of storage on top of the tree.
+    pub fn stats_with_storage(&mut self) -> Result<Stats, Error<S::Error>> {
+        let mut stats = self.stats()?;
+        let (mut objects, mut bytes) = (0, 0);
+
+        let storage = self.storage.lock();
+        for id in storage.ids()? {
+            objects += 1;
+            bytes += storage.size(&id)?;
+        }
+
+        stats.objects = Some(objects);
+        stats.bytes = Some(bytes);
+        Ok(stats)
+    }
+
+    /// Exports the in-memory node graph, marking which nodes are loaded and which are pending
+    /// an update. Nothing is loaded from storage, and key material is redacted.
+    pub fn export_structure(&self, format: ExportFormat) -> String {
+        export::export(&self.root, self.degree, &self.updated, format, false)
+    }
+
+    /// Like [`BKeyTree::export_structure`], but includes block and child keys in the export.
+    pub fn export_structure_with_keys(&self, format: ExportFormat) -> String {
+        export::export(&self.root, self.degree, &self.updated, format, true)
+    }
+
+    pub fn contains(&self, k: &BlockId) -> Result<bool, Error<S::Error>> {
+        Ok(self.lookup(k)?.is_some())
+    }
+
+    /// Like [`BKeyTree::get`], but through a shared reference, returning a copy of the key.
+    /// Nodes that aren't loaded are read into a side cache rather than the tree, which is dropped
+    /// the next time the tree is persisted or reloaded. See
+    /// [`BKeyTree::set_lookup_cache_capacity`] for how big it gets.
+    ///
+    /// Reading in a node takes the storage's lock, so lookups that miss both the tree and the
+    /// cache are serialized with each other and with anything else using the storage.
+    pub fn lookup(&self, k: &BlockId) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.root
+            .lookup(k, &|id, key, hash| self.cached_node(id, key, hash))
+    }
+
+    /// Returns every block in `range` and its key, in block order. Like [`BKeyTree::lookup`],
+    /// this goes through a shared reference.
+    pub fn range(
+        &self,
+        range: impl RangeBounds<BlockId>,
+    ) -> Result<Vec<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
+        let mut entries = vec![];
+        self.root.range_into(
+            &range,
+            &|id, key, hash| self.cached_node(id, key, hash),
+            &mut entries,
+        )?;
+        Ok(entries)
+    }
+
+    /// Returns node `id` from the lookup cache, reading it in if it isn't there yet.
+    fn cached_node(
+        &self,
+        id: NodeId,
+        key: Key<KEY_SZ>,
+        hash: &Hash,
+    ) -> Result<Arc<Node<KEY_SZ>>, Error<S::Error>> {
+        if let Some(node) = self.lookup_cache.lock().get(&id) {
+            return Ok(node);
+        }
+
+        // The cache isn't held while reading, so a node may occasionally be read twice.
+        let node = Arc::new(Node::load_child::<C, S>(
+            id,
+            key,
+            hash,
+            &mut self.storage.lock(),
+        )?);
+        self.lookup_cache.lock().insert(id, node.clone());
+
+        Ok(node)
     }
 
     pub fn get(&mut self, k: &BlockId) -> Result<Option<&Key<KEY_SZ>>, Error<S::Error>> {
         Ok(self
             .root
-            .get::<C, S>(k, &mut self.storage)?
+            .get::<C, S>(k, &mut self.storage.lock())?
             .map(|(idx, node)| &node.vals[idx]))
     }
 
     pub fn get_node(&mut self, k: &BlockId) -> Result<Option<&Node<KEY_SZ>>, Error<S::Error>> {
         Ok(self
             .root
-            .get::<C, S>(k, &mut self.storage)?
+            .get::<C, S>(k, &mut self.storage.lock())?
             .map(|(_, node)| node))
     }
 
     pub fn get_mut(&mut self, k: &BlockId) -> Result<Option<&mut Key<KEY_SZ>>, Error<S::Error>> {
         Ok(self
             .root
-            .get_mut::<C, S>(k, &mut self.storage)?
+            .get_mut::<C, S>(k, &mut self.storage.lock())?
             .map(|(idx, node)| &mut node.vals[idx]))
     }
 
@@ -284,7 +1632,7 @@ where
     ) -> Result<Option<(&BlockId, &Key<KEY_SZ>)>, Error<S::Error>> {
         Ok(self
             .root
-            .get::<C, S>(k, &mut self.storage)?
+            .get::<C, S>(k, &mut self.storage.lock())?
             .map(|(idx, node)| (&node.keys[idx], &node.vals[idx])))
     }
 
@@ -295,29 +1643,31 @@ where
         v: Key<KEY_SZ>,
     ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
         if self.root.is_full(self.degree) {
-            let mut new_root = Node::new(self.storage.alloc_id()?);
+            let mut new_root = Node::new(self.storage.lock().alloc_id()?);
             let new_root_key = self.generate_key();
 
             mem::swap(&mut self.root, &mut new_root);
 
             self.root.children.push(Child::Loaded(new_root));
             self.root.children_keys.push(new_root_key);
+            self.root.children_hashes.push(Hash::default());
 
+            let right_id = self.storage.lock().alloc_id()?;
             self.root.split_child(
                 0,
                 self.degree,
-                &mut self.storage,
+                right_id,
                 false,
                 &mut self.rng,
                 &mut self.updated,
-            )?;
+            );
         }
 
         let res = self.root.insert_nonfull::<C, R, S>(
             k,
             v,
             self.degree,
-            &mut self.storage,
+            &mut self.storage.lock(),
             false,
             &mut self.rng,
             &mut self.updated,
@@ -337,7 +1687,8 @@ where
         v: Key<KEY_SZ>,
     ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
         if self.root.is_full(self.degree) {
-            let mut new_root = Node::new(self.storage.alloc_id()?);
+            let mut new_root = Node::new(self.storage.lock().alloc_id()?);
+            let new_root_key = self.generate_key();
 
             self.updated.insert(self.root.id);
             self.updated.insert(new_root.id);
@@ -345,21 +1696,24 @@ where
             mem::swap(&mut self.root, &mut new_root);
 
             self.root.children.push(Child::Loaded(new_root));
+            self.root.children_keys.push(new_root_key);
+            self.root.children_hashes.push(Hash::default());
+            let right_id = self.storage.lock().alloc_id()?;
             self.root.split_child(
                 0,
                 self.degree,
-                &mut self.storage,
+                right_id,
                 true,
                 &mut self.rng,
                 &mut self.updated,
-            )?;
+            );
         }
 
         let res = self.root.insert_nonfull::<C, R, S>(
             k,
             v,
             self.degree,
-            &mut self.storage,
+            &mut self.storage.lock(),
             true,
             &mut self.rng,
             &mut self.updated,
@@ -381,16 +1735,58 @@ where
         k: &BlockId,
     ) -> Result<Option<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
         // We do this to make it easier to mark updated nodes when removing.
-        if !self.contains(k)? {
+        if self.get(k)?.is_none() {
             return Ok(None);
         }
 
-        if let Some(entry) =
-            self.root
-                .remove::<C, S>(k, self.degree, &mut self.storage, &mut self.updated)?
-        {
+        let mut merged = vec![];
+        let entry = self.root.remove::<C, S>(
+            k,
+            self.degree,
+            &mut self.storage.lock(),
+            &mut merged,
+            &mut self.updated,
+        )?;
+
+        // The last persisted tree may still refer to a node merged away, as may a fork or a kept
+        // generation, so the tree only lets go of its object once a root that doesn't refer to
+        // it is persisted.
+        for node in merged {
+            let tracked = self
+                .refs
+                .as_ref()
+                .is_some_and(|refs| refs.lock().is_tracked(&node.id));
+            if node.persisted || tracked {
+                if self.refs.is_some() {
+                    self.origins.insert(node.id, node.stored_children);
+                }
+                self.stale.push(node.id);
+            } else {
+                self.storage.lock().dealloc_id(node.id)?;
+            }
+        }
+
+        if let Some(entry) = entry {
             if !self.root.is_leaf() && self.root.is_empty() {
-                self.root = self.root.children.pop().unwrap().as_option_owned().unwrap();
+                // The last child takes over the root's object, so that the root keeps its ID and
+                // the child's object, which the last persisted tree may refer to, is left alone.
+                let mut root = self.root.children.pop().unwrap().as_option_owned().unwrap();
+                let old_id = mem::replace(&mut root.id, self.root.id);
+                let child_persisted = mem::replace(&mut root.persisted, self.root.persisted);
+                self.root = root;
+
+                if self.updated.remove(&old_id) {
+                    self.updated.insert(self.root.id);
+                }
+                if child_persisted {
+                    if self.refs.is_some() {
+                        self.origins
+                            .insert(old_id, self.root.stored_children.clone());
+                    }
+                    self.stale.push(old_id);
+                } else {
+                    self.storage.lock().dealloc_id(old_id)?;
+                }
             }
             self.len -= 1;
             Ok(Some(entry))
@@ -399,10 +1795,79 @@ where
         }
     }
 
+    /// Like [`KeyManagementScheme::commit`], but returns the error if a node holding an updated
+    /// block can't be read in. The updates are then left pending, and committing again retries
+    /// them.
+    pub fn try_commit(&mut self) -> Result<Vec<BlockId>, Error<S::Error>> {
+        // Add any in-flight blocks that haven't been updated. Those already added by a commit
+        // that failed are left as they are.
+        let inflight_blocks = self
+            .in_flight_blocks
+            .iter()
+            .filter_map(|(k, v)| (!self.updated_blocks.contains(k)).then_some((*k, *v)))
+            .collect::<Vec<_>>();
+
+        for (block, key) in inflight_blocks.into_iter() {
+            self.insert_for_update(block, key)?;
+        }
+
+        // The nodes holding updated blocks, and those on the paths to them, may not be loaded if
+        // we were reloaded since they were updated, so bring them all in before re-keying any.
+        let updated_blocks = self.updated_blocks.iter().copied().collect::<Vec<_>>();
+        for block in updated_blocks {
+            self.get(&block)?;
+        }
+        self.root
+            .load_updated::<C, S>(&mut self.storage.lock(), &self.updated)?;
+
+        // This will commit our changes, changing keys as necesssary to updated nodes as blocks.
+        self.root
+            .commit(&mut self.rng, &self.updated, &self.updated_blocks);
+
+        // Clear out our cached updates.
+        self.updated.clear();
+        self.in_flight_blocks.clear();
+        Ok(self.updated_blocks.drain().collect())
+    }
+
+    /// Takes the error the last [`KeyManagementScheme::commit`] failed with, if it did. Nothing
+    /// was committed then, and the updates are left pending.
+    pub fn take_commit_error(&mut self) -> Option<Box<dyn std::error::Error + Send + Sync>> {
+        self.commit_error.take()
+    }
+
+    /// Removes every key. The root keeps its ID, and the objects of the other nodes are removed
+    /// once a root that doesn't refer to them is persisted, as with those merged away.
     pub fn clear(&mut self) -> Result<NodeId, Error<S::Error>> {
+        // Find what the tree lets go of before changing anything. A fork may still refer to a
+        // shared node, and so to everything below it, so only the tree's reference to that node
+        // is dropped.
+        let mut stale = vec![];
+        let mut unpersisted = vec![];
+        {
+            let mut storage = self.storage.lock();
+            let refs = self.refs.as_ref().map(|refs| refs.lock());
+            self.root
+                .visit_below::<C, S, _>(&mut storage, &mut |node| {
+                    let tracked = refs.as_ref().is_some_and(|refs| refs.is_tracked(&node.id));
+                    if node.persisted || tracked {
+                        stale.push(node.id);
+                    } else {
+                        unpersisted.push(node.id);
+                    }
+                    !refs.as_ref().is_some_and(|refs| refs.is_shared(&node.id))
+                })?;
+        }
+
         self.len = 0;
-        self.root.clear::<C, S>(&mut self.storage)?;
-        self.root = Node::new(self.storage.alloc_id()?);
+        self.root.clear();
+        self.updated.clear();
+        self.stale.extend(stale);
+        self.lookup_cache.get_mut().clear();
+        for id in unpersisted {
+            self.storage.lock().dealloc_id(id)?;
+        }
+
         Ok(self.root.id)
     }
 
@@ -417,6 +1882,7 @@ impl<R, S, C, const KEY_SZ: usize> KeyManagementScheme for BKeyTree<R, S, C, KEY
 where
     R: RngCore + CryptoRng + Default,
     S: Storage<Id = u64>,
+    S::Error: Send + Sync + 'static,
     C: Crypter,
 {
     type Key = Key<KEY_SZ>;
@@ -445,31 +1911,18 @@ where
     }
 
     // TODO: Fix in key management trait that commit can be fallible.
+    // Until then, an error leaves the updates pending for the next commit to retry and is kept
+    // for `take_commit_error()`, and `try_commit()` is there for callers that want to see it.
     fn commit(&mut self) -> Vec<Self::KeyId> {
-        // Add any in-flight blocks that haven't been updated.
-        let inflight_blocks = self
-            .in_flight_blocks
-            .iter()
-            .filter_map(|(k, v)| (!self.updated_blocks.contains(k)).then_some((*k, *v)))
-            .collect::<Vec<_>>();
-
-        for (block, key) in inflight_blocks.into_iter() {
-            self.insert_for_update(block, key).unwrap();
+        match self.try_commit() {
+            Ok(committed) => {
+                self.commit_error = None;
+                committed
+            }
+            Err(err) => {
+                self.commit_error = Some(Box::new(err));
+                vec![]
+            }
         }
-
-        // This will commit our changes, changing keys as necesssary to updated nodes as blocks.
-        self.root
-            .commit::<C, R, S>(
-                &mut self.storage,
-                &mut self.rng,
-                &self.updated,
-                &self.updated_blocks,
-            )
-            .unwrap();
-
-        // Clear out our cached updates.
-        self.updated.clear();
-        self.in_flight_blocks.clear();
-        self.updated_blocks.drain().collect()
     }
 }
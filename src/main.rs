@@ -0,0 +1,259 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use rand::RngCore;
+use sdbtree::{
+    storage::dir::{DirectoryStorage, Layout},
+    BKeyTree,
+};
+#[cfg(unix)]
+use std::os::unix::fs::OpenOptionsExt;
+use std::{
+    fmt::Write as _,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    path::Path,
+};
+
+const KEY_SZ: usize = 32;
+
+/// Inspects and manages a B-key tree stored in a directory.
+#[derive(Parser)]
+#[command(version)]
+struct Cli {
+    /// Directory holding the tree's objects.
+    #[arg(short, long)]
+    path: String,
+
+    /// File holding the root key.
+    #[arg(short, long)]
+    key: String,
+
+    /// ID of the root node (not needed for `init`).
+    #[arg(short, long)]
+    root: Option<u64>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Creates and persists an empty tree, writing a fresh root key to the key file.
+    Init {
+        /// Minimum degree of the tree.
+        #[arg(short, long, default_value_t = 2)]
+        degree: usize,
+
+        /// Levels of subdirectories to spread objects over (0 keeps them all in one directory).
+        #[arg(short, long, default_value_t = 0)]
+        fanout: u8,
+    },
+
+    /// Prints the key for a block.
+    Get { block: u64 },
+
+    /// Inserts a key (hex) for a block, generating a random one if it isn't given.
+    Insert { block: u64, key: Option<String> },
+
+    /// Removes a block and its key.
+    Remove { block: u64 },
+
+    /// Prints every block and its key.
+    Dump,
+
+    /// Prints statistics about the tree.
+    Stats {
+        /// Also count the objects in storage and their size, scanning all of it.
+        #[arg(short, long)]
+        storage: bool,
+    },
+
+    /// Checks the tree's invariants and lists objects the tree can't reach.
+    Verify,
+
+    /// Overwrites and removes every object the tree can't reach.
+    Gc,
+
+    /// Commits any pending key updates and persists the tree.
+    Persist,
+
+    /// Persists the tree under a fresh root key, replacing the key file.
+    Rekey,
+
+    /// Rewrites a tree persisted before roots were tagged with their format in the current one.
+    Migrate,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+
+    if let Command::Init { degree, fanout } = cli.command {
+        if Path::new(&cli.key).exists() {
+            bail!("key file {} already exists", cli.key);
+        }
+
+        let layout = match fanout {
+            0 => Layout::Flat,
+            levels => Layout::FanOut { levels },
+        };
+
+        let key = generate_key();
+        let storage = DirectoryStorage::with_layout(&cli.path, layout)?;
+        let mut tree: BKeyTree = BKeyTree::with_storage_and_degree(storage, degree)?;
+        tree.persist(key)?;
+        write_key(&cli.key, key)?;
+
+        println!("root {}", tree.root_id());
+        return Ok(());
+    }
+
+    let root = cli.root.ok_or_else(|| anyhow!("--root is required"))?;
+    let key = read_key(&cli.key)?;
+
+    if let Command::Migrate = cli.command {
+        let tree = BKeyTree::migrate(root, &cli.path, key)?;
+        println!("root {}", tree.root_id());
+        return Ok(());
+    }
+
+    // Commands that only inspect the tree can share it with other inspectors.
+    let mut tree = match cli.command {
+        Command::Get { .. } | Command::Dump | Command::Stats { .. } | Command::Verify => {
+            BKeyTree::reload_read_only(root, &cli.path, key)?
+        }
+        _ => BKeyTree::reload(root, &cli.path, key)?,
+    };
+
+    match cli.command {
+        Command::Init { .. } | Command::Migrate => unreachable!(),
+        Command::Get { block } => match tree.get(&block)? {
+            Some(key) => println!("{}", to_hex(key)),
+            None => bail!("block {block} not found"),
+        },
+        Command::Insert { block, key: value } => {
+            let value = match value {
+                Some(hex) => from_hex(&hex)?,
+                None => generate_key(),
+            };
+            if tree.insert(block, value)?.is_some() {
+                bail!("block {block} already has a key");
+            }
+            tree.persist(key)?;
+            println!("{} {}", block, to_hex(&value));
+            println!("root {}", tree.root_id());
+        }
+        Command::Remove { block } => {
+            if tree.remove(&block)?.is_none() {
+                bail!("block {block} not found");
+            }
+            tree.persist(key)?;
+            println!("root {}", tree.root_id());
+        }
+        Command::Dump => {
+            for (block, key) in tree.entries()? {
+                println!("{} {}", block, to_hex(&key));
+            }
+        }
+        Command::Stats { storage } => {
+            let stats = if storage {
+                tree.stats_with_storage()?
+            } else {
+                tree.stats()?
+            };
+            println!("root {}", tree.root_id());
+            println!("{stats}");
+        }
+        Command::Verify => {
+            tree.verify()?;
+            for id in tree.orphans()? {
+                println!("orphan {id}");
+            }
+            println!("ok");
+        }
+        Command::Gc => {
+            let removed = tree.gc()?;
+            println!("removed {} objects", removed.len());
+        }
+        Command::Persist => {
+            let committed = tree.try_commit()?;
+            tree.persist(key)?;
+            println!("committed {} blocks", committed.len());
+            println!("root {}", tree.root_id());
+        }
+        Command::Rekey => {
+            // Stage the new key first so that it isn't lost if we crash after persisting.
+            let new_key = generate_key();
+            let staged = format!("{}.new", cli.key);
+            write_key(&staged, new_key)?;
+            tree.persist(new_key)?;
+            fs::rename(&staged, &cli.key)
+                .with_context(|| format!("couldn't replace key file {}", cli.key))?;
+            sync_parent(&cli.key)?;
+            println!("root {}", tree.root_id());
+        }
+    }
+
+    Ok(())
+}
+
+fn generate_key() -> [u8; KEY_SZ] {
+    let mut key = [0; KEY_SZ];
+    rand::thread_rng().fill_bytes(&mut key);
+    key
+}
+
+fn read_key(path: &str) -> Result<[u8; KEY_SZ]> {
+    let raw = fs::read(path).with_context(|| format!("couldn't read key file {path}"))?;
+    raw.try_into()
+        .map_err(|_| anyhow!("key file {path} must hold exactly {KEY_SZ} bytes"))
+}
+
+/// Writes `key` to a file only the owner can read, and syncs it, so that it's durable before
+/// anything is encrypted under it.
+fn write_key(path: &str, key: [u8; KEY_SZ]) -> Result<()> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+
+    let mut file = options
+        .open(path)
+        .with_context(|| format!("couldn't create key file {path}"))?;
+    file.write_all(&key)
+        .and_then(|_| file.sync_all())
+        .with_context(|| format!("couldn't write key file {path}"))
+}
+
+/// Syncs the directory holding `path`, so that a rename into it is durable.
+fn sync_parent(path: &str) -> Result<()> {
+    let dir = match Path::new(path).parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    File::open(dir)
+        .and_then(|dir| dir.sync_all())
+        .with_context(|| format!("couldn't sync directory {}", dir.display()))
+}
+
+/// Formats `bytes` as lowercase hex, as keys are shown in exports.
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Result<[u8; KEY_SZ]> {
+    // Digits are sliced out by byte, which only lines up with characters in ASCII.
+    if !hex.is_ascii() || hex.len() != 2 * KEY_SZ {
+        bail!("key must be {} hex digits", 2 * KEY_SZ);
+    }
+
+    let mut key = [0; KEY_SZ];
+    for (i, byte) in key.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16)
+            .map_err(|_| anyhow!("invalid hex digit in key"))?;
+    }
+
+    Ok(key)
+}
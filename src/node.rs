@@ -1,7 +1,18 @@
-use crate::{error::Error, utils, BlockId, Key, NodeId};
+#[cfg(feature = "async")]
+mod asynch;
+
+use crate::{error::Error, proof::ProofNode, utils, BlockId, Hash, Key, NodeId};
 use crypter::Crypter;
+use embedded_io::blocking::Write;
+use openssl::sha::{sha256, Sha256};
 use rand::{CryptoRng, RngCore};
-use std::{cmp::Ordering, collections::HashSet, mem};
+use std::{
+    cmp::Ordering,
+    collections::{HashMap, HashSet},
+    mem,
+    ops::{Bound, RangeBounds},
+    sync::Arc,
+};
 use storage::Storage;
 
 pub enum Child<const KEY_SZ: usize> {
@@ -25,12 +36,36 @@ impl<const KEY_SZ: usize> Child<KEY_SZ> {
     }
 }
 
+/// What's left to do after a [`Node::remove_step`].
+pub(crate) enum RemoveStep<const KEY_SZ: usize> {
+    /// Child `idx` has to be loaded before the step can go on.
+    Load(usize),
+    /// The removal is finished, with the removed entry if there was one.
+    Done(Option<(BlockId, Key<KEY_SZ>)>),
+    /// The entry at `idx` has to be replaced by the largest one under child `idx`.
+    Predecessor(usize),
+    /// The entry at `idx` has to be replaced by the smallest one under child `idx + 1`.
+    Successor(usize),
+    /// The removal goes on in child `idx`, after letting go of a node merged away.
+    Descend(usize, Option<Node<KEY_SZ>>),
+}
+
 pub struct Node<const KEY_SZ: usize> {
     pub(crate) id: NodeId,
     pub(crate) keys: Vec<BlockId>,
     pub(crate) vals: Vec<Key<KEY_SZ>>,
     pub(crate) children: Vec<Child<KEY_SZ>>,
     pub(crate) children_keys: Vec<Key<KEY_SZ>>,
+    // The hashes of the children as they were last persisted, which are checked when they're
+    // read back in. Those of loaded children are brought up to date when they're persisted.
+    pub(crate) children_hashes: Vec<Hash>,
+    // Whether the last persisted tree may refer to the node's object.
+    pub(crate) persisted: bool,
+    // Whether the node changed since it was last written out, or never was.
+    pub(crate) dirty: bool,
+    // The children of the node's object as it was last read or written, which forks still
+    // referring to that object refer to. Leaves have none, so this costs little.
+    pub(crate) stored_children: Vec<NodeId>,
 }
 
 impl<const KEY_SZ: usize> Node<KEY_SZ> {
@@ -41,6 +76,10 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
             vals: Vec::new(),
             children: Vec::new(),
             children_keys: Vec::new(),
+            children_hashes: Vec::new(),
+            persisted: false,
+            dirty: true,
+            stored_children: Vec::new(),
         }
     }
 
@@ -68,68 +107,289 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         // Acquire a read handle.
         let mut reader = storage.read_handle(&id)?;
 
+        Self::read_from::<C, S>(id, key, &mut reader)
+    }
+
+    /// Like `load()`, but checks that the node hashes to `hash`, the hash its parent holds for
+    /// it, so that a node swapped for another or for an older version of itself is refused.
+    pub(crate) fn load_child<C, S>(
+        id: u64,
+        key: Key<KEY_SZ>,
+        hash: &Hash,
+        storage: &mut S,
+    ) -> Result<Self, Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        let node = Self::load::<C, S>(id, key, storage)?;
+        node.check_hash(hash)?;
+        Ok(node)
+    }
+
+    pub(crate) fn check_hash<E>(&self, hash: &Hash) -> Result<(), Error<E>> {
+        if self.hash() != *hash {
+            return Err(Error::HashMismatch(self.id));
+        }
+        Ok(())
+    }
+
+    /// Returns the hash of this node, which commits to its blocks, their keys and, through the
+    /// hashes of its children, to everything below it. Keys only enter through their own hashes.
+    pub(crate) fn hash(&self) -> Hash {
+        hash_node(
+            &self.keys,
+            self.vals.iter().map(|val| sha256(val)),
+            &self.children_hashes,
+        )
+    }
+
+    /// Returns the keys the node's fields are encrypted under, one for each, all derived from
+    /// `key`. Each field is encrypted from the start of its key's keystream, so under one key, a
+    /// field whose contents are known, like the block IDs and hashes a proof gives away, would
+    /// give away the keystream to decrypt the others with.
+    fn field_keys<E>(key: Key<KEY_SZ>) -> Result<[Key<KEY_SZ>; 5], Error<E>> {
+        const FIELDS: [&[u8]; 5] = [
+            b"sdbtree node keys",
+            b"sdbtree node vals",
+            b"sdbtree node children",
+            b"sdbtree node children keys",
+            b"sdbtree node children hashes",
+        ];
+
+        let mut keys = [[0; KEY_SZ]; 5];
+        for (field_key, field) in keys.iter_mut().zip(FIELDS) {
+            *field_key = utils::derive_key(&key, field)?;
+        }
+        Ok(keys)
+    }
+
+    pub(crate) fn read_from<C, S>(
+        id: u64,
+        key: Key<KEY_SZ>,
+        reader: &mut S::ReadHandle<'_>,
+    ) -> Result<Self, Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
         // Read the fields, each of which is serialized as a length-prefixed array of bytes.
-        let keys_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?;
-        let vals_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?;
-        let children_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?;
+        let [keys_key, vals_key, children_key, children_keys_key, children_hashes_key] =
+            Self::field_keys::<S::Error>(key)?;
+        let keys_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(reader, keys_key)?;
+        let vals_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(reader, vals_key)?;
+        let children_raw = utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(reader, children_key)?;
+        let children_keys_raw =
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(reader, children_keys_key)?;
+        let children_hashes_raw =
+            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(reader, children_hashes_key)?;
+
+        Self::from_fields(
+            id,
+            &keys_raw,
+            &vals_raw,
+            &children_raw,
+            &children_keys_raw,
+            &children_hashes_raw,
+        )
+    }
+
+    /// Like `read_from()`, but decodes the node from the front of `raw`.
+    #[cfg(feature = "async")]
+    pub(crate) fn decode<C, E>(id: u64, key: Key<KEY_SZ>, raw: &mut &[u8]) -> Result<Self, Error<E>>
+    where
+        C: Crypter,
+    {
+        let [keys_key, vals_key, children_key, children_keys_key, children_hashes_key] =
+            Self::field_keys::<E>(key)?;
+        let keys_raw = utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(raw, keys_key)?;
+        let vals_raw = utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(raw, vals_key)?;
+        let children_raw = utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(raw, children_key)?;
         let children_keys_raw =
-            utils::read_length_prefixed_bytes::<C, S, KEY_SZ>(&mut reader, key)?;
+            utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(raw, children_keys_key)?;
+        let children_hashes_raw =
+            utils::take_length_prefixed_bytes::<C, E, KEY_SZ>(raw, children_hashes_key)?;
+
+        Self::from_fields(
+            id,
+            &keys_raw,
+            &vals_raw,
+            &children_raw,
+            &children_keys_raw,
+            &children_hashes_raw,
+        )
+    }
+
+    pub(crate) fn from_fields<E>(
+        id: u64,
+        keys_raw: &[u8],
+        vals_raw: &[u8],
+        children_raw: &[u8],
+        children_keys_raw: &[u8],
+        children_hashes_raw: &[u8],
+    ) -> Result<Self, Error<E>> {
+        let keys = utils::deserialize_ids(keys_raw)?;
+        let vals = utils::deserialize_keys(vals_raw)?;
+        let children = utils::deserialize_ids(children_raw)?;
+        let children_keys = utils::deserialize_keys(children_keys_raw)?;
+        let children_hashes = utils::deserialize_keys(children_hashes_raw)?;
+
+        // Reject shapes that the rest of the tree would index into out of bounds.
+        if vals.len() != keys.len()
+            || children_keys.len() != children.len()
+            || children_hashes.len() != children.len()
+            || !(children.is_empty() || children.len() == keys.len() + 1)
+        {
+            return Err(Error::Deserialization);
+        }
 
         Ok(Self {
             id,
-            keys: utils::deserialize_ids(&keys_raw),
-            vals: utils::deserialize_keys(&vals_raw),
-            children: utils::deserialize_ids(&children_raw)
-                .into_iter()
-                .map(|id| Child::Unloaded(id))
-                .collect(),
-            children_keys: utils::deserialize_keys(&children_keys_raw),
+            keys,
+            vals,
+            children: children.iter().copied().map(Child::Unloaded).collect(),
+            children_keys,
+            children_hashes,
+            persisted: true,
+            dirty: false,
+            stored_children: children,
         })
     }
 
-    pub fn persist<C, S>(&self, key: Key<KEY_SZ>, storage: &mut S) -> Result<(), Error<S::Error>>
+    /// Persists this node and every loaded node below it, children first, returning whether
+    /// this node was written out. See `relocate()`.
+    pub fn persist<C, S>(
+        &mut self,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<bool, Error<S::Error>>
     where
         C: Crypter,
         S: Storage<Id = u64>,
     {
-        self.persist_node::<C, S>(key, storage)?;
+        self.persist_children::<C, S>(storage, stale, updated)?;
+        self.relocate::<C, S>(key, storage, stale, updated)
+    }
 
-        for (i, child) in self.children.iter().enumerate() {
-            match child {
-                Child::Loaded(node) => {
-                    node.persist::<C, S>(self.children_keys[i], storage)?;
+    /// Persists every loaded node below this one, but not this node itself, which is marked
+    /// dirty if any of its children were written out.
+    pub fn persist_children<C, S>(
+        &mut self,
+        storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        for (i, child) in self.children.iter_mut().enumerate() {
+            if let Child::Loaded(node) = child {
+                if node.persist::<C, S>(self.children_keys[i], storage, stale, updated)? {
+                    self.children_hashes[i] = node.hash();
+                    self.dirty = true;
                 }
-                _ => {}
             }
         }
 
         Ok(())
     }
 
+    /// Returns whether any node on the path to `block` has changes to persist, or `None` if
+    /// `block` isn't in this subtree.
+    pub fn changed_on_path<C, S>(
+        &mut self,
+        block: &BlockId,
+        storage: &mut S,
+        updated: &HashSet<NodeId>,
+    ) -> Result<Option<bool>, Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        let changed = self.dirty || updated.contains(&self.id);
+        let idx = self.find_index(block);
+        if idx < self.len() && self.keys[idx] == *block {
+            return Ok(Some(changed));
+        } else if self.is_leaf() {
+            return Ok(None);
+        }
+
+        let res = self
+            .access_child::<C, S>(idx, storage)?
+            .changed_on_path::<C, S>(block, storage, updated)?;
+        Ok(res.map(|below| changed || below))
+    }
+
+    /// Persists the nodes below this one on the path to `block`, deepest first, but not this
+    /// node itself. See `relocate()`.
     pub fn persist_block<C, S>(
         &mut self,
         block: &BlockId,
-        mut key: Key<KEY_SZ>,
         storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
     ) -> Result<bool, Error<S::Error>>
     where
         C: Crypter,
         S: Storage<Id = u64>,
     {
-        let mut node = self;
-        loop {
-            node.persist_node::<C, S>(key, storage)?;
+        let idx = self.find_index(block);
+        if idx < self.len() && self.keys[idx] == *block {
+            return Ok(true);
+        } else if self.is_leaf() {
+            return Ok(false);
+        }
 
-            let idx = node.find_index(block);
-            if idx < node.len() && node.keys[idx] == *block {
-                return Ok(true);
-            } else if node.is_leaf() {
-                return Ok(false);
-            } else {
-                key = node.children_keys[idx];
-                node = node.access_child::<C, S>(idx, storage)?;
+        let key = self.children_keys[idx];
+        let child = self.access_child::<C, S>(idx, storage)?;
+        let res = child.persist_block::<C, S>(block, storage, stale, updated)?;
+        if child.relocate::<C, S>(key, storage, stale, updated)? {
+            let hash = child.hash();
+            self.children_hashes[idx] = hash;
+            self.dirty = true;
+        }
+
+        Ok(res)
+    }
+
+    /// Writes this node out without touching anything the last persisted tree refers to, if it's
+    /// dirty or marked as updated, returning whether it was. If that tree may refer to the
+    /// node's object, the node moves to a fresh object and its old ID is pushed onto `stale`, to
+    /// be removed once the new tree is in place. Since the new ID only reaches storage through
+    /// the parent, the parent has to be written afterwards.
+    fn relocate<C, S>(
+        &mut self,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<bool, Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        if !self.dirty && !updated.contains(&self.id) {
+            return Ok(false);
+        }
+
+        if self.persisted {
+            let old_id = mem::replace(&mut self.id, storage.alloc_id()?);
+            stale.push(old_id);
+
+            if updated.remove(&old_id) {
+                updated.insert(self.id);
             }
         }
+
+        self.persist_node::<C, S>(key, storage)?;
+        self.persisted = true;
+        self.dirty = false;
+        self.stored_children = self.child_ids();
+
+        Ok(true)
     }
 
     pub fn persist_node<C, S>(
@@ -140,35 +400,61 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
     where
         C: Crypter,
         S: Storage<Id = u64>,
+    {
+        // Write the node's new contents, then swap them in for the old ones.
+        {
+            let mut writer = storage.replace_handle(&self.id)?;
+            self.write_to::<C, S>(key, &mut writer)?;
+        }
+
+        Ok(storage.commit_handle(&self.id)?)
+    }
+
+    pub(crate) fn write_to<C, S>(
+        &self,
+        key: Key<KEY_SZ>,
+        writer: &mut S::WriteHandle<'_>,
+    ) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        writer
+            .write_all(&self.encode::<C, S::Error>(key)?)
+            .map_err(|_| Error::Write)
+    }
+
+    /// Returns the node as it's written to storage.
+    pub(crate) fn encode<C, E>(&self, key: Key<KEY_SZ>) -> Result<Vec<u8>, Error<E>>
+    where
+        C: Crypter,
     {
         // Serialize the keys and values.
         // This should really be done in one shot.
         let keys_raw = utils::serialize_ids(&self.keys);
         let vals_raw = utils::serialize_keys(&self.vals);
         let children_keys_raw = utils::serialize_keys(&self.children_keys);
+        let children_hashes_raw = utils::serialize_keys(&self.children_hashes);
 
         // Serialize the children IDs.
-        let children_raw = utils::serialize_ids(
-            &self
-                .children
-                .iter()
-                .map(|child| match child {
-                    Child::Loaded(node) => node.id,
-                    Child::Unloaded(id) => *id,
-                })
-                .collect::<Vec<_>>(),
-        );
-
-        // Acquire a write handle.
-        let mut writer = storage.write_handle(&self.id)?;
-
-        // Write each of the fields as a length-prefixed array of bytes.
-        utils::write_length_prefixed_bytes::<C, S, KEY_SZ>(&mut writer, &keys_raw, key)?;
-        utils::write_length_prefixed_bytes::<C, S, KEY_SZ>(&mut writer, &vals_raw, key)?;
-        utils::write_length_prefixed_bytes::<C, S, KEY_SZ>(&mut writer, &children_raw, key)?;
-        utils::write_length_prefixed_bytes::<C, S, KEY_SZ>(&mut writer, &children_keys_raw, key)?;
+        let children_raw = utils::serialize_ids(&self.child_ids());
+
+        // Each of the fields is a length-prefixed array of bytes, under a key of its own.
+        let mut raw = vec![];
+        for (field_raw, field_key) in [
+            &keys_raw,
+            &vals_raw,
+            &children_raw,
+            &children_keys_raw,
+            &children_hashes_raw,
+        ]
+        .into_iter()
+        .zip(Self::field_keys::<E>(key)?)
+        {
+            utils::push_length_prefixed_bytes::<C, E, KEY_SZ>(&mut raw, field_raw, field_key)?;
+        }
 
-        Ok(())
+        Ok(raw)
     }
 
     fn find_index(&self, k: &BlockId) -> usize {
@@ -202,8 +488,12 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
     {
         match self.children[idx] {
             Child::Unloaded(id) => {
-                self.children[idx] =
-                    Child::Loaded(Node::load::<C, S>(id, self.children_keys[idx], storage)?);
+                self.children[idx] = Child::Loaded(Node::load_child::<C, S>(
+                    id,
+                    self.children_keys[idx],
+                    &self.children_hashes[idx],
+                    storage,
+                )?);
             }
             _ => {}
         }
@@ -232,6 +522,132 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         }
     }
 
+    /// Looks up `k` without changing the tree, getting the children that aren't loaded from
+    /// `load`.
+    pub(crate) fn lookup<E>(
+        &self,
+        k: &BlockId,
+        load: &impl Fn(NodeId, Key<KEY_SZ>, &Hash) -> Result<Arc<Node<KEY_SZ>>, Error<E>>,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<E>> {
+        let idx = self.find_index(k);
+        if idx < self.len() && self.keys[idx] == *k {
+            Ok(Some(self.vals[idx]))
+        } else if self.is_leaf() {
+            Ok(None)
+        } else {
+            self.with_child(idx, load, |child| child.lookup(k, load))
+        }
+    }
+
+    /// Appends the nodes on the path to `k` to `path`, starting with this one and ending with
+    /// the node holding `k` or the leaf it would be in, getting the children that aren't loaded
+    /// from `load`.
+    pub(crate) fn prove_into<E>(
+        &self,
+        k: &BlockId,
+        load: &impl Fn(NodeId, Key<KEY_SZ>, &Hash) -> Result<Arc<Node<KEY_SZ>>, Error<E>>,
+        path: &mut Vec<ProofNode>,
+    ) -> Result<(), Error<E>> {
+        path.push(ProofNode {
+            keys: self.keys.clone(),
+            val_hashes: self.vals.iter().map(|val| sha256(val)).collect(),
+            children_hashes: self.children_hashes.clone(),
+        });
+
+        let idx = self.find_index(k);
+        if (idx < self.len() && self.keys[idx] == *k) || self.is_leaf() {
+            Ok(())
+        } else {
+            self.with_child(idx, load, |child| child.prove_into(k, load, path))
+        }
+    }
+
+    /// Appends the entries of this subtree that fall within `range` to `entries`, in order,
+    /// getting the children that aren't loaded from `load`.
+    pub(crate) fn range_into<E>(
+        &self,
+        range: &impl RangeBounds<BlockId>,
+        load: &impl Fn(NodeId, Key<KEY_SZ>, &Hash) -> Result<Arc<Node<KEY_SZ>>, Error<E>>,
+        entries: &mut Vec<(BlockId, Key<KEY_SZ>)>,
+    ) -> Result<(), Error<E>> {
+        let before_start = |k: BlockId| match range.start_bound() {
+            Bound::Included(start) => k < *start,
+            Bound::Excluded(start) => k <= *start,
+            Bound::Unbounded => false,
+        };
+        let after_end = |k: BlockId| match range.end_bound() {
+            Bound::Included(end) => k > *end,
+            Bound::Excluded(end) => k >= *end,
+            Bound::Unbounded => false,
+        };
+
+        for idx in 0..=self.len() {
+            // Child `idx` holds the keys between the ones at `idx - 1` and `idx`, so either of
+            // those can rule it out.
+            let past_end = idx > 0 && after_end(self.keys[idx - 1]);
+            let before = idx < self.len() && before_start(self.keys[idx]);
+            if !self.is_leaf() && !past_end && !before {
+                self.with_child(idx, load, |child| child.range_into(range, load, entries))?;
+            }
+
+            if idx < self.len() && range.contains(&self.keys[idx]) {
+                entries.push((self.keys[idx], self.vals[idx]));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn with_child<E, T>(
+        &self,
+        idx: usize,
+        load: &impl Fn(NodeId, Key<KEY_SZ>, &Hash) -> Result<Arc<Node<KEY_SZ>>, Error<E>>,
+        f: impl FnOnce(&Node<KEY_SZ>) -> Result<T, Error<E>>,
+    ) -> Result<T, Error<E>> {
+        match &self.children[idx] {
+            Child::Loaded(child) => f(child),
+            Child::Unloaded(id) => f(&*load(
+                *id,
+                self.children_keys[idx],
+                &self.children_hashes[idx],
+            )?),
+        }
+    }
+
+    /// Returns a copy of this node with every child unloaded, which is how it was last written
+    /// if it was just persisted.
+    pub(crate) fn unloaded_copy(&self) -> Self {
+        Self {
+            id: self.id,
+            keys: self.keys.clone(),
+            vals: self.vals.clone(),
+            children: self
+                .children
+                .iter()
+                .map(|child| match child {
+                    Child::Loaded(node) => Child::Unloaded(node.id),
+                    Child::Unloaded(id) => Child::Unloaded(*id),
+                })
+                .collect(),
+            children_keys: self.children_keys.clone(),
+            children_hashes: self.children_hashes.clone(),
+            persisted: self.persisted,
+            dirty: self.dirty,
+            stored_children: self.stored_children.clone(),
+        }
+    }
+
+    /// Returns the IDs of this node's children, loaded or not.
+    pub(crate) fn child_ids(&self) -> Vec<NodeId> {
+        self.children
+            .iter()
+            .map(|child| match child {
+                Child::Loaded(node) => node.id,
+                Child::Unloaded(id) => *id,
+            })
+            .collect()
+    }
+
     pub fn get_mut<C, S>(
         &mut self,
         k: &BlockId,
@@ -245,6 +661,8 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         loop {
             let idx = node.find_index(k);
             if idx < node.len() && node.keys[idx] == *k {
+                // The caller may change the entry, so the node has to be written out again.
+                node.dirty = true;
                 return Ok(Some((idx, node)));
             } else if node.is_leaf() {
                 return Ok(None);
@@ -254,24 +672,23 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         }
     }
 
-    pub fn split_child<R, S>(
+    /// Splits the full child `idx` in two, moving its upper half into a new node `right_id`.
+    pub fn split_child<R>(
         &mut self,
         idx: usize,
         degree: usize,
-        storage: &mut S,
+        right_id: NodeId,
         for_update: bool,
         rng: &mut R,
         updated: &mut HashSet<NodeId>,
-    ) -> Result<(), Error<S::Error>>
-    where
+    ) where
         R: RngCore + CryptoRng,
-        S: Storage<Id = u64>,
     {
         assert!(!self.is_full(degree));
         // assert!(self.children[idx].is_full(degree));
 
         let left = self.children[idx].as_option_mut().unwrap();
-        let mut right = Self::new(storage.alloc_id()?);
+        let mut right = Self::new(right_id);
         let right_key = utils::generate_key(rng);
 
         // Move the largest keys and values from the left to the right.
@@ -288,9 +705,14 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
             right
                 .children_keys
                 .extend(left.children_keys.drain(degree..));
+            right
+                .children_hashes
+                .extend(left.children_hashes.drain(degree..));
         }
 
         // Mark all the nodes we touched.
+        self.dirty = true;
+        left.dirty = true;
         if for_update {
             updated.insert(self.id);
             updated.insert(left.id);
@@ -302,8 +724,7 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         self.vals.insert(idx, val);
         self.children.insert(idx + 1, Child::Loaded(right));
         self.children_keys.insert(idx + 1, right_key);
-
-        Ok(())
+        self.children_hashes.insert(idx + 1, Hash::default());
     }
 
     pub fn insert_nonfull<C, R, S>(
@@ -334,25 +755,29 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
                 updated.insert(node.id);
             }
 
+            // If key already exists, we'll return that instead.
+            if idx < node.len() && k == node.keys[idx] {
+                return Ok(Some(node.vals[idx]));
+            }
+
             // Insert key and value into non-full node.
             if node.is_leaf() {
-                if idx < node.len() && k == node.keys[idx] {
-                    // If key already exists, we'll return that instead
-                    return Ok(Some(node.vals[idx]));
-                } else {
-                    // The key doesn't exist yet.
-                    node.keys.insert(idx, k);
-                    node.vals.insert(idx, v);
-                    return Ok(None);
-                }
+                node.keys.insert(idx, k);
+                node.vals.insert(idx, v);
+                node.dirty = true;
+                return Ok(None);
             }
             // Otherwise, we recurse downwards.
             else {
                 if node.access_child::<C, S>(idx, storage)?.is_full(degree) {
                     // Split the child and determine which child to recurse down.
-                    node.split_child(idx, degree, storage, for_update, rng, updated)?;
-                    if node.keys[idx] < k {
-                        idx += 1;
+                    // The median that moved up may be the key itself.
+                    let right_id = storage.alloc_id()?;
+                    node.split_child(idx, degree, right_id, for_update, rng, updated);
+                    match node.keys[idx].cmp(&k) {
+                        Ordering::Less => idx += 1,
+                        Ordering::Equal => return Ok(Some(node.vals[idx])),
+                        Ordering::Greater => {}
                     }
                 }
                 node = node.access_child::<C, S>(idx, storage)?;
@@ -392,132 +817,185 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
         Ok(node.keys.last().unwrap())
     }
 
-    // TODO: This could be implemented better with less redundant inserts to updated.
+    /// Removes `k` from this subtree. Nodes merged away are pushed onto `freed` rather than
+    /// deallocated, since the tree may still have to keep their objects around.
     pub fn remove<C, S>(
         &mut self,
         k: &BlockId,
         degree: usize,
         storage: &mut S,
+        freed: &mut Vec<Node<KEY_SZ>>,
         updated: &mut HashSet<NodeId>,
     ) -> Result<Option<(BlockId, Key<KEY_SZ>)>, Error<S::Error>>
     where
         C: Crypter,
         S: Storage<Id = u64>,
     {
+        loop {
+            match self.remove_step(k, degree, updated) {
+                RemoveStep::Load(idx) => {
+                    self.access_child::<C, S>(idx, storage)?;
+                }
+                RemoveStep::Done(entry) => return Ok(entry),
+                RemoveStep::Predecessor(idx) => {
+                    let pred = self.loaded_child(idx);
+
+                    // Replace key with the predecessor key and recursively delete it.
+                    // The key is copied out since the removal may shuffle the node it lives in.
+                    let pred_key = *pred.max_key::<C, S>(storage)?;
+                    let (pred_key, pred_val) = pred
+                        .remove::<C, S>(&pred_key, degree, storage, freed, updated)?
+                        .unwrap();
+
+                    // Update the nodes that were modified.
+                    updated.insert(pred.id);
+
+                    return Ok(Some(self.replace_entry(idx, pred_key, pred_val)));
+                }
+                RemoveStep::Successor(idx) => {
+                    let succ = self.loaded_child(idx + 1);
+
+                    // Replace key with the successor key and recursively delete it.
+                    // The key is copied out since the removal may shuffle the node it lives in.
+                    let succ_key = *succ.min_key::<C, S>(storage)?;
+                    let (succ_key, succ_val) = succ
+                        .remove::<C, S>(&succ_key, degree, storage, freed, updated)?
+                        .unwrap();
+
+                    // Update the nodes that were modified.
+                    updated.insert(succ.id);
+
+                    return Ok(Some(self.replace_entry(idx, succ_key, succ_val)));
+                }
+                RemoveStep::Descend(idx, merged) => {
+                    freed.extend(merged);
+                    return self
+                        .loaded_child(idx)
+                        .remove::<C, S>(k, degree, storage, freed, updated);
+                }
+            }
+        }
+    }
+
+    /// Does as much of the removal of `k` from this subtree as can be done without storage,
+    /// returning what's left to do. Nothing is changed before a needed child is loaded, so the
+    /// step can simply be retried after loading it.
+    // TODO: This could be implemented better with less redundant inserts to updated.
+    pub(crate) fn remove_step(
+        &mut self,
+        k: &BlockId,
+        degree: usize,
+        updated: &mut HashSet<NodeId>,
+    ) -> RemoveStep<KEY_SZ> {
         // Update the nodes that were modified.
         updated.insert(self.id);
 
-        let mut idx = self.find_index(k);
+        let idx = self.find_index(k);
 
         // Case 1: Key found in node and node is a leaf.
         if idx < self.len() && self.keys[idx] == *k && self.is_leaf() {
+            self.dirty = true;
             let key = self.keys.remove(idx);
             let val = self.vals.remove(idx);
-            return Ok(Some((key, val)));
+            return RemoveStep::Done(Some((key, val)));
         }
 
         // Case 2: Key found in node and node is an internal node.
         if idx < self.len() && self.keys[idx] == *k && !self.is_leaf() {
-            if self.access_child::<C, S>(idx, storage)?.len() >= degree {
+            if !self.is_child_loaded(idx) {
+                return RemoveStep::Load(idx);
+            }
+            if self.loaded_child(idx).len() >= degree {
                 // Case 2a: Child node that precedes k has at least t keys.
-                let pred = &mut self.children[idx].as_option_mut().unwrap();
-
-                // Replace key with the predecessor key and recursively delete it.
-                // Safety: we won't ever use the reference past this point.
-                let pred_key = pred.max_key::<C, S>(storage)? as *const _;
-                let (mut pred_key, mut pred_val) = pred
-                    .remove::<C, S>(unsafe { &*pred_key }, degree, storage, updated)?
-                    .unwrap();
-
-                // The actual replacement.
-                mem::swap(&mut self.keys[idx], &mut pred_key);
-                mem::swap(&mut self.vals[idx], &mut pred_val);
-
-                // Update the nodes that were modified.
-                updated.insert(pred.id);
+                return RemoveStep::Predecessor(idx);
+            }
 
-                return Ok(Some((pred_key, pred_val)));
-            } else if self.access_child::<C, S>(idx + 1, storage)?.len() >= degree {
+            if !self.is_child_loaded(idx + 1) {
+                return RemoveStep::Load(idx + 1);
+            }
+            if self.loaded_child(idx + 1).len() >= degree {
                 // Case 2b: Child node that succeeds k has at least t keys.
-                let succ = &mut self.children[idx + 1].as_option_mut().unwrap();
-
-                // Replace key with the successor key and recursively delete it.
-                // Safety: we don't ever use the reference past this point.
-                let succ_key = succ.min_key::<C, S>(storage)? as *const _;
-                let (mut succ_key, mut succ_val) = succ
-                    .remove::<C, S>(unsafe { &*succ_key }, degree, storage, updated)?
-                    .unwrap();
-
-                // The actual replacement.
-                mem::swap(&mut self.keys[idx], &mut succ_key);
-                mem::swap(&mut self.vals[idx], &mut succ_val);
+                return RemoveStep::Successor(idx);
+            }
 
-                // Update the nodes that were modified.
-                updated.insert(succ.id);
+            // Case 2c: Successor and predecessor only have t - 1 keys.
+            self.dirty = true;
+            let key = self.keys.remove(idx);
+            let val = self.vals.remove(idx);
 
-                return Ok(Some((succ_key, succ_val)));
-            } else {
-                // Case 2c: Successor and predecessor only have t - 1 keys.
-                let key = self.keys.remove(idx);
-                let val = self.vals.remove(idx);
-
-                let mut succ = self.children.remove(idx + 1).as_option_owned().unwrap();
-                let _succ_key = self.children_keys.remove(idx + 1);
-
-                let pred = &mut self.children[idx].as_option_mut().unwrap();
-
-                // Merge keys, values, and children into predecessor.
-                pred.keys.push(key);
-                pred.vals.push(val);
-                pred.keys.append(&mut succ.keys);
-                pred.vals.append(&mut succ.vals);
-                pred.children.append(&mut succ.children);
-                pred.children_keys.append(&mut succ.children_keys);
-                assert!(pred.is_full(degree));
-
-                // Deallocate the successor.
-                // This is the only case in which a node completely disappears.
-                storage.dealloc_id(succ.id)?;
-
-                // Update the nodes that were modified.
-                // Since the successor doesn't exist anymore, we can remove it.
-                updated.remove(&succ.id);
-                updated.insert(pred.id);
-
-                return pred.remove::<C, S>(k, degree, storage, updated);
-            }
+            let mut succ = self.children.remove(idx + 1).as_option_owned().unwrap();
+            let _succ_key = self.children_keys.remove(idx + 1);
+            let _succ_hash = self.children_hashes.remove(idx + 1);
+
+            let pred = self.loaded_child(idx);
+
+            // Merge keys, values, and children into predecessor.
+            pred.keys.push(key);
+            pred.vals.push(val);
+            pred.keys.append(&mut succ.keys);
+            pred.vals.append(&mut succ.vals);
+            pred.children.append(&mut succ.children);
+            pred.children_keys.append(&mut succ.children_keys);
+            pred.children_hashes.append(&mut succ.children_hashes);
+            assert!(pred.is_full(degree));
+
+            // Update the nodes that were modified.
+            // Since the successor doesn't exist anymore, we can remove it.
+            updated.remove(&succ.id);
+            pred.dirty = true;
+            updated.insert(pred.id);
+
+            // The successor gets deallocated.
+            // This is the only case in which a node completely disappears.
+            return RemoveStep::Descend(idx, Some(succ));
         }
 
         // If on a leaf, then no appropriate subtree contains the key.
         if self.is_leaf() {
-            return Ok(None);
+            return RemoveStep::Done(None);
         }
 
         // Case 3: Key not found in internal node.
-        if self.access_child::<C, S>(idx, storage)?.len() + 1 == degree {
-            if idx > 0 && self.access_child::<C, S>(idx - 1, storage)?.len() >= degree {
+        if !self.is_child_loaded(idx) {
+            return RemoveStep::Load(idx);
+        }
+        if self.loaded_child(idx).len() + 1 == degree {
+            if idx > 0 && !self.is_child_loaded(idx - 1) {
+                return RemoveStep::Load(idx - 1);
+            }
+            if idx + 1 < self.children.len() && !self.is_child_loaded(idx + 1) {
+                // The right sibling is only needed if the left one can't lend a key.
+                if idx == 0 || self.loaded_child(idx - 1).len() < degree {
+                    return RemoveStep::Load(idx + 1);
+                }
+            }
+
+            if idx > 0 && self.loaded_child(idx - 1).len() >= degree {
                 // Case 3a: Immediate left sibling has at least t keys.
+                self.dirty = true;
 
                 // Move key and value from parent down to child.
                 {
                     let parent_key = self.keys.remove(idx - 1);
                     let parent_val = self.vals.remove(idx - 1);
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     mid.keys.insert(0, parent_key);
                     mid.vals.insert(0, parent_val);
 
                     // Update the nodes that were modified.
+                    mid.dirty = true;
                     updated.insert(mid.id);
                 }
 
                 // Move rightmost key and value in left sibling to parent.
                 {
-                    let left = self.access_child::<C, S>(idx - 1, storage)?;
+                    let left = self.loaded_child(idx - 1);
                     let left_key = left.keys.pop().unwrap();
                     let left_val = left.vals.pop().unwrap();
 
                     // Update the nodes that were modified.
+                    left.dirty = true;
                     updated.insert(left.id);
 
                     self.keys.insert(idx - 1, left_key);
@@ -525,40 +1003,43 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
                 }
 
                 // Move rightmost child in left sibling to child.
-                let left = self.access_child::<C, S>(idx - 1, storage)?;
+                let left = self.loaded_child(idx - 1);
                 if !left.is_leaf() {
                     let child = left.children.pop().unwrap();
                     let child_key = left.children_keys.pop().unwrap();
+                    let child_hash = left.children_hashes.pop().unwrap();
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     mid.children.insert(0, child);
                     mid.children_keys.insert(0, child_key);
+                    mid.children_hashes.insert(0, child_hash);
                 }
-            } else if idx + 1 < self.children.len()
-                && self.access_child::<C, S>(idx + 1, storage)?.len() >= degree
-            {
+            } else if idx + 1 < self.children.len() && self.loaded_child(idx + 1).len() >= degree {
                 // Case 3a: Immediate right sibling has at least t keys.
+                self.dirty = true;
 
                 // Move key and value from parent down to child.
                 {
                     let parent_key = self.keys.remove(idx);
                     let parent_val = self.vals.remove(idx);
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     mid.keys.push(parent_key);
                     mid.vals.push(parent_val);
 
                     // Update the nodes that were modified.
+                    mid.dirty = true;
                     updated.insert(mid.id);
                 }
 
                 // Move leftmost key and value in right sibling to parent.
                 {
-                    let right = self.access_child::<C, S>(idx + 1, storage)?;
+                    let right = self.loaded_child(idx + 1);
                     let right_key = right.keys.remove(0);
                     let right_val = right.vals.remove(0);
 
                     // Update the nodes that were modified.
+                    right.dirty = true;
                     updated.insert(right.id);
 
                     self.keys.insert(idx, right_key);
@@ -566,33 +1047,38 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
                 }
 
                 // Move leftmost child in right sibling to child.
-                let right = self.access_child::<C, S>(idx + 1, storage)?;
+                let right = self.loaded_child(idx + 1);
                 if !right.is_leaf() {
                     let child = right.children.remove(0);
                     let child_key = right.children_keys.remove(0);
+                    let child_hash = right.children_hashes.remove(0);
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     mid.children.push(child);
                     mid.children_keys.push(child_key);
+                    mid.children_hashes.push(child_hash);
                 }
             } else if idx > 0 {
                 // Case 3b: Merge into left sibling.
+                self.dirty = true;
 
                 // Move key and value from parent down to left sibling (merged node).
                 {
                     let parent_key = self.keys.remove(idx - 1);
                     let parent_val = self.vals.remove(idx - 1);
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     let mut mid_keys = mid.keys.drain(..).collect();
                     let mut mid_vals = mid.vals.drain(..).collect();
                     let mut mid_children = mid.children.drain(..).collect();
                     let mut mid_children_keys = mid.children_keys.drain(..).collect();
+                    let mut mid_children_hashes = mid.children_hashes.drain(..).collect();
 
                     // Update the nodes that were modified.
+                    mid.dirty = true;
                     updated.insert(mid.id);
 
-                    let left = self.access_child::<C, S>(idx - 1, storage)?;
+                    let left = self.loaded_child(idx - 1);
                     left.keys.push(parent_key);
                     left.vals.push(parent_val);
 
@@ -601,116 +1087,397 @@ impl<const KEY_SZ: usize> Node<KEY_SZ> {
                     left.vals.append(&mut mid_vals);
                     left.children.append(&mut mid_children);
                     left.children_keys.append(&mut mid_children_keys);
+                    left.children_hashes.append(&mut mid_children_hashes);
 
                     // Update the nodes that were modified.
+                    left.dirty = true;
                     updated.insert(left.id);
                 }
 
                 // Remove the merged child.
-                self.children.remove(idx);
+                let mid = self.children.remove(idx).as_option_owned().unwrap();
                 self.children_keys.remove(idx);
+                self.children_hashes.remove(idx);
+                updated.remove(&mid.id);
 
                 // The only case where you fix the child to recurse down.
-                idx -= 1;
+                return RemoveStep::Descend(idx - 1, Some(mid));
             } else if idx + 1 < self.children.len() {
                 // Case 3b: Merge into right sibling.
+                self.dirty = true;
 
                 // Move key and value from parent down to right sibling (merged node).
                 {
                     let parent_key = self.keys.remove(idx);
                     let parent_val = self.vals.remove(idx);
 
-                    let right = self.access_child::<C, S>(idx + 1, storage)?;
+                    let right = self.loaded_child(idx + 1);
                     let mut right_keys = right.keys.drain(..).collect();
                     let mut right_vals = right.vals.drain(..).collect();
                     let mut right_children = right.children.drain(..).collect();
                     let mut right_children_keys = right.children_keys.drain(..).collect();
+                    let mut right_children_hashes = right.children_hashes.drain(..).collect();
 
                     // Update the nodes that were modified.
+                    right.dirty = true;
                     updated.insert(right.id);
 
-                    let mid = self.access_child::<C, S>(idx, storage)?;
+                    let mid = self.loaded_child(idx);
                     mid.keys.push(parent_key);
                     mid.vals.push(parent_val);
                     mid.keys.append(&mut right_keys);
                     mid.vals.append(&mut right_vals);
                     mid.children.append(&mut right_children);
                     mid.children_keys.append(&mut right_children_keys);
+                    mid.children_hashes.append(&mut right_children_hashes);
 
                     // Update the nodes that were modified.
+                    mid.dirty = true;
                     updated.insert(mid.id);
                 }
 
                 // Remove the right sibling.
-                self.children.remove(idx + 1);
+                let right = self.children.remove(idx + 1).as_option_owned().unwrap();
                 self.children_keys.remove(idx + 1);
+                self.children_hashes.remove(idx + 1);
+                updated.remove(&right.id);
+
+                return RemoveStep::Descend(idx, Some(right));
             }
         }
 
-        self.access_child::<C, S>(idx, storage)?
-            .remove::<C, S>(k, degree, storage, updated)
+        RemoveStep::Descend(idx, None)
     }
 
-    pub fn clear<C, S>(&mut self, storage: &mut S) -> Result<(), Error<S::Error>>
+    /// Swaps in a new key and value at `idx`, returning the old ones.
+    pub(crate) fn replace_entry(
+        &mut self,
+        idx: usize,
+        key: BlockId,
+        val: Key<KEY_SZ>,
+    ) -> (BlockId, Key<KEY_SZ>) {
+        self.dirty = true;
+        (
+            mem::replace(&mut self.keys[idx], key),
+            mem::replace(&mut self.vals[idx], val),
+        )
+    }
+
+    fn is_child_loaded(&self, idx: usize) -> bool {
+        matches!(self.children[idx], Child::Loaded(_))
+    }
+
+    pub(crate) fn loaded_child(&mut self, idx: usize) -> &mut Node<KEY_SZ> {
+        self.children[idx]
+            .as_option_mut()
+            .expect("child should have been loaded")
+    }
+
+    /// Visits the nodes below this one top-down, reading in unloaded ones. The nodes below a node
+    /// are only visited if `f` returns true for it.
+    pub fn visit_below<C, S, F>(
+        &mut self,
+        storage: &mut S,
+        f: &mut F,
+    ) -> Result<(), Error<S::Error>>
     where
         C: Crypter,
         S: Storage<Id = u64>,
+        F: FnMut(&Node<KEY_SZ>) -> bool,
     {
         for idx in 0..self.children.len() {
-            self.access_child::<C, S>(idx, storage)?
-                .clear::<C, S>(storage)?;
+            let child = self.access_child::<C, S>(idx, storage)?;
+            if f(child) {
+                child.visit_below::<C, S, F>(storage, f)?;
+            }
         }
 
+        Ok(())
+    }
+
+    /// Drops everything in this node, along with the nodes below it.
+    pub fn clear(&mut self) {
         self.keys.clear();
         self.vals.clear();
         self.children.clear();
         self.children_keys.clear();
+        self.children_hashes.clear();
+        self.dirty = true;
+    }
+
+    /// Visits every node in this subtree in pre-order along with its depth and whether it's
+    /// resident in memory. Unloaded children are read in for the visit, but aren't cached.
+    pub fn walk<C, S, F>(&self, storage: &mut S, f: &mut F) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+        F: FnMut(&Node<KEY_SZ>, usize, bool),
+    {
+        self.walk_from::<C, S, F>(0, true, storage, f)
+    }
+
+    fn walk_from<C, S, F>(
+        &self,
+        depth: usize,
+        loaded: bool,
+        storage: &mut S,
+        f: &mut F,
+    ) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+        F: FnMut(&Node<KEY_SZ>, usize, bool),
+    {
+        f(self, depth, loaded);
+
+        for (i, child) in self.children.iter().enumerate() {
+            match child {
+                Child::Loaded(node) => node.walk_from::<C, S, F>(depth + 1, true, storage, f)?,
+                Child::Unloaded(id) => Node::load_child::<C, S>(
+                    *id,
+                    self.children_keys[i],
+                    &self.children_hashes[i],
+                    storage,
+                )?
+                .walk_from::<C, S, F>(depth + 1, false, storage, f)?,
+            }
+        }
 
         Ok(())
     }
 
-    pub fn commit<C, R, S>(
+    /// Visits this node and every node below it that's resident in memory, without reading
+    /// anything from storage.
+    pub(crate) fn walk_loaded<F>(&self, f: &mut F)
+    where
+        F: FnMut(&Node<KEY_SZ>),
+    {
+        f(self);
+
+        for child in &self.children {
+            if let Child::Loaded(node) = child {
+                node.walk_loaded(f);
+            }
+        }
+    }
+
+    /// Reads in every node below this one and moves each to a fresh ID as a node that was never
+    /// persisted, recording the moves in `moved`. Nothing below this node refers to the objects
+    /// it was read from afterwards.
+    pub(crate) fn detach<C, S>(
         &mut self,
         storage: &mut S,
-        rng: &mut R,
-        updated: &HashSet<NodeId>,
-        updated_blocks: &HashSet<BlockId>,
+        moved: &mut HashMap<NodeId, NodeId>,
     ) -> Result<(), Error<S::Error>>
     where
         C: Crypter,
-        R: RngCore + CryptoRng,
         S: Storage<Id = u64>,
     {
-        // Update the keys for children that were updated.
-        for idx in self
-            .children
-            .iter()
-            .enumerate()
-            .map(|(i, child)| match child {
-                Child::Loaded(node) => (i, node.id),
-                Child::Unloaded(id) => (i, *id),
-            })
-            .filter_map(|(idx, id)| updated.contains(&id).then_some(idx))
-        {
-            self.children_keys[idx] = utils::generate_key(rng);
+        for idx in 0..self.children.len() {
+            let child = self.access_child::<C, S>(idx, storage)?;
+            child.detach::<C, S>(storage, moved)?;
+
+            let new_id = storage.alloc_id()?;
+            moved.insert(mem::replace(&mut child.id, new_id), new_id);
+            child.persisted = false;
+            child.dirty = true;
         }
 
-        // Only recurse down loaded nodes. If nodes were updated, they must have been brought in.
-        // Before we go down, we update any necessary keys in the child.
-        for child in self.children.iter_mut() {
-            match child {
+        Ok(())
+    }
+
+    /// Checks the B-tree invariants for this subtree, returning the number of entries in it.
+    /// Every key must fall strictly within `bounds`, and all leaves must sit at `leaf_depth`
+    /// (which is set by the first leaf reached if it isn't known yet).
+    pub fn verify<C, S>(
+        &self,
+        bounds: (Option<BlockId>, Option<BlockId>),
+        depth: usize,
+        degree: usize,
+        leaf_depth: &mut Option<usize>,
+        storage: &mut S,
+    ) -> Result<usize, Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        let invariant = |what| Err(Error::Invariant(self.id, what));
+
+        if self.keys.len() != self.vals.len() {
+            return invariant("mismatched keys and values");
+        }
+
+        if self.keys.len() > 2 * degree - 1 {
+            return invariant("too many keys");
+        }
+
+        // Only the root is allowed to fall below the minimum occupancy.
+        if depth > 0 && self.keys.len() + 1 < degree {
+            return invariant("too few keys");
+        }
+
+        if self.keys.windows(2).any(|pair| pair[0] >= pair[1]) {
+            return invariant("keys out of order");
+        }
+
+        if let (Some(lo), Some(first)) = (bounds.0, self.keys.first()) {
+            if *first <= lo {
+                return invariant("key below parent separator");
+            }
+        }
+
+        if let (Some(hi), Some(last)) = (bounds.1, self.keys.last()) {
+            if *last >= hi {
+                return invariant("key above parent separator");
+            }
+        }
+
+        if self.is_leaf() {
+            match leaf_depth {
+                Some(expected) if *expected != depth => return invariant("leaves at uneven depth"),
+                _ => *leaf_depth = Some(depth),
+            }
+            return Ok(self.len());
+        }
+
+        if self.is_empty() {
+            return invariant("empty internal node");
+        }
+
+        if self.children.len() != self.keys.len() + 1 {
+            return invariant("mismatched keys and children");
+        }
+
+        if self.children.len() != self.children_keys.len() {
+            return invariant("mismatched children and children keys");
+        }
+
+        if self.children.len() != self.children_hashes.len() {
+            return invariant("mismatched children and children hashes");
+        }
+
+        let mut len = self.len();
+
+        for (i, child) in self.children.iter().enumerate() {
+            let lo = if i == 0 {
+                bounds.0
+            } else {
+                Some(self.keys[i - 1])
+            };
+            let hi = self.keys.get(i).copied().or(bounds.1);
+
+            len += match child {
                 Child::Loaded(node) => {
-                    for (i, k) in node.keys.iter_mut().enumerate() {
-                        if updated_blocks.contains(k) {
-                            node.vals[i] = utils::generate_key(rng);
-                        }
-                    }
-                    node.commit::<C, R, S>(storage, rng, updated, updated_blocks)?
+                    node.verify::<C, S>((lo, hi), depth + 1, degree, leaf_depth, storage)?
+                }
+                Child::Unloaded(id) => Node::load_child::<C, S>(
+                    *id,
+                    self.children_keys[i],
+                    &self.children_hashes[i],
+                    storage,
+                )?
+                .verify::<C, S>(
+                    (lo, hi),
+                    depth + 1,
+                    degree,
+                    leaf_depth,
+                    storage,
+                )?,
+            };
+        }
+
+        Ok(len)
+    }
+
+    /// Reads in every child on the paths to updated nodes, so that [`Node::commit`] has nothing
+    /// left to load. They may not be loaded if we were reloaded since they were updated.
+    pub fn load_updated<C, S>(
+        &mut self,
+        storage: &mut S,
+        updated: &HashSet<NodeId>,
+    ) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: Storage<Id = u64>,
+    {
+        for idx in 0..self.children.len() {
+            if let Child::Unloaded(id) = self.children[idx] {
+                if updated.contains(&id) {
+                    self.access_child::<C, S>(idx, storage)?;
                 }
-                _ => {}
+            }
+        }
+
+        for child in self.children.iter_mut() {
+            if let Child::Loaded(node) = child {
+                node.load_updated::<C, S>(storage, updated)?;
             }
         }
 
         Ok(())
     }
+
+    /// Re-keys updated blocks and children. Everything on the updated paths must have been
+    /// brought in by [`Node::load_updated`] first, so that nothing is re-keyed unless all of it
+    /// can be.
+    pub fn commit<R>(
+        &mut self,
+        rng: &mut R,
+        updated: &HashSet<NodeId>,
+        updated_blocks: &HashSet<BlockId>,
+    ) where
+        R: RngCore + CryptoRng,
+    {
+        // Update the keys for blocks that were updated.
+        for (i, k) in self.keys.iter().enumerate() {
+            if updated_blocks.contains(k) {
+                self.vals[i] = utils::generate_key(rng);
+                self.dirty = true;
+            }
+        }
+
+        // Update the keys for children that were updated, to be re-encrypted under them on
+        // persist.
+        for idx in 0..self.children.len() {
+            if let Child::Loaded(node) = &mut self.children[idx] {
+                if updated.contains(&node.id) {
+                    node.dirty = true;
+                    self.children_keys[idx] = utils::generate_key(rng);
+                    self.dirty = true;
+                }
+            }
+        }
+
+        // Only recurse down loaded nodes. If nodes were updated, they've been brought in.
+        for child in self.children.iter_mut() {
+            if let Child::Loaded(node) = child {
+                node.commit(rng, updated, updated_blocks);
+            }
+        }
+    }
+}
+
+/// Hashes a node from its blocks, the hashes of their keys and the hashes of its children. This
+/// is all a [`Proof`](crate::proof::Proof) carries of each node, so it has to be enough.
+pub(crate) fn hash_node(
+    keys: &[BlockId],
+    val_hashes: impl IntoIterator<Item = Hash>,
+    children_hashes: &[Hash],
+) -> Hash {
+    let mut hasher = Sha256::new();
+
+    hasher.update(&(keys.len() as u64).to_le_bytes());
+    for block in keys {
+        hasher.update(&block.to_le_bytes());
+    }
+    for hash in val_hashes {
+        hasher.update(&hash);
+    }
+
+    hasher.update(&(children_hashes.len() as u64).to_le_bytes());
+    for hash in children_hashes {
+        hasher.update(hash);
+    }
+
+    hasher.finish()
 }
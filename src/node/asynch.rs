@@ -0,0 +1,356 @@
+use super::{Child, Node, RemoveStep};
+use crate::{error::Error, BlockId, Key, NodeId};
+use crypter::Crypter;
+use rand::{CryptoRng, RngCore};
+use std::{cmp::Ordering, collections::HashSet, future::Future, mem, pin::Pin};
+use storage::asynch::AsyncStorage;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+/// A boxed future, for the operations that recurse down the tree.
+type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
+
+type Entry<const KEY_SZ: usize> = (BlockId, Key<KEY_SZ>);
+
+/// The async counterparts of the node operations that touch storage. Each does the same as the
+/// blocking operation it's named after, and produces the same on-disk format.
+impl<const KEY_SZ: usize> Node<KEY_SZ> {
+    pub async fn load_async<C, S>(
+        id: u64,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<Self, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        let mut raw = vec![];
+        storage
+            .read_handle(&id)
+            .await?
+            .read_to_end(&mut raw)
+            .await
+            .map_err(|_| Error::Read)?;
+
+        Self::decode::<C, S::Error>(id, key, &mut raw.as_slice())
+    }
+
+    /// Persists this node and every loaded node below it, children first, returning whether
+    /// this node was written out. See `relocate()`.
+    pub async fn persist_async<C, S>(
+        &mut self,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<bool, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        self.persist_children_async::<C, S>(storage, stale, updated)
+            .await?;
+        self.relocate_async::<C, S>(key, storage, stale, updated)
+            .await
+    }
+
+    /// Persists every loaded node below this one, but not this node itself, which is marked
+    /// dirty if any of its children were written out.
+    pub fn persist_children_async<'a, C, S>(
+        &'a mut self,
+        storage: &'a mut S,
+        stale: &'a mut Vec<NodeId>,
+        updated: &'a mut HashSet<NodeId>,
+    ) -> BoxFuture<'a, Result<(), Error<S::Error>>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        Box::pin(async move {
+            for (i, child) in self.children.iter_mut().enumerate() {
+                if let Child::Loaded(node) = child {
+                    if node
+                        .persist_async::<C, S>(self.children_keys[i], storage, stale, updated)
+                        .await?
+                    {
+                        self.children_hashes[i] = node.hash();
+                        self.dirty = true;
+                    }
+                }
+            }
+
+            Ok(())
+        })
+    }
+
+    async fn relocate_async<C, S>(
+        &mut self,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+        stale: &mut Vec<NodeId>,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<bool, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        if !self.dirty && !updated.contains(&self.id) {
+            return Ok(false);
+        }
+
+        if self.persisted {
+            let old_id = mem::replace(&mut self.id, storage.alloc_id().await?);
+            stale.push(old_id);
+
+            if updated.remove(&old_id) {
+                updated.insert(self.id);
+            }
+        }
+
+        self.persist_node_async::<C, S>(key, storage).await?;
+        self.persisted = true;
+        self.dirty = false;
+        self.stored_children = self.child_ids();
+
+        Ok(true)
+    }
+
+    pub async fn persist_node_async<C, S>(
+        &self,
+        key: Key<KEY_SZ>,
+        storage: &mut S,
+    ) -> Result<(), Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        let raw = self.encode::<C, S::Error>(key)?;
+
+        // Write the node's new contents, then swap them in for the old ones.
+        {
+            let mut writer = storage.replace_handle(&self.id).await?;
+            writer.write_all(&raw).await.map_err(|_| Error::Write)?;
+            writer.flush().await.map_err(|_| Error::Write)?;
+        }
+
+        Ok(storage.commit_handle(&self.id).await?)
+    }
+
+    async fn access_child_async<C, S>(
+        &mut self,
+        idx: usize,
+        storage: &mut S,
+    ) -> Result<&mut Node<KEY_SZ>, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        if let Child::Unloaded(id) = self.children[idx] {
+            let child = Node::load_async::<C, S>(id, self.children_keys[idx], storage).await?;
+            child.check_hash(&self.children_hashes[idx])?;
+            self.children[idx] = Child::Loaded(child);
+        }
+        Ok(self.loaded_child(idx))
+    }
+
+    pub async fn get_async<C, S>(
+        &mut self,
+        k: &BlockId,
+        storage: &mut S,
+    ) -> Result<Option<(usize, &Node<KEY_SZ>)>, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        let mut node = self;
+        loop {
+            let idx = node.find_index(k);
+            if idx < node.len() && node.keys[idx] == *k {
+                return Ok(Some((idx, node)));
+            } else if node.is_leaf() {
+                return Ok(None);
+            } else {
+                node = node.access_child_async::<C, S>(idx, storage).await?;
+            }
+        }
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_nonfull_async<C, R, S>(
+        &mut self,
+        k: BlockId,
+        v: Key<KEY_SZ>,
+        degree: usize,
+        storage: &mut S,
+        for_update: bool,
+        rng: &mut R,
+        updated: &mut HashSet<NodeId>,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>>
+    where
+        C: Crypter,
+        R: RngCore + CryptoRng,
+        S: AsyncStorage<Id = u64>,
+    {
+        assert!(!self.is_full(degree));
+
+        let mut node = self;
+        loop {
+            let mut idx = node.find_index(&k);
+
+            if for_update {
+                updated.insert(node.id);
+            }
+
+            if idx < node.len() && k == node.keys[idx] {
+                return Ok(Some(node.vals[idx]));
+            }
+
+            if node.is_leaf() {
+                node.keys.insert(idx, k);
+                node.vals.insert(idx, v);
+                node.dirty = true;
+                return Ok(None);
+            }
+
+            if node
+                .access_child_async::<C, S>(idx, storage)
+                .await?
+                .is_full(degree)
+            {
+                let right_id = storage.alloc_id().await?;
+                node.split_child(idx, degree, right_id, for_update, rng, updated);
+                match node.keys[idx].cmp(&k) {
+                    Ordering::Less => idx += 1,
+                    Ordering::Equal => return Ok(Some(node.vals[idx])),
+                    Ordering::Greater => {}
+                }
+            }
+            node = node.access_child_async::<C, S>(idx, storage).await?;
+        }
+    }
+
+    pub async fn min_key_async<C, S>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<&BlockId, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        let mut node = self;
+
+        while !node.is_leaf()
+            && !node
+                .access_child_async::<C, S>(0, storage)
+                .await?
+                .is_empty()
+        {
+            node = node.loaded_child(0);
+        }
+
+        Ok(node.keys.first().unwrap())
+    }
+
+    pub async fn max_key_async<C, S>(
+        &mut self,
+        storage: &mut S,
+    ) -> Result<&BlockId, Error<S::Error>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        let mut node = self;
+
+        while !node.is_leaf()
+            && !node
+                .access_child_async::<C, S>(node.children.len() - 1, storage)
+                .await?
+                .is_empty()
+        {
+            node = node.loaded_child(node.children.len() - 1);
+        }
+
+        Ok(node.keys.last().unwrap())
+    }
+
+    /// See [`Node::remove`].
+    pub fn remove_async<'a, C, S>(
+        &'a mut self,
+        k: &'a BlockId,
+        degree: usize,
+        storage: &'a mut S,
+        freed: &'a mut Vec<Node<KEY_SZ>>,
+        updated: &'a mut HashSet<NodeId>,
+    ) -> BoxFuture<'a, Result<Option<Entry<KEY_SZ>>, Error<S::Error>>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        Box::pin(async move {
+            loop {
+                match self.remove_step(k, degree, updated) {
+                    RemoveStep::Load(idx) => {
+                        self.access_child_async::<C, S>(idx, storage).await?;
+                    }
+                    RemoveStep::Done(entry) => return Ok(entry),
+                    RemoveStep::Predecessor(idx) => {
+                        let pred = self.loaded_child(idx);
+                        let pred_key = *pred.max_key_async::<C, S>(storage).await?;
+                        let (pred_key, pred_val) = pred
+                            .remove_async::<C, S>(&pred_key, degree, storage, freed, updated)
+                            .await?
+                            .unwrap();
+                        updated.insert(pred.id);
+
+                        return Ok(Some(self.replace_entry(idx, pred_key, pred_val)));
+                    }
+                    RemoveStep::Successor(idx) => {
+                        let succ = self.loaded_child(idx + 1);
+                        let succ_key = *succ.min_key_async::<C, S>(storage).await?;
+                        let (succ_key, succ_val) = succ
+                            .remove_async::<C, S>(&succ_key, degree, storage, freed, updated)
+                            .await?
+                            .unwrap();
+                        updated.insert(succ.id);
+
+                        return Ok(Some(self.replace_entry(idx, succ_key, succ_val)));
+                    }
+                    RemoveStep::Descend(idx, merged) => {
+                        freed.extend(merged);
+                        return self
+                            .loaded_child(idx)
+                            .remove_async::<C, S>(k, degree, storage, freed, updated)
+                            .await;
+                    }
+                }
+            }
+        })
+    }
+
+    pub fn load_updated_async<'a, C, S>(
+        &'a mut self,
+        storage: &'a mut S,
+        updated: &'a HashSet<NodeId>,
+    ) -> BoxFuture<'a, Result<(), Error<S::Error>>>
+    where
+        C: Crypter,
+        S: AsyncStorage<Id = u64>,
+    {
+        Box::pin(async move {
+            for idx in 0..self.children.len() {
+                if let Child::Unloaded(id) = self.children[idx] {
+                    if updated.contains(&id) {
+                        self.access_child_async::<C, S>(idx, storage).await?;
+                    }
+                }
+            }
+
+            for child in self.children.iter_mut() {
+                if let Child::Loaded(node) = child {
+                    node.load_updated_async::<C, S>(storage, updated).await?;
+                }
+            }
+
+            Ok(())
+        })
+    }
+}
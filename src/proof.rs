@@ -0,0 +1,154 @@
+//! Proofs that a block is or isn't in a tree, which can be checked against nothing but the hash
+//! of its root. See [`BKeyTree::prove`](crate::BKeyTree::prove).
+
+use crate::{error::Error, node, BlockId, Hash};
+use openssl::sha::Sha256;
+
+/// A node on the path to a proven block. Keys are replaced by their hashes, so a proof gives
+/// away which blocks sit near the proven one, but none of their keys.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct ProofNode {
+    pub(crate) keys: Vec<BlockId>,
+    pub(crate) val_hashes: Vec<Hash>,
+    pub(crate) children_hashes: Vec<Hash>,
+}
+
+impl ProofNode {
+    fn hash(&self) -> Hash {
+        node::hash_node(
+            &self.keys,
+            self.val_hashes.iter().copied(),
+            &self.children_hashes,
+        )
+    }
+}
+
+/// Returns the hash of a tree at `epoch` whose root node hashes to `root` and whose metadata
+/// hashes to `meta`, as returned by [`BKeyTree::root_hash`](crate::BKeyTree::root_hash).
+pub(crate) fn tree_hash(root: &Hash, meta: &Hash, epoch: u64) -> Hash {
+    let mut hasher = Sha256::new();
+    hasher.update(root);
+    hasher.update(meta);
+    hasher.update(&epoch.to_le_bytes());
+    hasher.finish()
+}
+
+/// What a [`Proof`] shows about its block.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Membership {
+    /// The block is in the tree, under a key with this SHA-256 hash.
+    Present(Hash),
+    /// The block isn't in the tree.
+    Absent,
+}
+
+/// The nodes on the path from the root of a tree to a block, or to the leaf the block would be
+/// in if it isn't in the tree, along with the tree's epoch and the hash of its metadata.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Proof {
+    pub(crate) block: BlockId,
+    pub(crate) epoch: u64,
+    pub(crate) meta_hash: Hash,
+    pub(crate) path: Vec<ProofNode>,
+}
+
+impl Proof {
+    pub(crate) fn new(block: BlockId, epoch: u64, meta_hash: Hash, path: Vec<ProofNode>) -> Self {
+        Self {
+            block,
+            epoch,
+            meta_hash,
+            path,
+        }
+    }
+
+    pub fn block(&self) -> BlockId {
+        self.block
+    }
+
+    /// Returns the epoch of the tree the proof was made from, which only holds once the proof
+    /// checks out.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Checks the proof against `root_hash`, as returned by
+    /// [`BKeyTree::root_hash`](crate::BKeyTree::root_hash), returning what it shows about its
+    /// block, or `None` if it doesn't check out.
+    ///
+    /// The root node, the metadata and the epoch have to hash to `root_hash` together, each node
+    /// below has to hash to what its parent holds for it, and the path has to be the one a lookup
+    /// of the block would take, ending at the node holding the block or at a leaf.
+    pub fn verify(&self, root_hash: &Hash) -> Option<Membership> {
+        let mut expected = self.path.first()?.hash();
+        if tree_hash(&expected, &self.meta_hash, self.epoch) != *root_hash {
+            return None;
+        }
+
+        for (depth, node) in self.path.iter().enumerate() {
+            let is_last = depth + 1 == self.path.len();
+
+            // Reject shapes that a lookup would index into out of bounds or search wrongly.
+            if node.val_hashes.len() != node.keys.len()
+                || !(node.children_hashes.is_empty()
+                    || node.children_hashes.len() == node.keys.len() + 1)
+                || node.keys.windows(2).any(|pair| pair[0] >= pair[1])
+            {
+                return None;
+            }
+
+            if node.hash() != expected {
+                return None;
+            }
+
+            match node.keys.binary_search(&self.block) {
+                Ok(idx) if is_last => return Some(Membership::Present(node.val_hashes[idx])),
+                Err(_) if is_last && node.children_hashes.is_empty() => {
+                    return Some(Membership::Absent)
+                }
+                Err(idx) if !is_last && !node.children_hashes.is_empty() => {
+                    expected = node.children_hashes[idx];
+                }
+                _ => return None,
+            }
+        }
+
+        None
+    }
+
+    pub fn encode<E>(&self) -> Result<Vec<u8>, Error<E>> {
+        let path = self
+            .path
+            .iter()
+            .map(|node| (&node.keys, &node.val_hashes, &node.children_hashes))
+            .collect::<Vec<_>>();
+
+        bincode::serialize(&(self.block, self.epoch, self.meta_hash, path))
+            .map_err(|_| Error::Serialization)
+    }
+
+    pub fn decode<E>(raw: &[u8]) -> Result<Self, Error<E>> {
+        type Raw = (
+            BlockId,
+            u64,
+            Hash,
+            Vec<(Vec<BlockId>, Vec<Hash>, Vec<Hash>)>,
+        );
+        let (block, epoch, meta_hash, path): Raw =
+            bincode::deserialize(raw).map_err(|_| Error::Deserialization)?;
+
+        Ok(Self {
+            block,
+            epoch,
+            meta_hash,
+            path: path
+                .into_iter()
+                .map(|(keys, val_hashes, children_hashes)| ProofNode {
+                    keys,
+                    val_hashes,
+                    children_hashes,
+                })
+                .collect(),
+        })
+    }
+}
@@ -0,0 +1,109 @@
+//! Reference counts for the objects of trees that share storage through
+//! [`BKeyTree::fork`](crate::BKeyTree::fork).
+
+use crate::{error::Error, utils, Hash, NodeId};
+use embedded_io::blocking::Write;
+use std::{collections::HashMap, mem};
+use storage::Storage;
+
+/// How many parents refer to each object in storage shared by forks, a parent being another
+/// object or one of the trees. An object that isn't counted has a single parent.
+///
+/// Forking only counts the fork's root as one more parent of the root's children. The nodes
+/// below are shared through those children, and their counts are pushed down as the trees
+/// diverge: a tree letting go of an object that another still refers to counts the object that
+/// replaces it as one more parent of its children. Once a tree is forked, every object it writes
+/// is counted too, so that an object referred to by some fork but not by the tree is counted.
+///
+/// The counts are persisted with an HMAC, under a key kept in the metadata of every fork, so
+/// that they can't be forged to have a tree remove objects that a fork still refers to.
+pub(crate) struct Refs {
+    // The object the counts are persisted in, which every fork's metadata points to.
+    pub(crate) id: NodeId,
+    pub(crate) key: Hash,
+    counts: HashMap<NodeId, u64>,
+}
+
+impl Refs {
+    pub(crate) fn new(id: NodeId, key: Hash) -> Self {
+        Self {
+            id,
+            key,
+            counts: HashMap::new(),
+        }
+    }
+
+    pub(crate) fn load<S>(id: NodeId, key: Hash, storage: &mut S) -> Result<Self, Error<S::Error>>
+    where
+        S: Storage<Id = u64>,
+    {
+        let size = storage.size(&id)?;
+        let mut reader = storage.read_handle(&id)?;
+        let raw = utils::read_bytes::<S>(&mut reader, size)?;
+
+        let (raw, mac) = raw
+            .split_last_chunk::<{ mem::size_of::<Hash>() }>()
+            .ok_or(Error::Deserialization)?;
+        if !utils::verify_mac(&key, &[&id.to_le_bytes(), raw], mac)? {
+            return Err(Error::MacMismatch(id));
+        }
+
+        let counts = bincode::deserialize(raw).map_err(|_| Error::Deserialization)?;
+        Ok(Self { id, key, counts })
+    }
+
+    /// Atomically replaces the persisted counts with the current ones.
+    pub(crate) fn persist<S>(&self, storage: &mut S) -> Result<(), Error<S::Error>>
+    where
+        S: Storage<Id = u64>,
+    {
+        let mut raw = bincode::serialize(&self.counts).map_err(|_| Error::Serialization)?;
+        let mac = utils::mac(&self.key, &[&self.id.to_le_bytes(), &raw])?;
+        raw.extend(mac);
+
+        storage
+            .replace_handle(&self.id)?
+            .write_all(&raw)
+            .map_err(|_| Error::Write)?;
+
+        Ok(storage.commit_handle(&self.id)?)
+    }
+
+    /// Counts object `id` for the tree that wrote it, unless it's already counted.
+    pub(crate) fn track(&mut self, id: NodeId) {
+        self.counts.entry(id).or_insert(1);
+    }
+
+    /// Counts one more parent referring to object `id`.
+    pub(crate) fn share(&mut self, id: NodeId) {
+        *self.counts.entry(id).or_insert(1) += 1;
+    }
+
+    pub(crate) fn is_tracked(&self, id: &NodeId) -> bool {
+        self.counts.contains_key(id)
+    }
+
+    /// Returns whether more than one parent refers to object `id`.
+    pub(crate) fn is_shared(&self, id: &NodeId) -> bool {
+        self.counts.get(id).is_some_and(|count| *count > 1)
+    }
+
+    /// Drops one parent's reference to object `id`, returning whether that was the last one and
+    /// the object can go.
+    pub(crate) fn release(&mut self, id: &NodeId) -> bool {
+        match self.counts.get_mut(id) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            }
+            _ => {
+                self.counts.remove(id);
+                true
+            }
+        }
+    }
+
+    pub(crate) fn ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.counts.keys().copied()
+    }
+}
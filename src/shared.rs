@@ -0,0 +1,197 @@
+//! A [`BKeyTree`] that can be shared between threads, for lookups in parallel.
+//!
+//! Only lookups are concurrent. There's no per-node locking or lock coupling: a writer locks the
+//! whole tree, so writes happen one at a time however many threads issue them.
+
+use crate::{
+    counter::TrustedCounter, error::Error, snapshot::Snapshot, BKeyTree, BlockId, Key, NodeId,
+    AES256CTR_KEY_SZ,
+};
+use crypter::{openssl::Aes256Ctr, Crypter};
+use kms::KeyManagementScheme;
+use rand::{rngs::OsRng, CryptoRng, RngCore};
+use std::{
+    ops::RangeBounds,
+    sync::{PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+};
+use storage::{
+    dir::{self, DirectoryStorage},
+    Storage,
+};
+
+/// A [`BKeyTree`] behind a reader-writer lock, so that one instance can serve many threads.
+///
+/// Lookups run in parallel under the read lock, through [`BKeyTree::lookup`], and only contend
+/// with each other while reading in a node that isn't loaded. Anything that changes the tree,
+/// including [`SharedBKeyTree::derive`] for a block without a key, takes the write lock, so
+/// writers are serialized with each other and with lookups. The lock covers the whole tree:
+/// writers to disjoint parts of it still wait on each other, so this doesn't help a workload
+/// that's mostly writes.
+///
+/// A thread that panics while holding the lock leaves the tree as far as it got, like an
+/// operation that fails partway through, and the other threads carry on with it rather than
+/// panicking in turn. The persisted tree is untouched either way.
+///
+/// The tree's RNG must be `Send` for this to be shared, which is why it defaults to [`OsRng`]
+/// rather than a thread-local one.
+pub struct SharedBKeyTree<
+    R = OsRng,
+    S = DirectoryStorage,
+    C = Aes256Ctr,
+    const KEY_SZ: usize = AES256CTR_KEY_SZ,
+> {
+    tree: RwLock<BKeyTree<R, S, C, KEY_SZ>>,
+}
+
+impl SharedBKeyTree<OsRng, DirectoryStorage, Aes256Ctr, AES256CTR_KEY_SZ> {
+    pub fn new(path: impl AsRef<str>) -> Result<Self, Error<dir::Error>> {
+        Ok(BKeyTree::with_storage(DirectoryStorage::new(path.as_ref())?)?.into())
+    }
+
+    pub fn reload(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+    ) -> Result<Self, Error<dir::Error>> {
+        Ok(
+            BKeyTree::reload_with_storage(root_id, DirectoryStorage::new(path.as_ref())?, key)?
+                .into(),
+        )
+    }
+
+    /// See [`BKeyTree::reload_with_storage_and_counter`].
+    pub fn reload_with_counter(
+        root_id: u64,
+        path: impl AsRef<str>,
+        key: Key<AES256CTR_KEY_SZ>,
+        counter: impl TrustedCounter + Send + Sync + 'static,
+    ) -> Result<Self, Error<dir::Error>> {
+        Ok(BKeyTree::reload_with_storage_and_counter(
+            root_id,
+            DirectoryStorage::new(path.as_ref())?,
+            key,
+            counter,
+        )?
+        .into())
+    }
+}
+
+impl<R, S, C, const KEY_SZ: usize> SharedBKeyTree<R, S, C, KEY_SZ>
+where
+    R: RngCore + CryptoRng + Default,
+    S: Storage<Id = u64>,
+    S::Error: Send + Sync + 'static,
+    C: Crypter,
+{
+    /// Returns the tree, locked for shared access.
+    pub fn read(&self) -> RwLockReadGuard<'_, BKeyTree<R, S, C, KEY_SZ>> {
+        self.tree.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Returns the tree, locked for exclusive access.
+    pub fn write(&self) -> RwLockWriteGuard<'_, BKeyTree<R, S, C, KEY_SZ>> {
+        self.tree.write().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> BKeyTree<R, S, C, KEY_SZ> {
+        self.tree
+            .into_inner()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn len(&self) -> usize {
+        self.read().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn root_id(&self) -> NodeId {
+        self.read().root_id()
+    }
+
+    pub fn contains(&self, k: &BlockId) -> Result<bool, Error<S::Error>> {
+        self.read().contains(k)
+    }
+
+    pub fn get(&self, k: &BlockId) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.read().lookup(k)
+    }
+
+    /// See [`BKeyTree::range`].
+    pub fn range(
+        &self,
+        range: impl RangeBounds<BlockId>,
+    ) -> Result<Vec<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
+        self.read().range(range)
+    }
+
+    /// Inserts a key without marking any of the nodes touched on the way down as updated.
+    pub fn insert(
+        &self,
+        k: BlockId,
+        v: Key<KEY_SZ>,
+    ) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.write().insert(k, v)
+    }
+
+    pub fn remove(&self, k: &BlockId) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.write().remove(k)
+    }
+
+    pub fn persist(&self, key: Key<KEY_SZ>) -> Result<(), Error<S::Error>> {
+        self.write().persist(key)
+    }
+
+    /// See [`BKeyTree::snapshot`]. Lookups on the snapshot don't take the lock at all.
+    pub fn snapshot(&self) -> Option<Snapshot<S, C, KEY_SZ>> {
+        self.read().snapshot()
+    }
+
+    /// See [`KeyManagementScheme::derive`]. Blocks that already have a key are resolved under
+    /// the read lock.
+    pub fn derive(&self, block_id: BlockId) -> Result<Key<KEY_SZ>, Error<S::Error>> {
+        {
+            let tree = self.read();
+            if let Some(key) = tree.lookup(&block_id)? {
+                return Ok(key);
+            }
+            if let Some(key) = tree.in_flight_blocks.get(&block_id) {
+                return Ok(*key);
+            }
+        }
+
+        self.write().derive(block_id)
+    }
+
+    /// See [`KeyManagementScheme::update`].
+    pub fn update(&self, block_id: BlockId) -> Result<Key<KEY_SZ>, Error<S::Error>> {
+        self.write().update(block_id)
+    }
+
+    /// See [`KeyManagementScheme::commit`].
+    pub fn commit(&self) -> Vec<BlockId> {
+        self.write().commit()
+    }
+
+    /// See [`BKeyTree::take_commit_error`].
+    pub fn take_commit_error(&self) -> Option<Box<dyn std::error::Error + Send + Sync>> {
+        self.write().take_commit_error()
+    }
+
+    /// See [`BKeyTree::try_commit`].
+    pub fn try_commit(&self) -> Result<Vec<BlockId>, Error<S::Error>> {
+        self.write().try_commit()
+    }
+}
+
+impl<R, S, C, const KEY_SZ: usize> From<BKeyTree<R, S, C, KEY_SZ>>
+    for SharedBKeyTree<R, S, C, KEY_SZ>
+{
+    fn from(tree: BKeyTree<R, S, C, KEY_SZ>) -> Self {
+        Self {
+            tree: RwLock::new(tree),
+        }
+    }
+}
@@ -0,0 +1,138 @@
+//! Read-only views of a [`BKeyTree`](crate::BKeyTree) as it was last persisted.
+
+use crate::{
+    error::Error,
+    node::Node,
+    utils::{Locked, Lru},
+    BlockId, Hash, Key, NodeId, AES256CTR_KEY_SZ, DEFAULT_LOOKUP_CACHE_CAPACITY,
+};
+use crypter::{openssl::Aes256Ctr, Crypter};
+use std::{collections::BTreeMap, marker::PhantomData, ops::RangeBounds, sync::Arc};
+use storage::{dir::DirectoryStorage, Storage};
+
+/// The versions that live snapshots are pinned to, with how many snapshots pin each and the root
+/// each was taken from.
+pub(crate) type Pins<const KEY_SZ: usize> = Locked<BTreeMap<u64, (usize, Arc<Node<KEY_SZ>>)>>;
+
+/// A read-only view of a tree, pinned to the root it was last persisted with. See
+/// [`BKeyTree::snapshot`](crate::BKeyTree::snapshot).
+///
+/// The objects of the pinned version are kept in storage while the snapshot is alive, no matter
+/// how the tree changes in the meantime, and are removed by the first persist after every
+/// snapshot that may refer to them has been dropped.
+pub struct Snapshot<S = DirectoryStorage, C = Aes256Ctr, const KEY_SZ: usize = AES256CTR_KEY_SZ> {
+    version: u64,
+    root: Arc<Node<KEY_SZ>>,
+    len: usize,
+    storage: Arc<Locked<S>>,
+    pins: Arc<Pins<KEY_SZ>>,
+    // Nodes read in by lookups, which never change since nothing pinned is removed.
+    cache: Locked<Lru<NodeId, Arc<Node<KEY_SZ>>>>,
+    pd: PhantomData<C>,
+}
+
+impl<S, C, const KEY_SZ: usize> Snapshot<S, C, KEY_SZ>
+where
+    S: Storage<Id = u64>,
+    C: Crypter,
+{
+    /// Pins `version` of a tree, whose root was `root` and which held `len` entries.
+    pub(crate) fn new(
+        version: u64,
+        root: Arc<Node<KEY_SZ>>,
+        len: usize,
+        storage: Arc<Locked<S>>,
+        pins: Arc<Pins<KEY_SZ>>,
+    ) -> Self {
+        pins.lock()
+            .entry(version)
+            .or_insert_with(|| (0, root.clone()))
+            .0 += 1;
+
+        Self {
+            version,
+            root,
+            len,
+            storage,
+            pins,
+            cache: Locked::new(Lru::new(DEFAULT_LOOKUP_CACHE_CAPACITY)),
+            pd: PhantomData,
+        }
+    }
+
+    /// Bounds the cache that lookups read nodes into to `nodes` nodes, as
+    /// [`BKeyTree::set_lookup_cache_capacity`](crate::BKeyTree::set_lookup_cache_capacity) does
+    /// for the tree. It defaults to 1024 nodes.
+    pub fn set_cache_capacity(&mut self, nodes: usize) {
+        self.cache.get_mut().set_capacity(nodes);
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn root_id(&self) -> NodeId {
+        self.root.id
+    }
+
+    pub fn contains(&self, k: &BlockId) -> Result<bool, Error<S::Error>> {
+        Ok(self.get(k)?.is_some())
+    }
+
+    pub fn get(&self, k: &BlockId) -> Result<Option<Key<KEY_SZ>>, Error<S::Error>> {
+        self.root
+            .lookup(k, &|id, key, hash| self.cached_node(id, key, hash))
+    }
+
+    /// Returns every block in `range` and its key, in block order.
+    pub fn range(
+        &self,
+        range: impl RangeBounds<BlockId>,
+    ) -> Result<Vec<(BlockId, Key<KEY_SZ>)>, Error<S::Error>> {
+        let mut entries = vec![];
+        self.root.range_into(
+            &range,
+            &|id, key, hash| self.cached_node(id, key, hash),
+            &mut entries,
+        )?;
+        Ok(entries)
+    }
+
+    /// Returns node `id` from the cache, reading it in if it isn't there yet.
+    fn cached_node(
+        &self,
+        id: NodeId,
+        key: Key<KEY_SZ>,
+        hash: &Hash,
+    ) -> Result<Arc<Node<KEY_SZ>>, Error<S::Error>> {
+        if let Some(node) = self.cache.lock().get(&id) {
+            return Ok(node);
+        }
+
+        let node = Arc::new(Node::load_child::<C, S>(
+            id,
+            key,
+            hash,
+            &mut self.storage.lock(),
+        )?);
+        self.cache.lock().insert(id, node.clone());
+
+        Ok(node)
+    }
+}
+
+impl<S, C, const KEY_SZ: usize> Drop for Snapshot<S, C, KEY_SZ> {
+    fn drop(&mut self) {
+        let mut pins = self.pins.lock();
+        if let Some((count, _)) = pins.get_mut(&self.version) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.version);
+            }
+        }
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+
+/// A summary of the shape and pending state of a tree.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    /// Number of entries in the tree.
+    pub len: usize,
+    /// Minimum degree of the tree.
+    pub degree: usize,
+    /// Number of levels in the tree.
+    pub height: usize,
+    /// Total number of nodes.
+    pub nodes: usize,
+    /// Number of leaf nodes.
+    pub leaves: usize,
+    /// Average fraction of key slots in use across all nodes.
+    pub avg_fill: f64,
+    /// Smallest fraction of key slots in use by any non-root node (or the root, if it's alone).
+    pub min_fill: f64,
+    /// Number of nodes resident in memory.
+    pub loaded: usize,
+    /// Number of nodes that are only in storage.
+    pub unloaded: usize,
+    /// Number of nodes marked as updated in the current epoch.
+    pub updated: usize,
+    /// Number of blocks marked as updated in the current epoch.
+    pub updated_blocks: usize,
+    /// Number of blocks derived but not yet inserted into the tree.
+    pub in_flight_blocks: usize,
+    /// Number of objects in storage, reachable or not, if storage was scanned.
+    pub objects: Option<usize>,
+    /// Total size of the objects in storage, in bytes, if storage was scanned.
+    pub bytes: Option<u64>,
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "len {}", self.len)?;
+        writeln!(f, "degree {}", self.degree)?;
+        writeln!(f, "height {}", self.height)?;
+        writeln!(f, "nodes {}", self.nodes)?;
+        writeln!(f, "leaves {}", self.leaves)?;
+        writeln!(f, "avg_fill {:.3}", self.avg_fill)?;
+        writeln!(f, "min_fill {:.3}", self.min_fill)?;
+        writeln!(f, "loaded {}", self.loaded)?;
+        writeln!(f, "unloaded {}", self.unloaded)?;
+        writeln!(f, "updated {}", self.updated)?;
+        writeln!(f, "updated_blocks {}", self.updated_blocks)?;
+        write!(f, "in_flight_blocks {}", self.in_flight_blocks)?;
+        if let (Some(objects), Some(bytes)) = (self.objects, self.bytes) {
+            write!(f, "\nobjects {objects}\nbytes {bytes}")?;
+        }
+        Ok(())
+    }
+}
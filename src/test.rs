@@ -1,6 +1,22 @@
 use super::*;
 use anyhow::Result;
-use std::{collections::HashMap, fs};
+use counter::FileCounter;
+use openssl::sha::sha256;
+use proof::Membership;
+use rand::{
+    rngs::{OsRng, StdRng},
+    Rng, SeedableRng,
+};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+};
+use storage::{
+    cache::CachedStorage,
+    fault::{FaultyStorage, Op},
+    mem::MemoryStorage,
+    metrics::{CountingStorage, Snapshot},
+};
 
 #[test]
 fn simple() -> Result<()> {
@@ -42,6 +58,7 @@ fn reloading() -> Result<()> {
     let key = utils::generate_key(&mut rng);
     let root_id = tree.root_id();
     tree.persist(key)?;
+    drop(tree);
 
     let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-reload", key)?;
 
@@ -78,3 +95,2105 @@ fn correctness() -> Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn verification() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage_and_degree(MemoryStorage::new(), 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..100 {
+        let value = utils::generate_key(&mut rng);
+        model.insert(block, value);
+        tree.insert(block, value)?;
+    }
+    tree.verify()?;
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    // A leaf holding a block outside of its parent's separators breaks the ordering.
+    let mut leaf = &mut tree.root;
+    while !leaf.is_leaf() {
+        leaf = leaf.children[0].as_option_mut().unwrap();
+    }
+    let leaf_id = leaf.id;
+    let block = mem::replace(&mut leaf.keys[0], BlockId::MAX);
+    assert!(matches!(tree.verify(), Err(Error::Invariant(id, _)) if id == leaf_id));
+
+    let mut leaf = &mut tree.root;
+    while !leaf.is_leaf() {
+        leaf = leaf.children[0].as_option_mut().unwrap();
+    }
+    leaf.keys[0] = block;
+    tree.verify()?;
+
+    // So does a length that doesn't match the entries.
+    tree.len += 1;
+    assert!(matches!(
+        tree.verify(),
+        Err(Error::Invariant(_, "length doesn't match entries"))
+    ));
+    tree.len -= 1;
+
+    // A node overwritten in storage is refused when it's read back in.
+    tree.persist(key)?;
+    let root_id = tree.root_id();
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.verify()?;
+
+    let (first, second) = match &tree.root.children[..2] {
+        [Child::Unloaded(first), Child::Unloaded(second)] => (*first, *second),
+        _ => unreachable!(),
+    };
+    {
+        let mut storage = tree.storage.lock();
+        let size = storage.size(&second)?;
+        let raw = utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&second)?, size)?;
+        assert!(storage.replace_handle(&first)?.write_all(&raw).is_ok());
+        storage.commit_handle(&first)?;
+    }
+    assert!(tree.verify().is_err());
+
+    Ok(())
+}
+
+#[test]
+fn exports() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage_and_degree(MemoryStorage::new(), 2)?;
+    for block in 0..20 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(key)?;
+    let root_id = tree.root_id();
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.updated.insert(root_id);
+
+    let children = tree.root.children.len();
+    let secrets = tree
+        .root
+        .vals
+        .iter()
+        .chain(&tree.root.children_keys)
+        .map(|key| export::to_hex(key))
+        .collect::<Vec<_>>();
+
+    // Only the root is loaded after a reload, and nothing is read in to export the rest.
+    let dot = tree.export_structure(ExportFormat::Dot);
+    assert!(dot.starts_with("digraph bkeytree {"));
+    assert!(dot.contains(&format!("n{root_id} [label=\"{{node {root_id} (depth 0)")));
+    assert!(dot.contains("color=red, penwidth=2"));
+    assert_eq!(dot.matches("style=dashed").count(), children);
+    assert_eq!(dot.matches(&format!("n{root_id} -> ")).count(), children);
+
+    let json = tree.export_structure(ExportFormat::Json);
+    assert!(json.starts_with(&format!("{{\"root\":{root_id},\"redacted\":true,")));
+    assert_eq!(json.matches("\"state\":\"unloaded\"").count(), children);
+    assert_eq!(json.matches("\"updated\":true").count(), 1);
+
+    // Keys only show up when asked for.
+    for secret in &secrets {
+        assert!(!dot.contains(secret.as_str()));
+        assert!(!json.contains(secret.as_str()));
+    }
+
+    let dot = tree.export_structure_with_keys(ExportFormat::Dot);
+    let json = tree.export_structure_with_keys(ExportFormat::Json);
+    assert!(json.contains("\"redacted\":false"));
+    for secret in &secrets {
+        assert!(dot.contains(secret.as_str()));
+        assert!(json.contains(secret.as_str()));
+    }
+
+    Ok(())
+}
+
+#[test]
+fn stats() -> Result<()> {
+    let mut rng = ThreadRng::default();
+    let mut tree = BKeyTree::new("/tmp/bkeytreedir-stats")?;
+
+    for block in 0..1000 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.verify()?;
+
+    let stats = tree.stats()?;
+    assert_eq!(stats.len, 1000);
+    assert!(stats.height > 1);
+    assert!(stats.leaves < stats.nodes);
+    assert_eq!(stats.loaded, stats.nodes);
+    assert!(stats.min_fill >= 1.0 / 3.0);
+    assert_eq!((stats.objects, stats.bytes), (None, None));
+
+    let key = utils::generate_key(&mut rng);
+    let root_id = tree.root_id();
+    tree.persist(key)?;
+    drop(tree);
+
+    // Only the root should be resident after a reload, and gathering stats shouldn't change that.
+    let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-stats", key)?;
+    let reloaded = tree.stats()?;
+    assert_eq!(reloaded.loaded, 1);
+    assert_eq!(reloaded.unloaded, stats.nodes - 1);
+    assert_eq!(reloaded.height, stats.height);
+    assert_eq!(tree.stats()?.loaded, 1);
+    tree.verify()?;
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-stats");
+
+    Ok(())
+}
+
+#[test]
+fn orphans() -> Result<()> {
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-orphans");
+
+    let mut rng = ThreadRng::default();
+    let mut tree = BKeyTree::new("/tmp/bkeytreedir-orphans")?;
+
+    for block in 0..1000 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(utils::generate_key(&mut rng))?;
+
+    // Every object is either the root, the metadata or a node below the root.
+    assert!(tree.orphans()?.is_empty());
+    let stats = tree.stats_with_storage()?;
+    assert_eq!(stats.objects, Some(stats.nodes + 1));
+    assert!(stats.bytes.is_some_and(|bytes| bytes > 0));
+
+    // The objects of nodes merged away during removal are removed by the next persist.
+    for block in 0..900 {
+        tree.remove(&block)?;
+    }
+    let key = utils::generate_key(&mut rng);
+    tree.persist(key)?;
+    assert!(tree.orphans()?.is_empty());
+    let stats = tree.stats_with_storage()?;
+    assert_eq!(stats.objects, Some(stats.nodes + 1));
+
+    // Objects nothing refers to, like those an interrupted persist leaves behind, are orphans.
+    let orphans = {
+        let mut storage = tree.storage.lock();
+        (0..3)
+            .map(|_| {
+                let id = storage.alloc_id()?;
+                assert!(storage.replace_handle(&id)?.write_all(b"stray").is_ok());
+                storage.commit_handle(&id)?;
+                Ok(id)
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    assert_eq!(tree.orphans()?, orphans);
+
+    // Orphans are reported the same way after a reload.
+    let root_id = tree.root_id();
+    drop(tree);
+    let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-orphans", key)?;
+    assert_eq!(tree.orphans()?, orphans);
+
+    // Until changes are persisted, the last persisted root may refer to objects the tree no
+    // longer does, so nothing is collected.
+    let entries = tree.entries()?;
+    tree.remove(&999)?;
+    assert!(matches!(tree.orphans(), Err(Error::Unpersisted)));
+    assert!(matches!(tree.gc(), Err(Error::Unpersisted)));
+    drop(tree);
+    let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-orphans", key)?;
+
+    // Collecting them removes exactly the orphans and leaves the tree intact.
+    assert_eq!(tree.gc()?, orphans);
+    assert!(tree.orphans()?.is_empty());
+    for id in &orphans {
+        assert!(fs::metadata(format!("/tmp/bkeytreedir-orphans/{id}")).is_err());
+    }
+
+    drop(tree);
+    let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-orphans", key)?;
+    tree.verify()?;
+    assert_eq!(tree.entries()?, entries);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-orphans");
+
+    // The IDs of collected orphans are deallocated.
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage(MemoryStorage::new())?;
+    tree.insert(0, utils::generate_key(&mut rng))?;
+    tree.persist(key)?;
+    let mut orphans = {
+        let mut storage = tree.storage.lock();
+        (0..3)
+            .map(|_| {
+                let id = storage.alloc_id()?;
+                assert!(storage.replace_handle(&id)?.write_all(b"stray").is_ok());
+                storage.commit_handle(&id)?;
+                Ok(id)
+            })
+            .collect::<Result<Vec<_>>>()?
+    };
+    assert_eq!(tree.gc()?, orphans);
+    let mut reused = (0..3)
+        .map(|_| tree.storage.lock().alloc_id())
+        .collect::<Result<Vec<_>, _>>()?;
+    orphans.sort();
+    reused.sort();
+    assert_eq!(reused, orphans);
+
+    Ok(())
+}
+
+#[test]
+fn locking() -> Result<()> {
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-locking");
+
+    let mut rng = ThreadRng::default();
+    let mut tree = BKeyTree::new("/tmp/bkeytreedir-locking")?;
+    tree.insert(0, utils::generate_key(&mut rng))?;
+
+    let key = utils::generate_key(&mut rng);
+    let root_id = tree.root_id();
+    tree.persist(key)?;
+
+    // A writer excludes everyone else.
+    assert!(matches!(
+        BKeyTree::new("/tmp/bkeytreedir-locking"),
+        Err(Error::Storage(dir::Error::Locked(_)))
+    ));
+    assert!(matches!(
+        BKeyTree::reload_read_only(root_id, "/tmp/bkeytreedir-locking", key),
+        Err(Error::Storage(dir::Error::Locked(_)))
+    ));
+    drop(tree);
+
+    // Readers share, but can't write, and exclude writers.
+    let mut reader = BKeyTree::reload_read_only(root_id, "/tmp/bkeytreedir-locking", key)?;
+    let other = BKeyTree::reload_read_only(root_id, "/tmp/bkeytreedir-locking", key)?;
+    assert!(reader.contains(&0)?);
+    assert!(other.contains(&0)?);
+    assert!(matches!(
+        reader.persist(key),
+        Err(Error::Storage(dir::Error::ReadOnly))
+    ));
+    assert!(matches!(
+        BKeyTree::reload(root_id, "/tmp/bkeytreedir-locking", key),
+        Err(Error::Storage(dir::Error::Locked(_)))
+    ));
+    drop((reader, other));
+
+    // Readers don't create the lock file, so they can't open a directory no writer has.
+    fs::remove_file("/tmp/bkeytreedir-locking/.lock")?;
+    assert!(DirectoryStorage::open_read_only("/tmp/bkeytreedir-locking").is_err());
+    assert!(fs::metadata("/tmp/bkeytreedir-locking/.lock").is_err());
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-locking");
+
+    Ok(())
+}
+
+#[test]
+fn fanout() -> Result<()> {
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-fanout");
+
+    let mut rng = ThreadRng::default();
+    let layout = dir::Layout::FanOut { levels: 2 };
+    let mut tree: BKeyTree = BKeyTree::with_storage(DirectoryStorage::with_layout(
+        "/tmp/bkeytreedir-fanout",
+        layout,
+    )?)?;
+
+    for block in 0..1000 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+
+    let key = utils::generate_key(&mut rng);
+    let root_id = tree.root_id();
+    tree.persist(key)?;
+    let entries = tree.entries()?;
+    drop(tree);
+
+    // Objects live two levels down, named by their ID's low bytes.
+    let root_path = format!(
+        "/tmp/bkeytreedir-fanout/{:02x}/{:02x}/{root_id}",
+        root_id & 0xff,
+        (root_id >> 8) & 0xff
+    );
+    assert!(fs::metadata(root_path).is_ok());
+
+    // Reopening picks up the recorded layout, whatever is asked for.
+    let mut tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-fanout", key)?;
+    assert_eq!(tree.entries()?, entries);
+    assert!(tree.orphans()?.is_empty());
+    let stats = tree.stats_with_storage()?;
+    assert_eq!(stats.objects, Some(stats.nodes + 1));
+    drop(tree);
+
+    // A flat directory from before layouts were recorded stays flat.
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-fanout");
+    let mut tree = BKeyTree::new("/tmp/bkeytreedir-fanout")?;
+    tree.insert(0, utils::generate_key(&mut rng))?;
+    tree.persist(key)?;
+    let root_id = tree.root_id();
+    drop(tree);
+    fs::remove_file("/tmp/bkeytreedir-fanout/.layout")?;
+
+    let storage = DirectoryStorage::with_layout("/tmp/bkeytreedir-fanout", layout)?;
+    assert_eq!(storage.layout(), dir::Layout::Flat);
+    let tree: BKeyTree = BKeyTree::reload_with_storage(root_id, storage, key)?;
+    assert!(tree.contains(&0)?);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-fanout");
+
+    Ok(())
+}
+
+#[cfg(unix)]
+#[test]
+fn permissions() -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-permissions");
+
+    let mut rng = ThreadRng::default();
+    let layout = dir::Layout::FanOut { levels: 1 };
+    let storage = DirectoryStorage::with_layout("/tmp/bkeytreedir-permissions", layout)?;
+    let mut tree: BKeyTree = BKeyTree::with_storage(storage)?;
+    tree.insert(0, utils::generate_key(&mut rng))?;
+    tree.persist(utils::generate_key(&mut rng))?;
+    drop(tree);
+
+    // Only the owner can get at anything the storage creates.
+    let mode = |path: &str| -> Result<u32> { Ok(fs::metadata(path)?.permissions().mode() & 0o777) };
+    assert_eq!(mode("/tmp/bkeytreedir-permissions")?, 0o700);
+    assert_eq!(mode("/tmp/bkeytreedir-permissions/00")?, 0o700);
+    assert_eq!(mode("/tmp/bkeytreedir-permissions/00/0")?, 0o600);
+    assert_eq!(mode("/tmp/bkeytreedir-permissions/01/1")?, 0o600);
+    assert_eq!(mode("/tmp/bkeytreedir-permissions/.lock")?, 0o600);
+
+    // A directory that others can write to is refused.
+    fs::set_permissions(
+        "/tmp/bkeytreedir-permissions",
+        fs::Permissions::from_mode(0o777),
+    )?;
+    assert!(matches!(
+        BKeyTree::new("/tmp/bkeytreedir-permissions"),
+        Err(Error::Storage(dir::Error::Permissions(_)))
+    ));
+    fs::set_permissions(
+        "/tmp/bkeytreedir-permissions",
+        fs::Permissions::from_mode(0o700),
+    )?;
+
+    // So is one holding a subdirectory, an object or a layout file that others can write to.
+    for (path, mode) in [("00", 0o700), ("00/0", 0o600), (".layout", 0o600)] {
+        let path = format!("/tmp/bkeytreedir-permissions/{path}");
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o777))?;
+        assert!(matches!(
+            BKeyTree::new("/tmp/bkeytreedir-permissions"),
+            Err(Error::Storage(dir::Error::Permissions(_)))
+        ));
+        fs::set_permissions(&path, fs::Permissions::from_mode(mode))?;
+    }
+
+    // A subdirectory that turns up once the storage is open is checked before it's written to.
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-permissions")?;
+    let id = (0..3).map(|_| storage.alloc_id()).last().unwrap()?;
+    fs::create_dir(format!("/tmp/bkeytreedir-permissions/{id:02x}"))?;
+    fs::set_permissions(
+        format!("/tmp/bkeytreedir-permissions/{id:02x}"),
+        fs::Permissions::from_mode(0o777),
+    )?;
+    assert!(matches!(
+        storage.replace_handle(&id),
+        Err(dir::Error::Permissions(_))
+    ));
+    drop(storage);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-permissions");
+
+    Ok(())
+}
+
+/// Applies a random mix of operations to both a tree and a `BTreeMap` model, checking that they
+/// agree and that the tree's invariants hold after every step.
+fn check_against_model(degree: usize, steps: usize) -> Result<()> {
+    let path = format!("/tmp/bkeytreedir-model-{degree}");
+    let _ = fs::remove_dir_all(&path);
+
+    let mut rng = StdRng::seed_from_u64(degree as u64);
+    let mut tree = BKeyTree::with_degree(&path, degree)?;
+
+    // The model of the tree's entries, as well as the pending epoch state.
+    let mut model = BTreeMap::new();
+    let mut in_flight = HashMap::new();
+    let mut updated = HashSet::new();
+
+    for _ in 0..steps {
+        let block = rng.gen_range(0..256);
+
+        match rng.gen_range(0..100) {
+            0..=34 => {
+                let key = utils::generate_key(&mut rng);
+                assert_eq!(tree.insert(block, key)?, model.get(&block).copied());
+                model.entry(block).or_insert(key);
+            }
+            35..=44 => {
+                let key = utils::generate_key(&mut rng);
+                assert_eq!(
+                    tree.insert_for_update(block, key)?,
+                    model.get(&block).copied()
+                );
+                model.entry(block).or_insert(key);
+            }
+            45..=69 => {
+                assert_eq!(tree.remove(&block)?, model.remove(&block));
+            }
+            70..=89 => {
+                let key = if rng.gen() {
+                    updated.insert(block);
+                    tree.update(block)?
+                } else {
+                    tree.derive(block)?
+                };
+
+                // Derived keys are stable until the next commit.
+                match model.get(&block).or(in_flight.get(&block)) {
+                    Some(expected) => assert_eq!(key, *expected),
+                    None => {
+                        in_flight.insert(block, key);
+                    }
+                }
+            }
+            90..=96 => {
+                let mut committed = tree.commit();
+                committed.sort_unstable();
+
+                let mut expected = updated.drain().collect::<Vec<_>>();
+                expected.sort_unstable();
+                assert_eq!(committed, expected);
+
+                // In-flight blocks that weren't updated get inserted.
+                for (block, key) in in_flight.drain() {
+                    if !expected.contains(&block) {
+                        model.entry(block).or_insert(key);
+                    }
+                }
+
+                // Updated blocks that are in the tree get fresh keys.
+                for block in &expected {
+                    if let Some(old) = model.get_mut(block) {
+                        let new = *tree.get(block)?.unwrap();
+                        assert_ne!(new, *old);
+                        *old = new;
+                    }
+                }
+            }
+            _ => {
+                let key = utils::generate_key(&mut rng);
+                let root_id = tree.root_id();
+                tree.persist(key)?;
+
+                drop(tree);
+                tree = BKeyTree::reload(root_id, &path, key)?;
+            }
+        }
+
+        tree.verify()?;
+        assert_eq!(tree.len(), model.len());
+        assert_eq!(
+            tree.entries()?,
+            model.iter().map(|(b, k)| (*b, *k)).collect::<Vec<_>>()
+        );
+    }
+
+    let _ = fs::remove_dir_all(&path);
+
+    Ok(())
+}
+
+#[test]
+fn model() -> Result<()> {
+    for degree in 2..=16 {
+        check_against_model(degree, 2000)?;
+    }
+
+    Ok(())
+}
+
+#[test]
+fn failed_commits() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: FaultyTree =
+        BKeyTree::with_storage_and_degree(FaultyStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..64 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    let old = *tree.get(&0)?.unwrap();
+    tree.update(0)?;
+    tree.persist(key)?;
+
+    // After a reload, the leaf holding the updated block has to be read in to commit it. Failing
+    // to leaves the update pending rather than panicking or dropping it.
+    let root_id = tree.root_id();
+    let mut tree =
+        FaultyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.storage.lock().fail_nth(Op::Read, 0);
+    assert!(tree.try_commit().is_err());
+
+    tree.storage.lock().fail_nth(Op::Read, 0);
+    assert!(tree.commit().is_empty());
+    assert!(tree.take_commit_error().is_some());
+
+    assert_eq!(tree.commit(), vec![0]);
+    assert!(tree.take_commit_error().is_none());
+    assert_ne!(*tree.get(&0)?.unwrap(), old);
+    tree.verify()?;
+
+    // A block in the root is updated alongside a path that has to be read in after a reload.
+    // Failing to read it re-keys nothing, not even the blocks already at hand.
+    let in_root = tree.root.keys[0];
+    let old = *tree.get(&in_root)?.unwrap();
+    tree.insert_for_update(100, utils::generate_key(&mut rng))?;
+    tree.update(in_root)?;
+    tree.persist(key)?;
+
+    let root_id = tree.root_id();
+    let mut tree =
+        FaultyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.storage.lock().fail_nth(Op::Read, 0);
+    assert!(tree.try_commit().is_err());
+    assert_eq!(*tree.get(&in_root)?.unwrap(), old);
+
+    let committed = tree.commit();
+    assert!(committed.contains(&in_root));
+    assert_ne!(*tree.get(&in_root)?.unwrap(), old);
+    tree.verify()?;
+
+    Ok(())
+}
+
+#[test]
+fn persisted_blocks() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..64 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(key)?;
+
+    // Nothing is written for a block that isn't there, or one with nothing to persist.
+    let before = tree.storage.lock().snapshot();
+    assert!(!tree.persist_block(&1000, key)?);
+    assert!(tree.persist_block(&5, key)?);
+    assert_eq!(
+        tree.storage.lock().snapshot().since(&before).total().writes,
+        0
+    );
+
+    // An updated block's new key is persisted.
+    tree.update(5)?;
+    tree.commit();
+    let updated = *tree.get(&5)?.unwrap();
+    assert!(tree.persist_block(&5, key)?);
+
+    // So is an in-flight one, along with the nodes its insertion splits off the path.
+    let derived = tree.derive(1000)?;
+    assert!(tree.persist_block(&1000, key)?);
+
+    let root_id = tree.root_id();
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.verify()?;
+    assert_eq!(*tree.get(&5)?.unwrap(), updated);
+    assert_eq!(*tree.get(&1000)?.unwrap(), derived);
+    assert_eq!(tree.len(), 65);
+
+    Ok(())
+}
+
+#[test]
+fn decoders() -> Result<()> {
+    type E = std::io::Error;
+
+    let ids = utils::serialize_ids(&[1, 2, 3]);
+    let keys = utils::serialize_keys(&[[1; 32], [2; 32]]);
+    let map = utils::serialize_keys_map(&HashMap::from([(7, [7; 32])]));
+    assert_eq!(utils::deserialize_ids::<E>(&ids)?, vec![1, 2, 3]);
+    assert_eq!(
+        utils::deserialize_keys::<E, 32>(&keys)?,
+        vec![[1; 32], [2; 32]]
+    );
+    assert_eq!(
+        utils::deserialize_keys_map::<E, 32>(&map)?,
+        HashMap::from([(7, [7; 32])])
+    );
+
+    // Arrays cut short or with bytes left over are refused, as are lengths that would overflow.
+    let oversized = |raw: &[u8]| [raw, &[0]].concat();
+    for len in 0..ids.len() {
+        assert!(utils::deserialize_ids::<E>(&ids[..len]).is_err());
+    }
+    for len in 0..keys.len() {
+        assert!(utils::deserialize_keys::<E, 32>(&keys[..len]).is_err());
+    }
+    for len in 0..map.len() {
+        assert!(utils::deserialize_keys_map::<E, 32>(&map[..len]).is_err());
+    }
+    assert!(utils::deserialize_ids::<E>(&oversized(&ids)).is_err());
+    assert!(utils::deserialize_keys::<E, 32>(&oversized(&keys)).is_err());
+    assert!(utils::deserialize_keys_map::<E, 32>(&oversized(&map)).is_err());
+    assert!(utils::deserialize_ids::<E>(&u64::MAX.to_le_bytes()).is_err());
+    assert!(utils::deserialize_keys::<E, 32>(&u64::MAX.to_le_bytes()).is_err());
+
+    // Length prefixes can't reach past the end of the input.
+    let mut raw = vec![];
+    utils::push_length_prefixed_bytes_clear(&mut raw, &ids);
+    for len in 0..raw.len() {
+        assert!(utils::take_length_prefixed_bytes_clear::<E>(&mut &raw[..len]).is_err());
+    }
+    let mut rest = &raw[..];
+    assert_eq!(
+        utils::take_length_prefixed_bytes_clear::<E>(&mut rest)?,
+        ids
+    );
+    assert!(rest.is_empty());
+    assert!(utils::take_u64::<E>(&mut &[0; 7][..]).is_err());
+    assert!(
+        utils::take_length_prefixed_bytes_clear::<E>(&mut &u64::MAX.to_le_bytes()[..]).is_err()
+    );
+
+    // Nodes have to be shaped so that the tree can't index into them out of bounds.
+    let none = utils::serialize_ids(&[]);
+    let no_keys = utils::serialize_keys::<32>(&[]);
+    let one = utils::serialize_ids(&[1]);
+    let two = utils::serialize_ids(&[1, 2]);
+    let one_key = utils::serialize_keys(&[[0; 32]]);
+    let two_keys = utils::serialize_keys(&[[0; 32], [1; 32]]);
+    let from_fields = |fields: [&[u8]; 5]| {
+        Node::<32>::from_fields::<E>(0, fields[0], fields[1], fields[2], fields[3], fields[4])
+    };
+
+    assert!(from_fields([&one, &one_key, &none, &no_keys, &no_keys]).is_ok());
+    assert!(from_fields([&one, &one_key, &two, &two_keys, &two_keys]).is_ok());
+    assert!(from_fields([&one, &two_keys, &none, &no_keys, &no_keys]).is_err());
+    assert!(from_fields([&one, &one_key, &one, &one_key, &one_key]).is_err());
+    assert!(from_fields([&one, &one_key, &two, &one_key, &two_keys]).is_err());
+    assert!(from_fields([&one, &one_key, &two, &two_keys, &one_key]).is_err());
+    assert!(from_fields([&one[..8], &one_key, &none, &no_keys, &no_keys]).is_err());
+    assert!(from_fields([&one, &oversized(&one_key), &none, &no_keys, &no_keys]).is_err());
+
+    Ok(())
+}
+
+#[test]
+fn replacements() -> Result<()> {
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-replacements");
+
+    // A replacement discards whatever the object held before, even if it was longer.
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-replacements")?;
+    let id = storage.alloc_id()?;
+    assert!(storage.replace_handle(&id)?.write_all(&[1; 100]).is_ok());
+    storage.commit_handle(&id)?;
+    assert!(storage.replace_handle(&id)?.write_all(&[2; 10]).is_ok());
+    storage.commit_handle(&id)?;
+    assert_eq!(storage.size(&id)?, 10);
+    assert_eq!(
+        utils::read_bytes::<DirectoryStorage>(&mut storage.read_handle(&id)?, 10)?,
+        [2; 10]
+    );
+    drop(storage);
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-replacements");
+
+    // So a root that shrinks leaves nothing of its old contents behind its header and node.
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+    let mut tree = BKeyTree::with_degree("/tmp/bkeytreedir-replacements", 4)?;
+    for block in 0..7 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(key)?;
+    for block in 0..6 {
+        tree.remove(&block)?;
+    }
+    tree.persist(key)?;
+
+    let root_id = tree.root_id();
+    let size = tree.storage.lock().size(&root_id)?;
+    let encoded = tree.root.encode::<Aes256Ctr, dir::Error>(key)?;
+    assert_eq!(size, RootHeader::SIZE + encoded.len() as u64);
+
+    drop(tree);
+    let tree = BKeyTree::reload(root_id, "/tmp/bkeytreedir-replacements", key)?;
+    assert_eq!(tree.range(..)?.len(), 1);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-replacements");
+
+    Ok(())
+}
+
+#[test]
+fn syncing() -> Result<()> {
+    let mut storage = FaultyStorage::new(MemoryStorage::new());
+    let [a, b] = [(); 2].map(|_| storage.alloc_id().unwrap());
+    let write = |storage: &mut FaultyStorage<MemoryStorage>, raw: &[u8]| -> Result<()> {
+        for id in [a, b] {
+            assert!(storage.replace_handle(&id)?.write_all(raw).is_ok());
+            storage.commit_handle(&id)?;
+        }
+        Ok(())
+    };
+    let read = |storage: &mut FaultyStorage<MemoryStorage>| -> Result<Vec<Vec<u8>>> {
+        [a, b]
+            .into_iter()
+            .map(|id| {
+                Ok(utils::read_bytes::<FaultyStorage<MemoryStorage>>(
+                    &mut storage.read_handle(&id)?,
+                    1,
+                )?)
+            })
+            .collect()
+    };
+
+    // A crash loses whatever wasn't synced since the last sync.
+    write(&mut storage, b"1")?;
+    storage.sync()?;
+    write(&mut storage, b"2")?;
+    storage.crash()?;
+    assert_eq!(read(&mut storage)?, [b"1", b"1"]);
+
+    // Syncing one object keeps it, and only it.
+    write(&mut storage, b"2")?;
+    storage.sync_id(&a)?;
+    storage.crash()?;
+    assert_eq!(read(&mut storage)?, [b"2", b"1"]);
+
+    write(&mut storage, b"3")?;
+    storage.sync()?;
+    storage.crash()?;
+    assert_eq!(read(&mut storage)?, [b"3", b"3"]);
+
+    // Directory storage syncs objects written in place, and takes committed replacements to
+    // be durable already.
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-syncing");
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-syncing")?;
+    let [a, b] = [(); 2].map(|_| storage.alloc_id().unwrap());
+    assert!(storage.write_handle(&a)?.write_all(b"1").is_ok());
+    assert!(storage.replace_handle(&b)?.write_all(b"1").is_ok());
+    storage.commit_handle(&b)?;
+    storage.sync_id(&a)?;
+    storage.sync_id(&b)?;
+    assert!(storage.write_handle(&a)?.write_all(b"2").is_ok());
+    storage.sync()?;
+    drop(storage);
+
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-syncing")?;
+    for (id, expected) in [(a, b"2"), (b, b"1")] {
+        assert_eq!(
+            utils::read_bytes::<DirectoryStorage>(&mut storage.read_handle(&id)?, 1)?,
+            expected
+        );
+    }
+    drop(storage);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-syncing");
+
+    Ok(())
+}
+
+#[test]
+fn pending_replacements() -> Result<()> {
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-pending");
+
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-pending")?;
+    let id = storage.alloc_id()?;
+    assert!(storage.replace_handle(&id)?.write_all(b"old").is_ok());
+    storage.commit_handle(&id)?;
+
+    // A replacement isn't seen until it's committed, and one that never is changes nothing.
+    assert!(storage.replace_handle(&id)?.write_all(b"new!").is_ok());
+    let read = |storage: &mut DirectoryStorage| -> Result<Vec<u8>> {
+        let size = storage.size(&id)?;
+        Ok(utils::read_bytes::<DirectoryStorage>(
+            &mut storage.read_handle(&id)?,
+            size,
+        )?)
+    };
+    assert_eq!(read(&mut storage)?, b"old");
+    assert!(fs::metadata(format!("/tmp/bkeytreedir-pending/{id}.tmp")).is_ok());
+    assert_eq!(storage.ids()?, vec![id]);
+    drop(storage);
+
+    // What a crash leaves of it is cleaned up the next time the directory is opened for
+    // writing, but not for reading.
+    let storage = DirectoryStorage::open_read_only("/tmp/bkeytreedir-pending")?;
+    assert!(fs::metadata(format!("/tmp/bkeytreedir-pending/{id}.tmp")).is_ok());
+    drop(storage);
+
+    let mut storage = DirectoryStorage::new("/tmp/bkeytreedir-pending")?;
+    assert!(fs::metadata(format!("/tmp/bkeytreedir-pending/{id}.tmp")).is_err());
+    assert_eq!(read(&mut storage)?, b"old");
+
+    // Once committed, the replacement takes the object's place whole.
+    assert!(storage.replace_handle(&id)?.write_all(b"new!").is_ok());
+    storage.commit_handle(&id)?;
+    assert_eq!(read(&mut storage)?, b"new!");
+    drop(storage);
+
+    let _ = fs::remove_dir_all("/tmp/bkeytreedir-pending");
+
+    Ok(())
+}
+
+#[test]
+fn formats() -> Result<()> {
+    type Tree = BKeyTree<ThreadRng, MemoryStorage>;
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    // The root starts with a header tagging the format, followed by the metadata's ID and hash
+    // and a MAC of the lot.
+    let mut tree: Tree = BKeyTree::with_storage(MemoryStorage::new())?;
+    tree.insert(0, utils::generate_key(&mut rng))?;
+    tree.persist(key)?;
+    let root_id = tree.root_id();
+    let mut storage = tree.into_storage().ok().unwrap();
+
+    let mut header =
+        utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&root_id)?, RootHeader::SIZE)?;
+    assert_eq!(header[..8], *b"sdbtree\0");
+    assert_eq!(header[8..16], 4u64.to_le_bytes());
+
+    // A root in any other format, such as the one before the metadata's hash, is refused.
+    header[8] = 1;
+    let size = storage.size(&root_id)?;
+    let raw = utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&root_id)?, size)?;
+    let tagged = [&header[..], &raw[header.len()..]].concat();
+    assert!(storage.replace_handle(&root_id)?.write_all(&tagged).is_ok());
+    storage.commit_handle(&root_id)?;
+    assert!(matches!(
+        Tree::load_root(root_id, root_id, key, &mut storage),
+        Err(Error::Format(id)) if id == root_id
+    ));
+
+    // A tree from before the tag: two leaves under a root, each node as four encrypted fields
+    // without hashes, and the metadata ID appended to the root at every persist.
+    let legacy_node = |keys: &[u64],
+                       vals: &[Key<32>],
+                       children: &[u64],
+                       children_keys: &[Key<32>],
+                       key: Key<32>|
+     -> Result<Vec<u8>> {
+        let mut raw = vec![];
+        for field in [
+            utils::serialize_ids(keys),
+            utils::serialize_keys(vals),
+            utils::serialize_ids(children),
+            utils::serialize_keys(children_keys),
+        ] {
+            utils::push_length_prefixed_bytes::<Aes256Ctr, std::io::Error, 32>(
+                &mut raw, &field, key,
+            )?;
+        }
+        Ok(raw)
+    };
+
+    let mut storage = MemoryStorage::new();
+    let [root_id, left, right, meta_id] = [(); 4].map(|_| storage.alloc_id().unwrap());
+    let vals: Vec<Key<32>> = (0..5).map(|_| utils::generate_key(&mut rng)).collect();
+    let children_keys = [utils::generate_key(&mut rng), utils::generate_key(&mut rng)];
+    let in_flight = HashMap::from([(7, utils::generate_key(&mut rng))]);
+
+    let mut root_raw = legacy_node(&[3], &vals[2..3], &[left, right], &children_keys, key)?;
+    root_raw.extend(99u64.to_le_bytes());
+    root_raw.extend(meta_id.to_le_bytes());
+    let mut meta_raw = [5u64.to_le_bytes(), 2u64.to_le_bytes()].concat();
+    utils::push_length_prefixed_bytes_clear(
+        &mut meta_raw,
+        &bincode::serialize(&HashSet::<u64>::new())?,
+    );
+    utils::push_length_prefixed_bytes_clear(
+        &mut meta_raw,
+        &bincode::serialize(&HashSet::from([4u64]))?,
+    );
+    utils::push_length_prefixed_bytes_clear(&mut meta_raw, &utils::serialize_keys_map(&in_flight));
+
+    for (id, raw) in [
+        (root_id, root_raw),
+        (
+            left,
+            legacy_node(&[1, 2], &vals[..2], &[], &[], children_keys[0])?,
+        ),
+        (
+            right,
+            legacy_node(&[4, 5], &vals[3..], &[], &[], children_keys[1])?,
+        ),
+        (meta_id, meta_raw),
+    ] {
+        assert!(storage.write_handle(&id)?.write_all(&raw).is_ok());
+    }
+
+    // It isn't mistaken for a tagged root, but it can be migrated.
+    assert!(matches!(
+        Tree::load_root(root_id, root_id, key, &mut storage),
+        Err(Error::Format(_))
+    ));
+    let mut tree = Tree::migrate_with_storage(root_id, storage, key)?;
+    let entries = [1, 2, 3, 4, 5].into_iter().zip(vals).collect::<Vec<_>>();
+    assert_eq!(tree.root_id(), root_id);
+    assert_eq!(tree.entries()?, entries);
+    assert_eq!(tree.in_flight_blocks, in_flight);
+    assert_eq!(tree.updated_blocks, HashSet::from([4]));
+    tree.verify()?;
+
+    // Nothing of the old tree is left behind, and the new one reloads.
+    assert!(tree.orphans()?.is_empty());
+    for id in [left, right, meta_id] {
+        assert!(tree.storage.lock().size(&id).is_err());
+    }
+    let mut tree = Tree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    assert_eq!(tree.entries()?, entries);
+
+    Ok(())
+}
+
+type FaultyTree = BKeyTree<ThreadRng, FaultyStorage<MemoryStorage>>;
+
+/// Persists a tree, changes it, rotates some of its keys and persists it again, failing or
+/// tearing the `n`th write of the second persist and then crashing. Returns `false` once the
+/// second persist is done before its `n`th write.
+fn check_crash(n: u64, tear: bool) -> Result<bool> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let old_key = utils::generate_key(&mut rng);
+    let new_key = utils::generate_key(&mut rng);
+
+    let mut tree: FaultyTree =
+        BKeyTree::with_storage_and_degree(FaultyStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..64 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(old_key)?;
+    let old_root_id = tree.root_id();
+    let old = tree.entries()?;
+
+    for _ in 0..32 {
+        let block = rng.gen_range(0..96);
+        if rng.gen() {
+            tree.insert(block, utils::generate_key(&mut rng))?;
+        } else {
+            tree.remove(&block)?;
+        }
+    }
+
+    // Committing rewrites the nodes on the way to each updated block under fresh keys.
+    for _ in 0..8 {
+        let block = rng.gen_range(0..96);
+        if tree.contains(&block)? {
+            tree.update(block)?;
+        }
+    }
+    tree.try_commit()?;
+    let new_root_id = tree.root_id();
+    let new = tree.entries()?;
+
+    let writes = tree.storage.lock().count(Op::Write);
+    if tear {
+        tree.storage.lock().tear_nth_write(n, 8);
+    } else {
+        tree.storage.lock().fail_nth(Op::Write, n);
+    }
+    let persisted = tree.persist(new_key).is_ok();
+    if persisted && tree.storage.lock().count(Op::Write) <= writes + n {
+        return Ok(false);
+    }
+
+    // Whatever made it to storage before the crash, the tree reloads to one state or the other.
+    tree.storage.lock().crash()?;
+    let expected = match tree.load(new_root_id, new_key) {
+        Ok(()) => new,
+        Err(_) if !persisted => {
+            tree.load(old_root_id, old_key)?;
+            old
+        }
+        Err(err) => return Err(err.into()),
+    };
+
+    tree.verify()?;
+    assert_eq!(tree.entries()?, expected);
+
+    // Anything the interrupted persist left behind can be collected.
+    tree.gc()?;
+    assert!(tree.orphans()?.is_empty());
+    tree.verify()?;
+    assert_eq!(tree.entries()?, expected);
+
+    Ok(true)
+}
+
+#[test]
+fn crashes() -> Result<()> {
+    for tear in [false, true] {
+        let mut n = 0;
+        while check_crash(n, tear)? {
+            n += 1;
+        }
+        assert!(n > 0);
+    }
+
+    // A crash drops replacements that were staged but never committed.
+    let mut storage = FaultyStorage::new(MemoryStorage::new());
+    let id = storage.alloc_id()?;
+    assert!(storage.replace_handle(&id)?.write_all(b"staged").is_ok());
+    storage.crash()?;
+    assert!(storage.commit_handle(&id).is_err());
+    assert!(storage.size(&id).is_err());
+
+    Ok(())
+}
+
+type CountingTree = BKeyTree<ThreadRng, CountingStorage<MemoryStorage>>;
+
+#[test]
+fn node_loads() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..256 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    let height = tree.stats()?.height as u64;
+    tree.persist(key)?;
+
+    let root_id = tree.root_id();
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+
+    // Looking up a missing block loads each node below the root on the way to a leaf, once.
+    let before = tree.storage.lock().snapshot();
+    assert_eq!(tree.get(&1000)?, None);
+    let loads = tree.storage.lock().snapshot().since(&before);
+    assert_eq!(loads.total().reads, height - 1);
+    assert!(loads.ids().all(|id| loads.object(id).reads == 1));
+
+    // After that, the path is resident.
+    let before = tree.storage.lock().snapshot();
+    tree.get(&1000)?;
+    tree.insert(1000, utils::generate_key(&mut rng))?;
+    tree.update(1000)?;
+    tree.commit();
+    tree.remove(&1000)?;
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert_eq!((io.reads, io.writes), (0, 0));
+
+    // Removing from a cold tree also loads the siblings it borrows from or merges with, but
+    // still no node twice.
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    let before = tree.storage.lock().snapshot();
+    assert!(tree.remove(&0)?.is_some());
+    let loads = tree.storage.lock().snapshot().since(&before);
+    assert!(loads.total().reads <= 2 * (height - 1));
+    assert!(loads.ids().all(|id| loads.object(id).reads <= 1));
+
+    Ok(())
+}
+
+#[test]
+fn node_writes() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..256 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    let height = tree.stats()?.height;
+    tree.persist(key)?;
+
+    // Persisting an unchanged tree only rewrites the root and the metadata.
+    let written = |tree: &CountingTree, before: &Snapshot<u64>| {
+        let io = tree.storage.lock().snapshot().since(before);
+        io.ids().filter(|id| io.object(id).writes > 0).count()
+    };
+    let before = tree.storage.lock().snapshot();
+    tree.persist(key)?;
+    assert_eq!(written(&tree, &before), 2);
+
+    // Changing an entry only rewrites the nodes on the way to it, each to a fresh object.
+    *tree.get_mut(&0)?.unwrap() = utils::generate_key(&mut rng);
+    let before = tree.storage.lock().snapshot();
+    tree.persist(key)?;
+    assert_eq!(written(&tree, &before), height + 1);
+    tree.verify()?;
+
+    // Each of them is committed, and what only the old tree referred to is removed.
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert_eq!((io.commits, io.removes), (height as u64 + 1, height as u64));
+
+    // After a reset, an earlier snapshot is ahead of the counts, which mustn't underflow.
+    tree.storage.lock().reset();
+    assert_eq!(
+        tree.storage.lock().snapshot().since(&before).ids().count(),
+        0
+    );
+
+    Ok(())
+}
+
+#[test]
+fn lookups() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    let mut model = BTreeMap::new();
+    for _ in 0..256 {
+        let block = rng.gen_range(0..1024);
+        let key = utils::generate_key(&mut rng);
+        tree.insert(block, key)?;
+        model.entry(block).or_insert(key);
+    }
+    tree.persist(key)?;
+
+    let root_id = tree.root_id();
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+
+    // Lookups go through a shared reference, so several can be held at once.
+    let shared = &tree;
+    for block in 0..1024 {
+        assert_eq!(shared.lookup(&block)?, model.get(&block).copied());
+        assert_eq!(shared.contains(&block)?, model.contains_key(&block));
+    }
+
+    for (lo, hi) in [(0, 1024), (100, 100), (100, 101), (333, 777), (1000, 2000)] {
+        let expected: Vec<_> = model.range(lo..hi).map(|(b, k)| (*b, *k)).collect();
+        assert_eq!(shared.range(lo..hi)?, expected);
+        let expected: Vec<_> = model.range(lo..=hi).map(|(b, k)| (*b, *k)).collect();
+        assert_eq!(shared.range(lo..=hi)?, expected);
+    }
+    assert_eq!(
+        shared.range(..)?,
+        model.clone().into_iter().collect::<Vec<_>>()
+    );
+
+    // Once cached, nodes aren't read again, but they aren't added to the tree either.
+    let before = tree.storage.lock().snapshot();
+    tree.range(..)?;
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert_eq!(io.reads, 0);
+    assert_eq!(tree.stats()?.loaded, 1);
+
+    // Persisting drops the cache, as the IDs it freed may be handed out again.
+    let (&block, _) = model.iter().next().unwrap();
+    tree.update(block)?;
+    tree.commit();
+    tree.persist(key)?;
+    assert_ne!(tree.lookup(&block)?, model.get(&block).copied());
+    let before = tree.storage.lock().snapshot();
+    tree.range(..)?;
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert!(io.reads > 0);
+
+    // A cache too small for the tree keeps dropping nodes, which are then read in again.
+    tree.set_lookup_cache_capacity(4);
+    let expected: Vec<_> = tree.range(..)?;
+    let before = tree.storage.lock().snapshot();
+    assert_eq!(tree.range(..)?, expected);
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert!(io.reads > 0);
+
+    Ok(())
+}
+
+type CachedTree = BKeyTree<ThreadRng, CachedStorage<CountingStorage<MemoryStorage>>>;
+
+#[test]
+fn caching() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    // The cache is far smaller than the tree, so objects keep getting evicted and written back.
+    let storage = CachedStorage::new(CountingStorage::new(MemoryStorage::new()), 8);
+    let mut tree: CachedTree = BKeyTree::with_storage_and_degree(storage, 2)?;
+    let mut model = BTreeMap::new();
+
+    for _ in 0..8 {
+        for _ in 0..64 {
+            let block = rng.gen_range(0..256);
+            if rng.gen() {
+                let key = utils::generate_key(&mut rng);
+                tree.insert(block, key)?;
+                model.entry(block).or_insert(key);
+            } else {
+                tree.remove(&block)?;
+                model.remove(&block);
+            }
+        }
+
+        tree.persist(key)?;
+        let root_id = tree.root_id();
+        tree = CachedTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+
+        tree.verify()?;
+        assert_eq!(
+            tree.entries()?,
+            model.iter().map(|(b, k)| (*b, *k)).collect::<Vec<_>>()
+        );
+    }
+
+    // Once the root and metadata are loaded, loading them again doesn't reach storage.
+    let root_id = tree.root_id();
+    tree.load(root_id, key)?;
+    let before = tree.storage.lock().inner().snapshot();
+    tree.load(root_id, key)?;
+    let io = tree
+        .storage
+        .lock()
+        .inner()
+        .snapshot()
+        .since(&before)
+        .total();
+    assert_eq!(io.reads, 0);
+
+    // Repeated writes to an object are written back once.
+    let id = tree.storage.lock().alloc_id()?;
+    for byte in 0..4 {
+        let mut storage = tree.storage.lock();
+        let mut writer = storage.write_handle(&id)?;
+        assert!(writer.write_all(&[byte; 16]).is_ok());
+    }
+    let before = tree.storage.lock().inner().snapshot();
+    tree.storage.lock().sync()?;
+    let io = tree
+        .storage
+        .lock()
+        .inner()
+        .snapshot()
+        .since(&before)
+        .object(&id);
+    assert_eq!((io.writes, io.bytes_written), (1, 16));
+    tree.storage.lock().remove_id(&id)?;
+
+    // Everything the cache held back makes it to the storage underneath.
+    let storage = tree.into_storage().ok().unwrap().into_inner()?;
+    let mut tree = CountingTree::reload_with_storage(root_id, storage, key)?;
+    tree.verify()?;
+    assert_eq!(
+        tree.entries()?,
+        model.iter().map(|(b, k)| (*b, *k)).collect::<Vec<_>>()
+    );
+
+    // With syncs deferred, persisting over and over doesn't reach storage until the cache is
+    // flushed, which writes the root back once.
+    let storage = CachedStorage::deferred(CountingStorage::new(MemoryStorage::new()), 1024);
+    let mut tree: CachedTree = BKeyTree::with_storage_and_degree(storage, 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..64 {
+        let block_key = utils::generate_key(&mut rng);
+        tree.insert(block, block_key)?;
+        model.insert(block, block_key);
+        tree.persist(key)?;
+    }
+    let root_id = tree.root_id();
+    assert_eq!(tree.storage.lock().inner().snapshot().total().writes, 0);
+
+    // Those persists aren't durable, so a trusted counter can't be advanced by them.
+    assert!(matches!(
+        tree.set_trusted_counter(FileCounter::new("/tmp/bkeytree-deferred-counter")),
+        Err(Error::DeferredSyncs)
+    ));
+    tree.storage.lock().flush()?;
+    let io = tree.storage.lock().inner().snapshot();
+    assert_eq!(io.object(&root_id).writes, 1);
+
+    let storage = tree.into_storage().ok().unwrap().into_inner()?;
+    let mut tree = CountingTree::reload_with_storage(root_id, storage, key)?;
+    tree.verify()?;
+    assert!(tree.orphans()?.is_empty());
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    // An object that fails to be written back on eviction stays cached until it can be.
+    let mut inner = FaultyStorage::new(MemoryStorage::new());
+    inner.fail_nth(Op::Write, 0);
+    let mut storage = CachedStorage::new(inner, 1);
+    let [a, b] = [(); 2].map(|_| storage.alloc_id().unwrap());
+    assert!(storage.write_handle(&a)?.write_all(b"a").is_ok());
+    assert!(storage.write_handle(&b).is_err());
+    assert!(storage.write_handle(&b)?.write_all(b"b").is_ok());
+    assert_eq!(storage.inner().size(&a)?, 1);
+    let storage = storage.into_inner()?;
+    assert_eq!(storage.size(&b)?, 1);
+
+    Ok(())
+}
+
+type SharedTree = shared::SharedBKeyTree<OsRng, MemoryStorage>;
+
+#[test]
+fn sharing() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: BKeyTree<OsRng, MemoryStorage> =
+        BKeyTree::with_storage_and_degree(MemoryStorage::new(), 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..1000 {
+        let key = utils::generate_key(&mut rng);
+        tree.insert(block, key)?;
+        model.insert(block, key);
+    }
+    tree.persist(key)?;
+
+    // Start cold, so readers have to load nodes while a writer inserts alongside them.
+    let root_id = tree.root_id();
+    let tree: SharedTree =
+        BKeyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?.into();
+    let new_keys: Vec<_> = (1000..1500)
+        .map(|block| (block, utils::generate_key(&mut rng)))
+        .collect();
+
+    std::thread::scope(|scope| -> Result<()> {
+        let readers: Vec<_> = (0..4)
+            .map(|_| {
+                scope.spawn(|| -> Result<()> {
+                    for (block, key) in &model {
+                        assert_eq!(tree.get(block)?, Some(*key));
+                    }
+                    Ok(())
+                })
+            })
+            .collect();
+
+        for (block, key) in &new_keys {
+            assert_eq!(tree.insert(*block, *key)?, None);
+        }
+
+        for reader in readers {
+            reader.join().unwrap()?;
+        }
+
+        Ok(())
+    })?;
+
+    // A thread panicking while it holds the lock doesn't take the tree down with it.
+    let block = 1500;
+    let new_key = utils::generate_key(&mut rng);
+    std::thread::scope(|scope| {
+        let writer = scope.spawn(|| {
+            let _tree = tree.write();
+            panic!("writer failed");
+        });
+        assert!(writer.join().is_err());
+    });
+    assert_eq!(tree.insert(block, new_key)?, None);
+    let updated = tree.update(block)?;
+    assert_eq!(tree.try_commit()?, vec![block]);
+    assert_eq!(tree.get(&block)?, Some(updated));
+    model.extend(new_keys);
+    model.insert(block, updated);
+    let mut tree = tree.into_inner();
+    tree.verify()?;
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[test]
+fn snapshots() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage_and_degree(MemoryStorage::new(), 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..1000 {
+        let key = utils::generate_key(&mut rng);
+        tree.insert(block, key)?;
+        model.insert(block, key);
+    }
+    assert!(tree.snapshot().is_none());
+    tree.persist(utils::generate_key(&mut rng))?;
+
+    // Each snapshot keeps seeing its own version while the tree moves on, including after
+    // merges and rekeys that replace the nodes it refers to.
+    let mut snapshots = vec![];
+    for round in 0..4 {
+        snapshots.push((tree.snapshot().unwrap(), model.clone()));
+
+        for block in (round * 250..(round + 1) * 250).step_by(2) {
+            tree.remove(&block)?;
+            model.remove(&block);
+        }
+        for _ in 0..100 {
+            let block = rng.gen_range(0..2000);
+            let key = utils::generate_key(&mut rng);
+            tree.insert(block, key)?;
+            model.entry(block).or_insert(key);
+        }
+        let &block = model.keys().next().unwrap();
+        tree.update(block)?;
+        tree.commit();
+        model.insert(block, tree.lookup(&block)?.unwrap());
+        tree.persist(utils::generate_key(&mut rng))?;
+    }
+
+    // Nothing a snapshot refers to counts as an orphan. A snapshot's cache is bounded, and one
+    // too small for the tree reads nodes in again as needed.
+    assert!(tree.orphans()?.is_empty());
+    tree.gc()?;
+    snapshots[0].0.set_cache_capacity(4);
+    for (snapshot, model) in &snapshots {
+        assert_eq!(snapshot.len(), model.len());
+        for block in [0, 1, 499, 500, 1001, 1999] {
+            assert_eq!(snapshot.get(&block)?, model.get(&block).copied());
+        }
+        assert_eq!(
+            snapshot.range(..)?,
+            model.clone().into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    // The storage can't be taken from the tree while they share it.
+    let mut tree = tree.into_storage().err().unwrap();
+
+    // Once they're dropped, the next persist removes what only they referred to.
+    let objects = tree.stats_with_storage()?.objects.unwrap();
+    drop(snapshots);
+    tree.persist(utils::generate_key(&mut rng))?;
+    tree.gc()?;
+    let stats = tree.stats_with_storage()?;
+    assert!(stats.objects.unwrap() < objects);
+    assert_eq!(stats.objects, Some(stats.nodes + 1));
+
+    tree.verify()?;
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[test]
+fn forks() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..1000 {
+        let key = utils::generate_key(&mut rng);
+        tree.insert(block, key)?;
+        model.insert(block, key);
+    }
+    tree.persist(key)?;
+    let root_id = tree.root_id();
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+
+    // Forking only adds a root, a metadata object and the reference counts, without reading in
+    // a single node.
+    let objects = tree.stats_with_storage()?.objects.unwrap();
+    let before = tree.storage.lock().snapshot();
+    let mut fork = tree.fork(key)?;
+    let mut fork_model = model.clone();
+    let io = tree.storage.lock().snapshot().since(&before).total();
+    assert_eq!(io.reads, 0);
+    assert_eq!(tree.stats_with_storage()?.objects, Some(objects + 3));
+    assert_eq!(fork.entries()?, tree.entries()?);
+
+    // The two diverge through merges, splits and rekeys without affecting each other.
+    for (tree, model, offset) in [(&mut tree, &mut model, 0), (&mut fork, &mut fork_model, 1)] {
+        for block in (offset..1000).step_by(3) {
+            tree.remove(&block)?;
+            model.remove(&block);
+        }
+        for _ in 0..200 {
+            let block = rng.gen_range(1000..3000);
+            let key = utils::generate_key(&mut rng);
+            tree.insert(block, key)?;
+            model.entry(block).or_insert(key);
+        }
+        let &block = model.keys().nth(offset as usize).unwrap();
+        tree.update(block)?;
+        tree.commit();
+        model.insert(block, tree.lookup(&block)?.unwrap());
+        tree.persist(key)?;
+    }
+
+    // Neither collects what the other refers to.
+    assert!(tree.gc()?.is_empty());
+    assert!(fork.gc()?.is_empty());
+    for (tree, model) in [(&mut tree, &model), (&mut fork, &fork_model)] {
+        tree.verify()?;
+        assert_eq!(
+            tree.entries()?,
+            model.clone().into_iter().collect::<Vec<_>>()
+        );
+    }
+
+    // The counts survive a reload of both.
+    let (root_id, fork_root_id) = (tree.root_id(), fork.root_id());
+    drop(fork);
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    let mut fork = tree.reload_fork(fork_root_id, key)?;
+    assert!(tree.gc()?.is_empty());
+    fork.verify()?;
+
+    // The counts are authenticated, so they can't be doctored to have a tree remove what a fork
+    // still refers to.
+    let (refs_id, refs_key) = {
+        let refs = tree.refs.as_ref().unwrap().lock();
+        (refs.id, refs.key)
+    };
+    {
+        let mut storage = tree.storage.lock();
+        let size = storage.size(&refs_id)?;
+        let mut raw = utils::read_bytes::<CountingStorage<MemoryStorage>>(
+            &mut storage.read_handle(&refs_id)?,
+            size,
+        )?;
+        raw[0] ^= 1;
+        assert!(storage.replace_handle(&refs_id)?.write_all(&raw).is_ok());
+        storage.commit_handle(&refs_id)?;
+        assert!(matches!(
+            Refs::load(refs_id, refs_key, &mut *storage),
+            Err(Error::MacMismatch(id)) if id == refs_id
+        ));
+
+        raw[0] ^= 1;
+        assert!(storage.replace_handle(&refs_id)?.write_all(&raw).is_ok());
+        storage.commit_handle(&refs_id)?;
+    }
+    assert_eq!(
+        fork.entries()?,
+        fork_model.clone().into_iter().collect::<Vec<_>>()
+    );
+
+    // Once the fork has let go of every node, only the tree's are left.
+    for block in fork_model.keys() {
+        fork.remove(block)?;
+    }
+    fork.persist(key)?;
+    tree.persist(key)?;
+    assert!(tree.gc()?.is_empty());
+    let stats = tree.stats_with_storage()?;
+    assert_eq!(stats.objects, Some(stats.nodes + 4));
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    Ok(())
+}
+
+#[test]
+fn cleared_objects() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: CountingTree =
+        BKeyTree::with_storage_and_degree(CountingStorage::new(MemoryStorage::new()), 2)?;
+    for block in 0..1000 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(key)?;
+
+    // Clearing keeps the root's object, and the next persist removes those of the other nodes.
+    let root_id = tree.root_id();
+    assert_eq!(tree.clear()?, root_id);
+    tree.persist(key)?;
+    assert!(tree.orphans()?.is_empty());
+    assert_eq!(tree.stats_with_storage()?.objects, Some(2));
+
+    let mut tree =
+        CountingTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    tree.verify()?;
+    assert!(tree.entries()?.is_empty());
+
+    // A fork keeps what it shares with a tree that's cleared.
+    for block in 0..1000 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    let mut fork = tree.fork(key)?;
+    let entries = fork.entries()?;
+    tree.clear()?;
+    tree.persist(key)?;
+    assert!(tree.gc()?.is_empty());
+    assert!(fork.gc()?.is_empty());
+    fork.verify()?;
+    assert_eq!(fork.entries()?, entries);
+
+    Ok(())
+}
+
+#[test]
+fn generations() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage_and_degree(MemoryStorage::new(), 2)?;
+    let mut model = BTreeMap::new();
+    for block in 0..1000 {
+        let key = utils::generate_key(&mut rng);
+        tree.insert(block, key)?;
+        model.insert(block, key);
+    }
+    tree.set_kept_generations(2);
+    tree.persist(key)?;
+    assert!(tree.list_generations().is_empty());
+
+    // Each persist keeps the tree it supersedes, dropping the oldest past the limit.
+    let mut history = vec![model.clone()];
+    for round in 0..3 {
+        for block in (round..1000).step_by(4) {
+            tree.remove(&block)?;
+            model.remove(&block);
+        }
+        for _ in 0..100 {
+            let block = rng.gen_range(1000..2000);
+            let key = utils::generate_key(&mut rng);
+            tree.insert(block, key)?;
+            model.entry(block).or_insert(key);
+        }
+        tree.persist(key)?;
+        history.push(model.clone());
+    }
+    assert_eq!(tree.list_generations().len(), 2);
+    assert!(tree.gc()?.is_empty());
+
+    // The generations survive a reload, and the tree can be rolled back to either.
+    let (root_id, generations) = (tree.root_id(), tree.list_generations());
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    assert_eq!(tree.list_generations(), generations);
+    assert!(matches!(
+        tree.reload_generation(2, key),
+        Err(Error::NoGeneration(2))
+    ));
+    tree.reload_generation(1, key)?;
+    tree.persist(key)?;
+    assert!(tree.gc()?.is_empty());
+    tree.verify()?;
+    assert_eq!(
+        tree.entries()?,
+        history[1].clone().into_iter().collect::<Vec<_>>()
+    );
+
+    // The tree rolled back from is now the most recent generation, so the rollback can be undone.
+    tree.reload_generation(0, key)?;
+    tree.persist(key)?;
+    tree.verify()?;
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+
+    // Pruning leaves only the tree.
+    tree.prune_generations(key)?;
+    assert!(tree.list_generations().is_empty());
+    assert!(tree.gc()?.is_empty());
+    let stats = tree.stats_with_storage()?;
+    assert_eq!(stats.objects, Some(stats.nodes + 1));
+
+    Ok(())
+}
+
+#[test]
+fn epochs() -> Result<()> {
+    let _ = fs::remove_file("/tmp/bkeytree-epochs-counter");
+    let _ = fs::remove_file("/tmp/bkeytree-epochs-fresh-counter");
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage(MemoryStorage::new())?;
+    tree.set_trusted_counter(FileCounter::new("/tmp/bkeytree-epochs-counter"))?;
+    for block in 0..100 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    tree.persist(key)?;
+    assert_eq!(tree.epoch(), 1);
+
+    // Copy every object, as an attacker with access to storage could.
+    let root_id = tree.root_id();
+    let meta_id = tree.meta_id;
+    let mut storage = tree.into_storage().ok().unwrap();
+    let mut copy = MemoryStorage::new();
+    let mut forged = MemoryStorage::new();
+    for id in storage.ids()? {
+        let size = storage.size(&id)?;
+        let raw = utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&id)?, size)?;
+        for copy in [&mut copy, &mut forged] {
+            assert!(copy.replace_handle(&id)?.write_all(&raw).is_ok());
+            copy.commit_handle(&id)?;
+        }
+    }
+
+    // Each persist advances the counter along with the tree.
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> = BKeyTree::reload_with_storage_and_counter(
+        root_id,
+        storage,
+        key,
+        FileCounter::new("/tmp/bkeytree-epochs-counter"),
+    )?;
+    tree.remove(&0)?;
+    tree.persist(key)?;
+    assert_eq!(tree.epoch(), 2);
+    assert_eq!(FileCounter::new("/tmp/bkeytree-epochs-counter").read()?, 2);
+
+    // The copy still has the removed block's key, so it's refused.
+    assert!(matches!(
+        BKeyTree::<ThreadRng, MemoryStorage>::reload_with_storage_and_counter(
+            root_id,
+            copy,
+            key,
+            FileCounter::new("/tmp/bkeytree-epochs-counter"),
+        ),
+        Err(Error::Stale(1, 2))
+    ));
+
+    // The epoch is encrypted with a stream cipher, so flipping bits of its ciphertext flips the
+    // same bits of the epoch, here taking it from 1 to 2. The metadata's MAC gives that away.
+    let size = forged.size(&meta_id)?;
+    let mut raw = utils::read_bytes::<MemoryStorage>(&mut forged.read_handle(&meta_id)?, size)?;
+    let epoch_at = raw.len() - mem::size_of::<Hash>() - mem::size_of::<u64>();
+    raw[epoch_at] ^= 1 ^ 2;
+    assert!(forged.replace_handle(&meta_id)?.write_all(&raw).is_ok());
+    forged.commit_handle(&meta_id)?;
+    assert!(matches!(
+        BKeyTree::<ThreadRng, MemoryStorage>::reload_with_storage_and_counter(
+            root_id,
+            forged,
+            key,
+            FileCounter::new("/tmp/bkeytree-epochs-counter"),
+        ),
+        Err(Error::MacMismatch(id)) if id == meta_id
+    ));
+
+    // A counter left behind, as by a crash right after the root was persisted, catches up.
+    let storage = tree.into_storage().ok().unwrap();
+    let tree: BKeyTree<ThreadRng, MemoryStorage> = BKeyTree::reload_with_storage_and_counter(
+        root_id,
+        storage,
+        key,
+        FileCounter::new("/tmp/bkeytree-epochs-fresh-counter"),
+    )?;
+    assert_eq!(tree.epoch(), 2);
+    assert_eq!(
+        FileCounter::new("/tmp/bkeytree-epochs-fresh-counter").read()?,
+        2
+    );
+
+    let _ = fs::remove_file("/tmp/bkeytree-epochs-counter");
+    let _ = fs::remove_file("/tmp/bkeytree-epochs-fresh-counter");
+
+    Ok(())
+}
+
+#[test]
+fn hashes() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+    let first_child = |tree: &BKeyTree<ThreadRng, MemoryStorage>| match &tree.root.children[0] {
+        Child::Loaded(node) => node.id,
+        Child::Unloaded(id) => *id,
+    };
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage(MemoryStorage::new())?;
+    for block in 0..100 {
+        tree.insert(block, utils::generate_key(&mut rng))?;
+    }
+    assert_eq!(tree.root_hash(), None);
+
+    // Persisting again changes the hash, since it commits to the metadata, epoch and all.
+    tree.persist(key)?;
+    let hash = tree.root_hash();
+    assert!(hash.is_some());
+    let (old_meta, old_root) = {
+        let mut storage = tree.storage.lock();
+        let size = storage.size(&tree.meta_id)?;
+        let meta =
+            utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&tree.meta_id)?, size)?;
+        let size = storage.size(&tree.root.id)?;
+        let root =
+            utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&tree.root.id)?, size)?;
+        (meta, root)
+    };
+    tree.persist(key)?;
+    assert_ne!(tree.root_hash(), hash);
+    let hash = tree.root_hash();
+
+    // Hold on to the first child as it is now, to replay it later.
+    let old_id = first_child(&tree);
+    let old_raw = {
+        let mut storage = tree.storage.lock();
+        let size = storage.size(&old_id)?;
+        utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&old_id)?, size)?
+    };
+
+    *tree.get_mut(&0)?.unwrap() = utils::generate_key(&mut rng);
+    tree.persist(key)?;
+    assert_ne!(tree.root_hash(), hash);
+    let hash = tree.root_hash();
+
+    let root_id = tree.root_id();
+    let new_id = first_child(&tree);
+    let tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::reload_with_storage(root_id, tree.into_storage().ok().unwrap(), key)?;
+    assert_eq!(tree.root_hash(), hash);
+    let mut storage = tree.into_storage().ok().unwrap();
+
+    // The child's key didn't change, so the replayed object decrypts, but its hash gives it
+    // away.
+    assert!(storage.replace_handle(&new_id)?.write_all(&old_raw).is_ok());
+    storage.commit_handle(&new_id)?;
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::reload_with_storage(root_id, storage, key)?;
+    assert!(matches!(tree.verify(), Err(Error::HashMismatch(id)) if id == new_id));
+
+    // Nor does an old root get past the header's MAC once its header is pointed at the current
+    // metadata.
+    let (meta_id, meta_hash) = (tree.meta_id, tree.meta_hash);
+    let mut storage = tree.into_storage().ok().unwrap();
+    let size = storage.size(&root_id)?;
+    let root = utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&root_id)?, size)?;
+    let mut forged = old_root.clone();
+    forged[16..24].copy_from_slice(&meta_id.to_le_bytes());
+    forged[24..56].copy_from_slice(&meta_hash);
+    assert!(storage.replace_handle(&root_id)?.write_all(&forged).is_ok());
+    storage.commit_handle(&root_id)?;
+    assert!(matches!(
+        BKeyTree::<ThreadRng, MemoryStorage>::load_root(root_id, root_id, key, &mut storage),
+        Err(Error::MacMismatch(id)) if id == root_id
+    ));
+
+    // Left with the current header, it doesn't match the hash the metadata holds for the root.
+    let header_len = RootHeader::SIZE as usize;
+    let forged = [&root[..header_len], &old_root[header_len..]].concat();
+    assert!(storage.replace_handle(&root_id)?.write_all(&forged).is_ok());
+    storage.commit_handle(&root_id)?;
+    let (old, header) =
+        BKeyTree::<ThreadRng, MemoryStorage>::load_root(root_id, root_id, key, &mut storage)?;
+    assert!(matches!(
+        BKeyTree::<ThreadRng, MemoryStorage>::load_meta(&header, root_id, &old, key, &mut storage),
+        Err(Error::HashMismatch(id)) if id == root_id
+    ));
+    assert!(storage.replace_handle(&root_id)?.write_all(&root).is_ok());
+    storage.commit_handle(&root_id)?;
+
+    // Nor does replayed metadata get past the hash the root's header holds for it.
+    assert!(storage
+        .replace_handle(&meta_id)?
+        .write_all(&old_meta)
+        .is_ok());
+    storage.commit_handle(&meta_id)?;
+    assert!(matches!(
+        BKeyTree::<ThreadRng, MemoryStorage>::reload_with_storage(root_id, storage, key),
+        Err(Error::HashMismatch(id)) if id == meta_id
+    ));
+
+    Ok(())
+}
+
+#[test]
+fn proofs() -> Result<()> {
+    let mut rng = StdRng::seed_from_u64(0);
+    let key = utils::generate_key(&mut rng);
+    let mut map = HashMap::new();
+
+    let mut tree: BKeyTree<ThreadRng, MemoryStorage> =
+        BKeyTree::with_storage(MemoryStorage::new())?;
+    for block in 0..100 {
+        let val = utils::generate_key(&mut rng);
+        map.insert(block, val);
+        tree.insert(block, val)?;
+    }
+    assert!(tree.prove(&0)?.is_none());
+    tree.persist(key)?;
+    let hash = tree.root_hash().unwrap();
+
+    for block in [0, 42, 99] {
+        let proof = tree.prove(&block)?.unwrap();
+        assert_eq!(
+            proof.verify(&hash),
+            Some(Membership::Present(sha256(&map[&block])))
+        );
+    }
+    assert_eq!(
+        tree.prove(&100)?.unwrap().verify(&hash),
+        Some(Membership::Absent)
+    );
+
+    // Proofs survive being sent along.
+    let proof = tree.prove(&42)?.unwrap();
+    let raw = proof.encode::<std::io::Error>()?;
+    assert_eq!(Proof::decode::<std::io::Error>(&raw)?, proof);
+
+    // A proof gives away the block IDs and hashes the root holds, but since each of its fields
+    // is encrypted under a key of its own, what they give away of their keystreams doesn't
+    // decrypt the keys it holds.
+    let root_id = tree.root_id();
+    let raw = {
+        let mut storage = tree.storage.lock();
+        let size = storage.size(&root_id)?;
+        utils::read_bytes::<MemoryStorage>(&mut storage.read_handle(&root_id)?, size)?
+    };
+    let mut fields = &raw[RootHeader::SIZE as usize..];
+    let [keys, vals, _, _, hashes] = [(); 5]
+        .map(|_| utils::take_length_prefixed_bytes_clear::<std::io::Error>(&mut fields).unwrap());
+    let known = [
+        utils::serialize_ids(&proof.path[0].keys),
+        utils::serialize_keys(&proof.path[0].children_hashes),
+    ];
+    let vals_raw = utils::serialize_keys(&tree.root.vals);
+    for (ciphertext, plaintext) in [keys, hashes].into_iter().zip(known) {
+        let guessed = ciphertext
+            .iter()
+            .zip(&plaintext)
+            .zip(vals)
+            .map(|((c, p), v)| c ^ p ^ v)
+            .collect::<Vec<_>>();
+        assert!(!guessed.is_empty());
+        assert_ne!(guessed, vals_raw[..guessed.len()]);
+    }
+
+    // A removed block stays proven present until the tree's persisted.
+    tree.remove(&42)?;
+    assert_eq!(tree.prove(&42)?.unwrap().verify(&hash), proof.verify(&hash));
+    tree.persist(key)?;
+    let new_hash = tree.root_hash().unwrap();
+    assert_eq!(
+        tree.prove(&42)?.unwrap().verify(&new_hash),
+        Some(Membership::Absent)
+    );
+
+    // The old proof doesn't check out against the new root, nor does a doctored one, whether
+    // it's the proven key's hash, a block or child hash on the path, the epoch or the metadata's
+    // hash that's been changed, or the path that's been cut short.
+    assert_eq!(proof.verify(&new_hash), None);
+    let last = proof.path.len() - 1;
+    let idx = proof.path[last].keys.binary_search(&42).unwrap();
+    let mut doctored = vec![proof.clone(); 6];
+    doctored[0].path[last].val_hashes[idx] = sha256(b"forged");
+    doctored[1].path[0].keys[0] += 1;
+    doctored[2].epoch += 1;
+    doctored[3].meta_hash[0] ^= 1;
+    doctored[4].path[0].children_hashes[0][0] ^= 1;
+    doctored[5].path.pop();
+    for doctored in doctored {
+        assert_eq!(doctored.verify(&hash), None);
+    }
+
+    // Nor does a proof for a block it wasn't made for.
+    let mut other = tree.prove(&0)?.unwrap();
+    other.block = 99;
+    assert_eq!(other.verify(&new_hash), None);
+
+    // The tree won't prove anything about a block whose key may live on outside its nodes.
+    tree.derive(100)?;
+    assert!(matches!(tree.prove(&100), Err(Error::InFlight(100))));
+    let fork = tree.fork(key)?;
+    assert!(matches!(fork.prove(&0), Err(Error::Forked)));
+    assert!(matches!(tree.prove(&0), Err(Error::Forked)));
+    tree.set_kept_generations(1);
+    tree.persist(key)?;
+    assert!(matches!(tree.prove(&0), Err(Error::KeepsGenerations)));
+
+    Ok(())
+}
+
+/// Runs the async tree against a model, then checks that the blocking tree reloads the same
+/// entries from what it persisted.
+#[cfg(feature = "async")]
+async fn check_async(path: &'static str) -> Result<()> {
+    use asynch::AsyncBKeyTree;
+
+    let counter = format!("{path}-counter");
+    let _ = fs::remove_file(&counter);
+
+    let mut rng = StdRng::seed_from_u64(42);
+    let mut model = BTreeMap::new();
+    let mut tree = AsyncBKeyTree::with_degree(path, 3).await?;
+
+    for _ in 0..2000 {
+        let block = rng.gen_range(0..500);
+        if rng.gen_bool(0.6) {
+            // Inserting a block that's already there keeps its key.
+            let key = utils::generate_key(&mut rng);
+            let expected = model.get(&block).copied();
+            model.entry(block).or_insert(key);
+            assert_eq!(tree.insert(block, key).await?, expected);
+        } else {
+            assert_eq!(tree.remove(&block).await?, model.remove(&block));
+        }
+        assert_eq!(tree.len(), model.len());
+    }
+
+    let key = utils::generate_key(&mut rng);
+    tree.persist(key).await?;
+    let root_id = tree.root_id();
+    drop(tree);
+
+    // The async tree checks and advances a trusted counter just like the blocking one.
+    let mut tree =
+        AsyncBKeyTree::reload_with_counter(root_id, path, key, FileCounter::new(&counter)).await?;
+    for (block, key) in &model {
+        assert_eq!(tree.get(block).await?, Some(key));
+    }
+
+    // Derive a key for a new block and rotate the key of an existing one.
+    let (&updated, &old_key) = model.iter().next().unwrap();
+    let derived = tree.derive(500).await?;
+    tree.update(updated).await?;
+    assert_eq!(tree.commit().await?, vec![updated]);
+
+    let new_key = *tree.get(&updated).await?.unwrap();
+    assert_ne!(new_key, old_key);
+    assert_eq!(tree.get(&500).await?, Some(&derived));
+    model.insert(updated, new_key);
+    model.insert(500, derived);
+
+    let key = utils::generate_key(&mut rng);
+    tree.persist(key).await?;
+    let root_id = tree.root_id();
+    drop(tree);
+
+    assert_eq!(FileCounter::new(&counter).read()?, 2);
+
+    let mut tree = BKeyTree::reload_with_counter(root_id, path, key, FileCounter::new(&counter))?;
+    tree.verify()?;
+    assert_eq!(tree.entries()?, model.into_iter().collect::<Vec<_>>());
+    assert_eq!(tree.orphans()?, vec![]);
+
+    let _ = fs::remove_file(&counter);
+
+    Ok(())
+}
+
+#[cfg(feature = "async")]
+#[test]
+fn asynchronous() -> Result<()> {
+    let path = "/tmp/bkeytreedir-async";
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()?;
+
+    // Spawning the check makes sure the tree's futures can move between threads.
+    let res = rt.block_on(async { tokio::spawn(check_async(path)).await? });
+
+    let _ = fs::remove_dir_all(path);
+
+    res
+}
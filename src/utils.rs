@@ -1,8 +1,13 @@
-use crate::{error::Error, Key};
+use crate::{error::Error, Hash, Key};
 use crypter::Crypter;
 use embedded_io::blocking::{Read, Write};
+use openssl::{hash::MessageDigest, memcmp, pkey::PKey, sign::Signer};
 use rand::{CryptoRng, RngCore};
-use std::{collections::HashMap, mem};
+use std::{
+    collections::{BTreeMap, HashMap},
+    hash, mem,
+    sync::{Mutex, MutexGuard, PoisonError},
+};
 use storage::Storage;
 
 pub fn generate_key<R, const KEY_SZ: usize>(rng: &mut R) -> Key<KEY_SZ>
@@ -23,19 +28,14 @@ pub fn serialize_ids(ids: &[u64]) -> Vec<u8> {
     ser
 }
 
-pub fn deserialize_ids(ids_raw: &[u8]) -> Vec<u64> {
-    let mut ids = vec![];
+pub fn deserialize_ids<E>(ids_raw: &[u8]) -> Result<Vec<u64>, Error<E>> {
+    let (len, rest) = split_len::<E>(ids_raw, mem::size_of::<u64>())?;
 
-    let len = u64::from_le_bytes(ids_raw[..mem::size_of::<u64>()].try_into().unwrap());
-
-    for i in 1..=len {
-        let start = i as usize * mem::size_of::<u64>();
-        let end = start + mem::size_of::<u64>();
-        let id = u64::from_le_bytes(ids_raw[start..end].try_into().unwrap());
-        ids.push(id);
-    }
-
-    ids
+    Ok(rest
+        .chunks_exact(mem::size_of::<u64>())
+        .take(len)
+        .map(u64_from_le_slice)
+        .collect())
 }
 
 pub fn serialize_keys<const KEY_SZ: usize>(keys: &[Key<KEY_SZ>]) -> Vec<u8> {
@@ -47,19 +47,16 @@ pub fn serialize_keys<const KEY_SZ: usize>(keys: &[Key<KEY_SZ>]) -> Vec<u8> {
     ser
 }
 
-pub fn deserialize_keys<const KEY_SZ: usize>(keys_raw: &[u8]) -> Vec<Key<KEY_SZ>> {
-    let mut keys = vec![];
-
-    let len = u64::from_le_bytes(keys_raw[..mem::size_of::<u64>()].try_into().unwrap());
-
-    for i in 0..len {
-        let start = i as usize * KEY_SZ + mem::size_of::<u64>();
-        let end = start + KEY_SZ;
-        let key = keys_raw[start..end].try_into().unwrap();
-        keys.push(key);
-    }
+pub fn deserialize_keys<E, const KEY_SZ: usize>(
+    keys_raw: &[u8],
+) -> Result<Vec<Key<KEY_SZ>>, Error<E>> {
+    let (len, rest) = split_len::<E>(keys_raw, KEY_SZ)?;
 
-    keys
+    Ok(rest
+        .chunks_exact(KEY_SZ)
+        .take(len)
+        .map(key_from_slice)
+        .collect())
 }
 
 pub fn serialize_keys_map<const KEY_SZ: usize>(keys: &HashMap<u64, Key<KEY_SZ>>) -> Vec<u8> {
@@ -75,26 +72,48 @@ pub fn serialize_keys_map<const KEY_SZ: usize>(keys: &HashMap<u64, Key<KEY_SZ>>)
     ser
 }
 
-pub fn deserialize_keys_map<const KEY_SZ: usize>(keys_raw: &[u8]) -> HashMap<u64, Key<KEY_SZ>> {
-    let mut keys = HashMap::new();
-
-    let len = u64::from_le_bytes(keys_raw[..mem::size_of::<u64>()].try_into().unwrap());
+pub fn deserialize_keys_map<E, const KEY_SZ: usize>(
+    keys_raw: &[u8],
+) -> Result<HashMap<u64, Key<KEY_SZ>>, Error<E>> {
     let entry_size = mem::size_of::<u64>() + KEY_SZ;
+    let (len, rest) = split_len::<E>(keys_raw, entry_size)?;
+
+    Ok(rest
+        .chunks_exact(entry_size)
+        .take(len)
+        .map(|entry| {
+            let (block, key) = entry.split_at(mem::size_of::<u64>());
+            (u64_from_le_slice(block), key_from_slice(key))
+        })
+        .collect())
+}
 
-    for i in 0..len as usize {
-        let block_start = i * entry_size + mem::size_of::<u64>();
-        let block_end = block_start + mem::size_of::<u64>();
-
-        let key_start = block_end;
-        let key_end = key_start + KEY_SZ;
+/// Splits the length prefix off of a serialized array, checking that exactly that many
+/// `entry_size`-byte entries follow it.
+fn split_len<E>(raw: &[u8], entry_size: usize) -> Result<(usize, &[u8]), Error<E>> {
+    if raw.len() < mem::size_of::<u64>() {
+        return Err(Error::Deserialization);
+    }
 
-        let block = u64::from_le_bytes(keys_raw[block_start..block_end].try_into().unwrap());
-        let key = keys_raw[key_start..key_end].try_into().unwrap();
+    let (len, rest) = raw.split_at(mem::size_of::<u64>());
+    let len = usize::try_from(u64_from_le_slice(len)).map_err(|_| Error::Deserialization)?;
 
-        keys.insert(block, key);
+    match len.checked_mul(entry_size) {
+        Some(size) if size == rest.len() => Ok((len, rest)),
+        _ => Err(Error::Deserialization),
     }
+}
+
+fn u64_from_le_slice(raw: &[u8]) -> u64 {
+    let mut bytes = [0; mem::size_of::<u64>()];
+    bytes.copy_from_slice(raw);
+    u64::from_le_bytes(bytes)
+}
 
-    keys
+fn key_from_slice<const KEY_SZ: usize>(raw: &[u8]) -> Key<KEY_SZ> {
+    let mut key = [0; KEY_SZ];
+    key.copy_from_slice(raw);
+    key
 }
 
 pub fn read_u64<S>(reader: &mut S::ReadHandle<'_>) -> Result<u64, Error<S::Error>>
@@ -115,57 +134,220 @@ where
     Ok(())
 }
 
-pub fn read_length_prefixed_bytes_clear<S>(
+pub fn read_length_prefixed_bytes<C, S, const KEY_SZ: usize>(
     reader: &mut S::ReadHandle<'_>,
+    key: Key<KEY_SZ>,
 ) -> Result<Vec<u8>, Error<S::Error>>
 where
+    C: Crypter,
     S: Storage,
 {
     let len = read_u64::<S>(reader)?;
-    let mut bytes = vec![0; len as usize];
-    reader.read_exact(&mut bytes).map_err(|_| Error::Read)?;
-    Ok(bytes)
+    let bytes = read_bytes::<S>(reader, len)?;
+    C::onetime_decrypt(&key, &bytes).map_err(|_| Error::Decrypt)
 }
 
-pub fn read_length_prefixed_bytes<C, S, const KEY_SZ: usize>(
-    reader: &mut S::ReadHandle<'_>,
-    key: Key<KEY_SZ>,
-) -> Result<Vec<u8>, Error<S::Error>>
+/// Reads `len` bytes, growing the buffer as the bytes actually arrive. The length comes from
+/// storage, so a corrupted one must not be able to trigger a huge allocation up front.
+pub fn read_bytes<S>(reader: &mut S::ReadHandle<'_>, len: u64) -> Result<Vec<u8>, Error<S::Error>>
 where
-    C: Crypter,
     S: Storage,
 {
-    let len = read_u64::<S>(reader)?;
-    let mut bytes = vec![0; len as usize];
-    reader.read_exact(&mut bytes).map_err(|_| Error::Read)?;
-    Ok(C::onetime_decrypt(&key, &bytes).map_err(|_| ()).unwrap())
+    const CHUNK_SZ: usize = 4096;
+
+    let len = usize::try_from(len).map_err(|_| Error::Deserialization)?;
+    let mut bytes = Vec::with_capacity(len.min(CHUNK_SZ));
+    let mut chunk = [0; CHUNK_SZ];
+
+    while bytes.len() < len {
+        let n = (len - bytes.len()).min(CHUNK_SZ);
+        reader
+            .read_exact(&mut chunk[..n])
+            .map_err(|_| Error::Read)?;
+        bytes.extend_from_slice(&chunk[..n]);
+    }
+
+    Ok(bytes)
 }
 
-pub fn write_length_prefixed_bytes_clear<S>(
-    writer: &mut S::WriteHandle<'_>,
-    bytes: &[u8],
-) -> Result<(), Error<S::Error>>
+/// Splits a little-endian `u64` off the front of `raw`.
+pub fn take_u64<E>(raw: &mut &[u8]) -> Result<u64, Error<E>> {
+    Ok(u64_from_le_slice(take_bytes(
+        raw,
+        mem::size_of::<u64>() as u64,
+    )?))
+}
+
+/// Splits a hash off the front of `raw`.
+pub fn take_hash<E>(raw: &mut &[u8]) -> Result<Hash, Error<E>> {
+    let mut hash = Hash::default();
+    hash.copy_from_slice(take_bytes(raw, mem::size_of::<Hash>() as u64)?);
+    Ok(hash)
+}
+
+/// Splits `len` bytes off the front of `raw`.
+fn take_bytes<'a, E>(raw: &mut &'a [u8], len: u64) -> Result<&'a [u8], Error<E>> {
+    let len = usize::try_from(len)
+        .ok()
+        .filter(|len| *len <= raw.len())
+        .ok_or(Error::Deserialization)?;
+
+    let (bytes, rest) = raw.split_at(len);
+    *raw = rest;
+    Ok(bytes)
+}
+
+pub fn take_length_prefixed_bytes_clear<'a, E>(raw: &mut &'a [u8]) -> Result<&'a [u8], Error<E>> {
+    let len = take_u64(raw)?;
+    take_bytes(raw, len)
+}
+
+pub fn take_length_prefixed_bytes<C, E, const KEY_SZ: usize>(
+    raw: &mut &[u8],
+    key: Key<KEY_SZ>,
+) -> Result<Vec<u8>, Error<E>>
 where
-    S: Storage,
+    C: Crypter,
 {
-    write_u64::<S>(writer, bytes.len() as u64)?;
-    Ok(writer.write_all(&bytes).map_err(|_| Error::Write)?)
+    let bytes = take_length_prefixed_bytes_clear(raw)?;
+    C::onetime_decrypt(&key, bytes).map_err(|_| Error::Decrypt)
+}
+
+pub fn push_length_prefixed_bytes_clear(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend((bytes.len() as u64).to_le_bytes());
+    buf.extend(bytes);
 }
 
-pub fn write_length_prefixed_bytes<C, S, const KEY_SZ: usize>(
-    writer: &mut S::WriteHandle<'_>,
+pub fn push_length_prefixed_bytes<C, E, const KEY_SZ: usize>(
+    buf: &mut Vec<u8>,
     bytes: &[u8],
     key: Key<KEY_SZ>,
-) -> Result<(), Error<S::Error>>
+) -> Result<(), Error<E>>
 where
     C: Crypter,
-    S: Storage,
 {
-    writer
-        .write_all(&(bytes.len() as u64).to_le_bytes())
-        .map_err(|_| Error::Write)?;
+    buf.extend((bytes.len() as u64).to_le_bytes());
+    buf.extend(C::onetime_encrypt(&key, bytes).map_err(|_| Error::Encrypt)?);
+    Ok(())
+}
+
+/// Returns the HMAC-SHA256 of `parts`, one after the other, under `key`.
+pub fn mac<E>(key: &[u8], parts: &[&[u8]]) -> Result<Hash, Error<E>> {
+    let key = PKey::hmac(key).map_err(|_| Error::Encrypt)?;
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).map_err(|_| Error::Encrypt)?;
+    for part in parts {
+        signer.update(part).map_err(|_| Error::Encrypt)?;
+    }
 
-    let bytes = C::onetime_encrypt(&key, bytes).map_err(|_| ()).unwrap();
+    let mut mac = [0; 32];
+    signer.sign(&mut mac).map_err(|_| Error::Encrypt)?;
+    Ok(mac)
+}
+
+/// Derives a key for `purpose` from `key`, so that no key is used for more than one thing.
+pub fn derive_key<E, const KEY_SZ: usize>(
+    key: &[u8],
+    purpose: &[u8],
+) -> Result<Key<KEY_SZ>, Error<E>> {
+    let mut derived = [0; KEY_SZ];
+    for (i, chunk) in derived.chunks_mut(mem::size_of::<Hash>()).enumerate() {
+        let block = mac(key, &[purpose, &(i as u64).to_le_bytes()])?;
+        chunk.copy_from_slice(&block[..chunk.len()]);
+    }
+    Ok(derived)
+}
+
+/// Returns whether `expected` is the HMAC-SHA256 of `parts` under `key`, without leaking where
+/// they differ through timing.
+pub fn verify_mac<E>(key: &[u8], parts: &[&[u8]], expected: &Hash) -> Result<bool, Error<E>> {
+    Ok(memcmp::eq(&mac(key, parts)?, expected))
+}
+
+/// A mutex that ignores poisoning. What it guards is only ever left half-updated by a panic in
+/// code that would have left it the same way without the mutex.
+#[derive(Default)]
+pub struct Locked<T>(Mutex<T>);
 
-    Ok(writer.write_all(&bytes).map_err(|_| Error::Write)?)
+impl<T> Locked<T> {
+    pub fn new(val: T) -> Self {
+        Self(Mutex::new(val))
+    }
+
+    pub fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.0.get_mut().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.0.into_inner().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// A map that holds up to a fixed number of entries, dropping the least recently used one to
+/// make room for another.
+pub struct Lru<K, V> {
+    capacity: usize,
+    entries: HashMap<K, (V, u64)>,
+    // Keys by when they were last used, least recently used first.
+    order: BTreeMap<u64, K>,
+    tick: u64,
+}
+
+impl<K: Clone + Eq + hash::Hash, V: Clone> Lru<K, V> {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            tick: 0,
+        }
+    }
+
+    /// Returns a copy of the value for `key`, marking it as just used.
+    pub fn get(&mut self, key: &K) -> Option<V> {
+        let (val, used) = self.entries.get_mut(key)?;
+        self.order.remove(used);
+        *used = self.tick;
+        self.order.insert(self.tick, key.clone());
+        self.tick += 1;
+        Some(val.clone())
+    }
+
+    pub fn insert(&mut self, key: K, val: V) {
+        if let Some((_, used)) = self.entries.remove(&key) {
+            self.order.remove(&used);
+        }
+        self.shrink(self.capacity.saturating_sub(1));
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.order.insert(self.tick, key.clone());
+        self.entries.insert(key, (val, self.tick));
+        self.tick += 1;
+    }
+
+    /// Changes how many entries are held, dropping the least recently used ones if there are
+    /// too many.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        self.shrink(capacity);
+    }
+
+    pub fn clear(&mut self) {
+        self.entries.clear();
+        self.order.clear();
+    }
+
+    fn shrink(&mut self, len: usize) {
+        while self.entries.len() > len {
+            let Some((_, key)) = self.order.pop_first() else {
+                break;
+            };
+            self.entries.remove(&key);
+        }
+    }
 }
@@ -0,0 +1,91 @@
+//! Asynchronous counterpart of [`Storage`](crate::Storage), for use from async runtimes.
+
+#[cfg(feature = "dir")]
+pub mod dir;
+
+use std::{error::Error, future::Future};
+use tokio::io::{AsyncRead, AsyncSeek, AsyncWrite};
+
+/// Like [`Storage`](crate::Storage), but every operation is awaited rather than blocking the
+/// calling thread. See there for what each operation does.
+pub trait AsyncStorage: Send {
+    /// Type for an object identifier.
+    type Id: PartialEq + Send + Sync;
+
+    /// Type for storage errors.
+    type Error: Error + Send;
+
+    /// Type of handle to read data with.
+    type ReadHandle<'a>: AsyncRead + AsyncSeek + Unpin + Send
+    where
+        Self: 'a;
+
+    /// Type of handle to write data with.
+    type WriteHandle<'a>: AsyncWrite + AsyncSeek + Unpin + Send
+    where
+        Self: 'a;
+
+    /// Type of handle to read and write data with.
+    type RwHandle<'a>: AsyncRead + AsyncWrite + AsyncSeek + Unpin + Send
+    where
+        Self: 'a;
+
+    /// Allocates an object `id`.
+    fn alloc_id(&mut self) -> impl Future<Output = Result<Self::Id, Self::Error>> + Send;
+
+    /// Deallocates an object `id`.
+    fn dealloc_id(&mut self, id: Self::Id) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Removes object `id` from storage. Its ID stays allocated until it's deallocated.
+    fn remove_id(&mut self, id: &Self::Id) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Truncates an object `id` to `size` bytes.
+    fn truncate_id(
+        &mut self,
+        id: &Self::Id,
+        size: u64,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Returns a handle to read data from object `id`.
+    fn read_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> impl Future<Output = Result<Self::ReadHandle<'_>, Self::Error>> + Send;
+
+    /// Returns a handle to write data to object `id` in place.
+    fn write_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> impl Future<Output = Result<Self::WriteHandle<'_>, Self::Error>> + Send;
+
+    /// Returns a handle to write the new contents of object `id`, which only take effect once
+    /// [`AsyncStorage::commit_handle`] is called.
+    fn replace_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> impl Future<Output = Result<Self::WriteHandle<'_>, Self::Error>> + Send;
+
+    /// Atomically swaps in the contents written through the last replace handle for object `id`.
+    fn commit_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Returns a handle to read from/write to object `id`.
+    fn rw_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> impl Future<Output = Result<Self::RwHandle<'_>, Self::Error>> + Send;
+
+    /// Returns the IDs of every object in storage.
+    fn ids(&self) -> impl Future<Output = Result<Vec<Self::Id>, Self::Error>> + Send;
+
+    /// Returns the size of object `id` in bytes.
+    fn size(&self, id: &Self::Id) -> impl Future<Output = Result<u64, Self::Error>> + Send;
+
+    /// Forces every write made so far to stable storage.
+    fn sync(&mut self) -> impl Future<Output = Result<(), Self::Error>> + Send;
+
+    /// Forces every write made so far to object `id` to stable storage.
+    fn sync_id(&mut self, id: &Self::Id) -> impl Future<Output = Result<(), Self::Error>> + Send;
+}
@@ -0,0 +1,233 @@
+use super::AsyncStorage;
+use crate::{
+    dir::{self, DirectoryStorage, Error, Layout},
+    Storage,
+};
+use std::io;
+use tokio::fs::{self, DirBuilder, File, OpenOptions};
+
+/// [`DirectoryStorage`], but with file I/O done through tokio. It uses the same on-disk format,
+/// layout and lock, so either can open a directory the other wrote.
+///
+/// Opening the directory blocks, since it only happens once; everything after that is async and
+/// must run within a tokio runtime.
+pub struct AsyncDirectoryStorage {
+    inner: DirectoryStorage,
+}
+
+impl AsyncDirectoryStorage {
+    /// See [`DirectoryStorage::new`].
+    pub fn new(root: &str) -> Result<Self, Error> {
+        Ok(DirectoryStorage::new(root)?.into())
+    }
+
+    /// See [`DirectoryStorage::with_layout`].
+    pub fn with_layout(root: &str, layout: Layout) -> Result<Self, Error> {
+        Ok(DirectoryStorage::with_layout(root, layout)?.into())
+    }
+
+    /// See [`DirectoryStorage::open_read_only`].
+    pub fn open_read_only(root: &str) -> Result<Self, Error> {
+        Ok(DirectoryStorage::open_read_only(root)?.into())
+    }
+
+    pub fn layout(&self) -> Layout {
+        self.inner.layout()
+    }
+
+    /// Creates any missing directories leading to object `id`, making each new one durable.
+    /// Those already there are checked, since they may have turned up since the storage was
+    /// opened.
+    async fn create_dirs(&self, id: u64) -> Result<(), Error> {
+        for pair in self.inner.dirs(id).windows(2) {
+            match fs::metadata(&pair[1]).await {
+                Ok(metadata) => dir::check_permissions(&pair[1], &metadata)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    let mut builder = DirBuilder::new();
+                    #[cfg(unix)]
+                    builder.mode(dir::DIR_MODE);
+                    builder.create(&pair[1]).await?;
+                    File::open(&pair[0]).await?.sync_all().await?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl From<DirectoryStorage> for AsyncDirectoryStorage {
+    fn from(inner: DirectoryStorage) -> Self {
+        Self { inner }
+    }
+}
+
+/// Returns options that create files readable and writable only by their owner.
+fn file_options() -> OpenOptions {
+    OpenOptions::from(dir::file_options())
+}
+
+impl AsyncStorage for AsyncDirectoryStorage {
+    type Id = u64;
+    type Error = Error;
+    type ReadHandle<'a> = File;
+    type WriteHandle<'a> = File;
+    type RwHandle<'a> = File;
+
+    async fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
+        self.inner.check_writable()?;
+
+        // The allocator starts fresh every time the directory is opened, so skip over any IDs
+        // that already name an object on disk.
+        loop {
+            let id = self.inner.next_id()?;
+            if !fs::try_exists(self.inner.canonicalize(id)).await? {
+                return Ok(id);
+            }
+        }
+    }
+
+    async fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        // Deallocating doesn't touch the disk, so there's nothing to wait for.
+        self.inner.dealloc_id(id)
+    }
+
+    async fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.inner.check_writable()?;
+        self.inner.mark_clean(*id);
+        Ok(fs::remove_file(self.inner.canonicalize(*id)).await?)
+    }
+
+    async fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
+        self.inner.check_writable()?;
+        self.create_dirs(*id).await?;
+        self.inner.mark_dirty(*id);
+        Ok(file_options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.inner.canonicalize(*id))
+            .await?
+            .set_len(size)
+            .await?)
+    }
+
+    async fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error> {
+        Ok(File::open(self.inner.canonicalize(*id)).await?)
+    }
+
+    async fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        self.inner.check_writable()?;
+        self.create_dirs(*id).await?;
+        self.inner.mark_dirty(*id);
+        Ok(file_options()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.inner.canonicalize(*id))
+            .await?)
+    }
+
+    async fn replace_handle(
+        &mut self,
+        id: &Self::Id,
+    ) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        self.inner.check_writable()?;
+        self.create_dirs(*id).await?;
+        Ok(file_options()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(self.inner.canonicalize_tmp(*id))
+            .await?)
+    }
+
+    async fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.inner.check_writable()?;
+
+        // Make sure the new contents are durable before they replace the old ones, and that the
+        // rename itself is durable before returning.
+        File::open(self.inner.canonicalize_tmp(*id))
+            .await?
+            .sync_all()
+            .await?;
+        fs::rename(
+            self.inner.canonicalize_tmp(*id),
+            self.inner.canonicalize(*id),
+        )
+        .await?;
+        File::open(self.inner.dirs(*id).last().unwrap())
+            .await?
+            .sync_all()
+            .await?;
+        Ok(())
+    }
+
+    async fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error> {
+        self.inner.check_writable()?;
+        self.create_dirs(*id).await?;
+        self.inner.mark_dirty(*id);
+        Ok(file_options()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.inner.canonicalize(*id))
+            .await?)
+    }
+
+    async fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        let mut ids = vec![];
+        let mut pending = vec![(self.inner.root().to_string(), self.inner.levels())];
+
+        while let Some((dir, levels)) = pending.pop() {
+            let mut entries = fs::read_dir(&dir).await?;
+            while let Some(entry) = entries.next_entry().await? {
+                let Some(name) = entry.file_name().to_str().map(String::from) else {
+                    continue;
+                };
+
+                if levels == 0 {
+                    ids.extend(dir::object_id(&name));
+                } else if dir::is_fan_out_dir(&name) && entry.file_type().await?.is_dir() {
+                    pending.push((format!("{dir}/{name}"), levels - 1));
+                }
+            }
+        }
+
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    async fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        Ok(fs::metadata(self.inner.canonicalize(*id)).await?.len())
+    }
+
+    async fn sync(&mut self) -> Result<(), Self::Error> {
+        let (ids, dirs) = self.inner.pending_sync();
+        for id in ids {
+            self.sync_id(&id).await?;
+        }
+        for dir in dirs {
+            File::open(dir).await?.sync_all().await?;
+        }
+        Ok(())
+    }
+
+    async fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        if self.inner.is_dirty(*id) {
+            File::open(self.inner.canonicalize(*id))
+                .await?
+                .sync_all()
+                .await?;
+            self.inner.mark_clean(*id);
+        }
+        Ok(())
+    }
+}
+
+impl From<AsyncDirectoryStorage> for DirectoryStorage {
+    fn from(storage: AsyncDirectoryStorage) -> Self {
+        storage.inner
+    }
+}
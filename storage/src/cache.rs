@@ -0,0 +1,403 @@
+pub use crate::mem::Handle;
+
+use crate::Storage;
+use embedded_io::blocking::{Read, Write};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    hash::Hash,
+};
+use thiserror::Error;
+
+/// Storage that keeps recently used objects in memory and holds back writes to them until they're
+/// synced or evicted.
+///
+/// Dirty objects are written back whole, through the inner storage's replace handles, so a crash
+/// leaves each object with either the contents it was last written back with or the ones before.
+/// Allocations go straight through, and so do removals and deallocations unless syncs are
+/// deferred.
+///
+/// A cache made with [`CachedStorage::deferred`] holds back syncs too, so that persisting a tree
+/// over and over only costs I/O once the cache fills up or is flushed. See there for what that
+/// does to durability.
+pub struct CachedStorage<S: Storage> {
+    inner: S,
+    capacity: usize,
+    objects: HashMap<S::Id, Entry>,
+    staged: HashMap<S::Id, Vec<u8>>,
+    // Cached objects by when they were last used, least recently used first.
+    lru: BTreeMap<u64, S::Id>,
+    tick: u64,
+    // Whether syncs are held back until the next flush, along with the removals and
+    // deallocations that have to wait for one.
+    deferred: bool,
+    removed: HashSet<S::Id>,
+    freed: Vec<S::Id>,
+}
+
+struct Entry {
+    data: Vec<u8>,
+    dirty: bool,
+    // Whether the inner storage has the object at all.
+    stored: bool,
+    used: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("I/O error while moving an object through the cache")]
+    Io,
+
+    #[error("no replacement staged for object")]
+    NotStaged,
+
+    #[error("no such object")]
+    NotFound,
+
+    #[error(transparent)]
+    Inner(E),
+}
+
+impl<S> CachedStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    /// Caches up to `capacity` objects (at least one) in front of `inner`.
+    pub fn new(inner: S, capacity: usize) -> Self {
+        Self {
+            inner,
+            capacity: capacity.max(1),
+            objects: HashMap::new(),
+            staged: HashMap::new(),
+            lru: BTreeMap::new(),
+            tick: 0,
+            deferred: false,
+            removed: HashSet::new(),
+            freed: vec![],
+        }
+    }
+
+    /// Like `new()`, but syncs only take effect once the cache is flushed, which happens when a
+    /// dirty object has to be evicted, on [`CachedStorage::flush`] and on
+    /// [`CachedStorage::into_inner`]. Removals and deallocations wait for the next flush as well.
+    ///
+    /// Dirty objects are written back in an order that keeps copy-on-write structures like a
+    /// tree intact: objects the inner storage doesn't have yet first, then the ones they replace,
+    /// and the removals last, with a sync in between each. A crash loses whatever wasn't flushed,
+    /// leaving the inner storage as it was after the last flush. That includes persists the
+    /// tree took to be durable, so a tree refuses to keep a trusted counter over such a cache.
+    pub fn deferred(inner: S, capacity: usize) -> Self {
+        Self {
+            deferred: true,
+            ..Self::new(inner, capacity)
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    /// Writes back every dirty object and returns the inner storage.
+    pub fn into_inner(mut self) -> Result<S, Error<S::Error>> {
+        self.flush()?;
+        Ok(self.inner)
+    }
+
+    /// Writes back every dirty object, objects the inner storage doesn't have yet first. Unless
+    /// syncs are deferred, the inner storage isn't synced; if they are, it's synced between the
+    /// new and the replaced objects and after them, and then the held back removals and
+    /// deallocations go through.
+    pub fn flush(&mut self) -> Result<(), Error<S::Error>> {
+        let mut dirty: Vec<_> = self
+            .objects
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(id, entry)| (entry.stored, id.clone()))
+            .collect();
+        dirty.sort_by_key(|(stored, _)| *stored);
+        let fresh = dirty.iter().take_while(|(stored, _)| !stored).count();
+
+        for (i, (_, id)) in dirty.iter().enumerate() {
+            if self.deferred && i == fresh {
+                self.inner.sync().map_err(Error::Inner)?;
+            }
+            self.write_back(id)?;
+        }
+
+        if self.deferred {
+            self.inner.sync().map_err(Error::Inner)?;
+
+            // Nothing the inner storage holds can refer to these anymore. Each is only forgotten
+            // once it's gone through, so that a failure leaves the rest to the next flush.
+            for id in self.removed.clone() {
+                self.inner.remove_id(&id).map_err(Error::Inner)?;
+                self.removed.remove(&id);
+            }
+            while let Some(id) = self.freed.last() {
+                self.inner.dealloc_id(id.clone()).map_err(Error::Inner)?;
+                self.freed.pop();
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Writes back object `id` if it's dirty.
+    fn write_back(&mut self, id: &S::Id) -> Result<(), Error<S::Error>> {
+        let Some(entry) = self.objects.get_mut(id) else {
+            return Ok(());
+        };
+        if !entry.dirty {
+            return Ok(());
+        }
+
+        self.inner
+            .replace_handle(id)
+            .map_err(Error::Inner)?
+            .write_all(&entry.data)
+            .map_err(|_| Error::Io)?;
+        self.inner.commit_handle(id).map_err(Error::Inner)?;
+
+        entry.dirty = false;
+        entry.stored = true;
+
+        Ok(())
+    }
+
+    /// Marks object `id` as just used.
+    fn touch(&mut self, id: &S::Id) {
+        if let Some(entry) = self.objects.get_mut(id) {
+            self.lru.remove(&entry.used);
+            entry.used = self.tick;
+            self.lru.insert(self.tick, id.clone());
+            self.tick += 1;
+        }
+    }
+
+    /// Evicts least recently used objects until there's room for one more. An object stays
+    /// cached if writing it back fails.
+    fn make_room(&mut self) -> Result<(), Error<S::Error>> {
+        while self.objects.len() >= self.capacity {
+            let Some((&used, id)) = self.lru.first_key_value() else {
+                break;
+            };
+            let id = id.clone();
+
+            // With syncs deferred, dirty objects can only be written back in order, all at once.
+            if self.deferred && self.objects.get(&id).is_some_and(|entry| entry.dirty) {
+                self.flush()?;
+            } else {
+                self.write_back(&id)?;
+            }
+
+            self.lru.remove(&used);
+            self.objects.remove(&id);
+        }
+        Ok(())
+    }
+
+    /// Caches `data` as the contents of object `id`.
+    fn insert(
+        &mut self,
+        id: &S::Id,
+        data: Vec<u8>,
+        dirty: bool,
+        stored: bool,
+    ) -> Result<(), Error<S::Error>> {
+        if let Some(entry) = self.objects.get_mut(id) {
+            entry.data = data;
+            entry.dirty |= dirty;
+        } else {
+            self.make_room()?;
+            self.objects.insert(
+                id.clone(),
+                Entry {
+                    data,
+                    dirty,
+                    stored,
+                    used: 0,
+                },
+            );
+        }
+        self.touch(id);
+        Ok(())
+    }
+
+    /// Brings object `id` into the cache, as an empty object if it doesn't exist and `create` is
+    /// set.
+    fn fetch(&mut self, id: &S::Id, create: bool) -> Result<&mut Entry, Error<S::Error>> {
+        if self.removed.contains(id) {
+            // The inner storage still has the object, which is now replaced rather than removed.
+            if !create {
+                return Err(Error::NotFound);
+            }
+            self.removed.remove(id);
+            self.insert(id, vec![], true, true)?;
+        } else if !self.objects.contains_key(id) {
+            let (data, stored) = match self.inner.size(id) {
+                Ok(size) => {
+                    let mut data = vec![0; size as usize];
+                    self.inner
+                        .read_handle(id)
+                        .map_err(Error::Inner)?
+                        .read_exact(&mut data)
+                        .map_err(|_| Error::Io)?;
+                    (data, true)
+                }
+                // Storage has no size for an object that doesn't exist.
+                Err(_) if create => (vec![], false),
+                Err(err) => return Err(Error::Inner(err)),
+            };
+            self.insert(id, data, false, stored)?;
+        } else {
+            self.touch(id);
+        }
+
+        Ok(self.objects.get_mut(id).unwrap())
+    }
+
+    /// Removes object `id` from the inner storage, or holds the removal back until the next
+    /// flush if syncs are deferred.
+    fn remove_stored(&mut self, id: &S::Id) -> Result<(), Error<S::Error>> {
+        if self.deferred {
+            self.inner.size(id).map_err(Error::Inner)?;
+            self.removed.insert(id.clone());
+            return Ok(());
+        }
+        self.inner.remove_id(id).map_err(Error::Inner)
+    }
+}
+
+impl<S> Storage for CachedStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    type Id = S::Id;
+    type Error = Error<S::Error>;
+    type ReadHandle<'a>
+        = Handle<'a>
+    where
+        S: 'a;
+    type WriteHandle<'a>
+        = Handle<'a>
+    where
+        S: 'a;
+    type RwHandle<'a>
+        = Handle<'a>
+    where
+        S: 'a;
+
+    fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
+        // The inner storage doesn't know about objects that haven't been written back yet, so
+        // skip over IDs that still name one.
+        loop {
+            let id = self.inner.alloc_id().map_err(Error::Inner)?;
+            if !self.objects.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        if self.deferred {
+            self.freed.push(id);
+            return Ok(());
+        }
+        self.inner.dealloc_id(id).map_err(Error::Inner)
+    }
+
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        if self.removed.contains(id) {
+            return Err(Error::NotFound);
+        }
+
+        match self.objects.remove(id) {
+            Some(entry) => {
+                self.lru.remove(&entry.used);
+                if entry.stored {
+                    self.remove_stored(id)?;
+                }
+                Ok(())
+            }
+            None => self.remove_stored(id),
+        }
+    }
+
+    fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
+        let entry = self.fetch(id, true)?;
+        entry.data.resize(size as usize, 0);
+        entry.dirty = true;
+        Ok(())
+    }
+
+    fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error> {
+        Ok(Handle::new(&mut self.fetch(id, false)?.data))
+    }
+
+    fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let entry = self.fetch(id, true)?;
+        entry.dirty = true;
+        Ok(Handle::new(&mut entry.data))
+    }
+
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let data = self.staged.entry(id.clone()).or_default();
+        data.clear();
+        Ok(Handle::new(data))
+    }
+
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        let data = self.staged.remove(id).ok_or(Error::NotStaged)?;
+        let stored = self.removed.remove(id)
+            || self.objects.get(id).is_some_and(|entry| entry.stored)
+            || self.inner.size(id).is_ok();
+        self.insert(id, data, true, stored)
+    }
+
+    fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error> {
+        let entry = self.fetch(id, true)?;
+        entry.dirty = true;
+        Ok(Handle::new(&mut entry.data))
+    }
+
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        let mut ids = self.inner.ids().map_err(Error::Inner)?;
+        ids.retain(|id| !self.removed.contains(id));
+        ids.extend(
+            self.objects
+                .iter()
+                .filter(|(_, entry)| !entry.stored)
+                .map(|(id, _)| id.clone()),
+        );
+        Ok(ids)
+    }
+
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        match self.objects.get(id) {
+            Some(entry) => Ok(entry.data.len() as u64),
+            None if self.removed.contains(id) => Err(Error::NotFound),
+            None => self.inner.size(id).map_err(Error::Inner),
+        }
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        if self.deferred {
+            return Ok(());
+        }
+        self.flush()?;
+        self.inner.sync().map_err(Error::Inner)
+    }
+
+    fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        if self.deferred {
+            return Ok(());
+        }
+        self.write_back(id)?;
+        self.inner.sync_id(id).map_err(Error::Inner)
+    }
+
+    fn defers_syncs(&self) -> bool {
+        self.deferred || self.inner.defers_syncs()
+    }
+}
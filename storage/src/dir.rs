@@ -1,15 +1,37 @@
 use crate::Storage;
 use allocator::{seq::SequentialAllocator, Allocator};
 use embedded_io::adapters::FromStd;
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
 use std::{
-    fs::{self, File},
-    io,
+    collections::HashSet,
+    fs::{self, DirBuilder, File, OpenOptions, TryLockError},
+    io::{self, Read, Write},
+    path::Path,
 };
 use thiserror::Error;
 
+const LAYOUT_FILE: &str = ".layout";
+
+/// How objects are arranged under the root directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    /// Every object sits directly in the root directory, as `{root}/{id}`.
+    Flat,
+    /// Objects are spread over 1 to 8 `levels` of subdirectories, each named by one of the ID's
+    /// low bytes in hex. With two levels, object `0x1234` is `{root}/34/12/4660`.
+    FanOut { levels: u8 },
+}
+
 pub struct DirectoryStorage {
     root: String,
+    layout: Layout,
     allocator: SequentialAllocator<u64>,
+    // Objects written in place since they were last synced.
+    dirty: HashSet<u64>,
+    read_only: bool,
+    // Held for as long as the storage is open; the lock is released when the file is closed.
+    _lock: File,
 }
 
 #[derive(Debug, Error)]
@@ -22,21 +44,344 @@ pub enum Error {
 
     #[error("couldn't deallocate ID: {0}")]
     Dealloc(u64),
+
+    #[error("storage directory {0} is already locked")]
+    Locked(String),
+
+    #[error("storage is open read-only")]
+    ReadOnly,
+
+    #[error("invalid layout file in {0}")]
+    Layout(String),
+
+    #[error("storage path {0} is writable by group or others")]
+    Permissions(String),
 }
 
 impl DirectoryStorage {
+    /// Opens the directory at `root`, creating it if needed, and locks it exclusively.
     pub fn new(root: &str) -> Result<Self, Error> {
-        fs::create_dir_all(root)?;
+        Self::with_layout(root, Layout::Flat)
+    }
+
+    /// Like `new()`, but lays out a fresh directory with `layout`. An existing directory keeps
+    /// the layout it was created with.
+    pub fn with_layout(root: &str, layout: Layout) -> Result<Self, Error> {
+        if let Layout::FanOut { levels: 0 | 9.. } = layout {
+            return Err(Error::Layout(root.into()));
+        }
+
+        create_dir(root, true)?;
+        Self::open(root, layout, false)
+    }
+
+    /// Opens an existing directory at `root` without the ability to modify it. Any number of
+    /// read-only openers can share the directory, but not with a writer.
+    pub fn open_read_only(root: &str) -> Result<Self, Error> {
+        Self::open(root, Layout::Flat, true)
+    }
 
-        Ok(Self {
+    pub fn layout(&self) -> Layout {
+        self.layout
+    }
+
+    fn open(root: &str, layout: Layout, read_only: bool) -> Result<Self, Error> {
+        check_permissions(root, &fs::metadata(root)?)?;
+
+        // A reader only needs the lock file to take a shared lock on, which every writer leaves
+        // behind, so it neither creates nor opens it for writing.
+        let path = format!("{root}/.lock");
+        let res = if read_only {
+            let lock = File::open(&path)?;
+            lock.try_lock_shared().map(|()| lock)
+        } else {
+            let lock = file_options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(&path)?;
+            lock.try_lock().map(|()| lock)
+        };
+
+        let lock = match res {
+            Ok(lock) => lock,
+            Err(TryLockError::WouldBlock) => return Err(Error::Locked(root.into())),
+            Err(TryLockError::Error(err)) => return Err(err.into()),
+        };
+
+        let layout = Self::detect_layout(root, layout, read_only)?;
+        check_contents(root, layout)?;
+
+        let storage = Self {
             root: root.into(),
+            layout,
             allocator: SequentialAllocator::new(),
-        })
+            dirty: HashSet::new(),
+            read_only,
+            _lock: lock,
+        };
+        if !read_only {
+            storage.remove_pending()?;
+        }
+
+        Ok(storage)
+    }
+
+    /// Removes the replacements left behind by a crash before they were committed. Nothing
+    /// refers to them, and the lock keeps anyone else from having one in progress.
+    fn remove_pending(&self) -> Result<(), Error> {
+        let mut pending = vec![];
+        walk_files(&self.root, self.levels(), &mut |name, path| {
+            if name.strip_suffix(".tmp").and_then(object_id).is_some() {
+                pending.push(path);
+            }
+        })?;
+
+        // They may hold anything an object was about to be replaced with, so they're overwritten
+        // with zeros before they're removed.
+        for path in pending {
+            let mut file = OpenOptions::new().write(true).open(&path)?;
+            let size = file.metadata()?.len();
+            io::copy(&mut io::repeat(0).take(size), &mut file)?;
+            file.sync_all()?;
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Reads the layout recorded in the directory, recording `layout` if there isn't one yet.
+    /// Directories from before layouts were recorded are flat.
+    fn detect_layout(root: &str, layout: Layout, read_only: bool) -> Result<Layout, Error> {
+        let path = format!("{root}/{LAYOUT_FILE}");
+
+        match fs::read_to_string(&path) {
+            Ok(recorded) => {
+                return match recorded.split_whitespace().collect::<Vec<_>>()[..] {
+                    ["flat"] => Ok(Layout::Flat),
+                    ["fanout", levels] => match levels.parse() {
+                        Ok(levels @ 1..=8) => Ok(Layout::FanOut { levels }),
+                        _ => Err(Error::Layout(root.into())),
+                    },
+                    _ => Err(Error::Layout(root.into())),
+                };
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err.into()),
+        }
+
+        let mut existing = vec![];
+        collect_ids(root, 0, &mut existing)?;
+        let layout = if existing.is_empty() {
+            layout
+        } else {
+            Layout::Flat
+        };
+
+        if !read_only {
+            let recorded = match layout {
+                Layout::Flat => "flat\n".to_string(),
+                Layout::FanOut { levels } => format!("fanout {levels}\n"),
+            };
+            let mut file = file_options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&path)?;
+            file.write_all(recorded.as_bytes())?;
+            file.sync_all()?;
+            File::open(root)?.sync_all()?;
+        }
+
+        Ok(layout)
+    }
+
+    pub(crate) fn root(&self) -> &str {
+        &self.root
+    }
+
+    pub(crate) fn check_writable(&self) -> Result<(), Error> {
+        if self.read_only {
+            Err(Error::ReadOnly)
+        } else {
+            Ok(())
+        }
+    }
+
+    pub(crate) fn levels(&self) -> u8 {
+        match self.layout {
+            Layout::Flat => 0,
+            Layout::FanOut { levels } => levels,
+        }
+    }
+
+    /// Returns the directories leading to object `id`, from the root to the one holding it.
+    pub(crate) fn dirs(&self, id: u64) -> Vec<String> {
+        let mut dirs = vec![self.root.clone()];
+        for level in 0..self.levels() {
+            let byte = (id >> (8 * level)) & 0xff;
+            dirs.push(format!("{}/{byte:02x}", dirs[dirs.len() - 1]));
+        }
+        dirs
+    }
+
+    /// Allocates an ID without checking whether it already names an object on disk.
+    pub(crate) fn next_id(&mut self) -> Result<u64, Error> {
+        self.allocator.alloc().map_err(|_| Error::Alloc)
+    }
+
+    /// Records that object `id` was written in place and needs syncing.
+    pub(crate) fn mark_dirty(&mut self, id: u64) {
+        self.dirty.insert(id);
+    }
+
+    /// Records that object `id` is synced or gone.
+    pub(crate) fn mark_clean(&mut self, id: u64) {
+        self.dirty.remove(&id);
+    }
+
+    pub(crate) fn is_dirty(&self, id: u64) -> bool {
+        self.dirty.contains(&id)
+    }
+
+    /// Returns the objects that need syncing, and the directories to sync after them: the root
+    /// and each one holding such an object.
+    pub(crate) fn pending_sync(&self) -> (Vec<u64>, HashSet<String>) {
+        let mut dirs = HashSet::from([self.root.clone()]);
+        for &id in &self.dirty {
+            dirs.extend(self.dirs(id).pop());
+        }
+        (self.dirty.iter().copied().collect(), dirs)
+    }
+
+    /// Creates any missing directories leading to object `id`, making each new one durable.
+    /// Those already there are checked, since they may have turned up since the storage was
+    /// opened.
+    fn create_dirs(&self, id: u64) -> Result<(), Error> {
+        for pair in self.dirs(id).windows(2) {
+            match fs::metadata(&pair[1]) {
+                Ok(metadata) => check_permissions(&pair[1], &metadata)?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                    create_dir(&pair[1], false)?;
+                    File::open(&pair[0])?.sync_all()?;
+                }
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(())
+    }
+
+    pub(crate) fn canonicalize(&self, id: u64) -> String {
+        format!("{}/{}", self.dirs(id).last().unwrap(), id)
+    }
+
+    pub(crate) fn canonicalize_tmp(&self, id: u64) -> String {
+        format!("{}/{}.tmp", self.dirs(id).last().unwrap(), id)
+    }
+}
+
+/// Returns options that create files readable and writable only by their owner.
+pub(crate) fn file_options() -> OpenOptions {
+    let mut options = File::options();
+    #[cfg(unix)]
+    options.mode(0o600);
+    options
+}
+
+/// Creates a directory that only its owner can access.
+fn create_dir(path: &str, recursive: bool) -> io::Result<()> {
+    let mut builder = DirBuilder::new();
+    builder.recursive(recursive);
+    #[cfg(unix)]
+    builder.mode(DIR_MODE);
+    builder.create(path)
+}
+
+/// The permissions of the directories the storage creates.
+#[cfg(unix)]
+pub(crate) const DIR_MODE: u32 = 0o700;
+
+/// Returns the ID an entry's name stands for, if it names an object. Anything else, like a
+/// pending replacement, isn't one.
+pub(crate) fn object_id(name: &str) -> Option<u64> {
+    name.parse().ok()
+}
+
+/// Returns whether an entry's name fits a subdirectory of a fan-out layout.
+pub(crate) fn is_fan_out_dir(name: &str) -> bool {
+    name.len() == 2 && u8::from_str_radix(name, 16).is_ok()
+}
+
+/// Refuses a directory that others could plant or swap objects in, or a file they could
+/// rewrite, given its metadata.
+pub(crate) fn check_permissions(path: &str, metadata: &fs::Metadata) -> Result<(), Error> {
+    #[cfg(unix)]
+    if metadata.permissions().mode() & 0o022 != 0 {
+        return Err(Error::Permissions(path.into()));
+    }
+    Ok(())
+}
+
+/// Checks the permissions of everything the storage keeps under `root`: the layout file, the
+/// subdirectories of a fan-out layout and the objects in them.
+fn check_contents(root: &str, layout: Layout) -> Result<(), Error> {
+    let path = format!("{root}/{LAYOUT_FILE}");
+    match fs::metadata(&path) {
+        Ok(metadata) => check_permissions(&path, &metadata)?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err.into()),
+    }
+
+    let levels = match layout {
+        Layout::Flat => 0,
+        Layout::FanOut { levels } => levels,
+    };
+    check_dir(root, levels)
+}
+
+/// Checks the permissions of the objects `levels` directories below `dir`, and of the
+/// subdirectories on the way.
+fn check_dir(dir: &str, levels: u8) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+
+        let path = format!("{dir}/{name}");
+        if levels == 0 {
+            if object_id(&name).is_some() {
+                check_permissions(&path, &fs::metadata(&path)?)?;
+            }
+        } else if is_fan_out_dir(&name) && entry.file_type()?.is_dir() {
+            check_permissions(&path, &fs::metadata(&path)?)?;
+            check_dir(&path, levels - 1)?;
+        }
     }
+    Ok(())
+}
+
+/// Collects the IDs of the objects `levels` directories below `dir`.
+fn collect_ids(dir: &str, levels: u8, ids: &mut Vec<u64>) -> Result<(), Error> {
+    walk_files(dir, levels, &mut |name, _| ids.extend(object_id(name)))
+}
 
-    fn canonicalize(&self, id: u64) -> String {
-        format!("{}/{}", self.root, id)
+/// Calls `f` with the name and path of every entry `levels` directories below `dir`, going
+/// through the subdirectories that a fan-out layout creates.
+fn walk_files(dir: &str, levels: u8, f: &mut impl FnMut(&str, String)) -> Result<(), Error> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let Some(name) = entry.file_name().to_str().map(String::from) else {
+            continue;
+        };
+
+        if levels == 0 {
+            f(&name, format!("{dir}/{name}"));
+        } else if is_fan_out_dir(&name) && entry.file_type()?.is_dir() {
+            walk_files(&format!("{dir}/{name}"), levels - 1, f)?;
+        }
     }
+    Ok(())
 }
 
 impl Storage for DirectoryStorage {
@@ -47,17 +392,37 @@ impl Storage for DirectoryStorage {
     type RwHandle<'a> = FromStd<File>;
 
     fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
-        self.allocator.alloc().map_err(|_| Error::Alloc)
+        self.check_writable()?;
+
+        // The allocator starts fresh every time the directory is opened, so skip over any IDs
+        // that already name an object on disk.
+        loop {
+            let id = self.next_id()?;
+            if !Path::new(&self.canonicalize(id)).exists() {
+                return Ok(id);
+            }
+        }
     }
 
     fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        self.check_writable()?;
         self.allocator.dealloc(id).map_err(|_| Error::Dealloc(id))
     }
 
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.check_writable()?;
+        self.mark_clean(*id);
+        Ok(fs::remove_file(self.canonicalize(*id))?)
+    }
+
     fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
-        Ok(File::options()
+        self.check_writable()?;
+        self.create_dirs(*id)?;
+        self.mark_dirty(*id);
+        Ok(file_options()
             .write(true)
             .create(true)
+            .truncate(false)
             .open(self.canonicalize(*id))?
             .set_len(size)?)
     }
@@ -69,21 +434,86 @@ impl Storage for DirectoryStorage {
     }
 
     fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        self.check_writable()?;
+        self.create_dirs(*id)?;
+        self.mark_dirty(*id);
         Ok(FromStd::new(
-            File::options()
+            file_options()
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(self.canonicalize(*id))?,
         ))
     }
 
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        self.check_writable()?;
+        self.create_dirs(*id)?;
+        Ok(FromStd::new(
+            file_options()
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(self.canonicalize_tmp(*id))?,
+        ))
+    }
+
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.check_writable()?;
+
+        // Make sure the new contents are durable before they replace the old ones, and that the
+        // rename itself is durable before returning.
+        File::open(self.canonicalize_tmp(*id))?.sync_all()?;
+        fs::rename(self.canonicalize_tmp(*id), self.canonicalize(*id))?;
+        File::open(self.dirs(*id).last().unwrap())?.sync_all()?;
+        Ok(())
+    }
+
     fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        self.check_writable()?;
+        self.create_dirs(*id)?;
+        self.mark_dirty(*id);
         Ok(FromStd::new(
-            File::options()
+            file_options()
                 .read(true)
                 .write(true)
                 .create(true)
+                .truncate(false)
                 .open(self.canonicalize(*id))?,
         ))
     }
+
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        let mut ids = vec![];
+        collect_ids(&self.root, self.levels(), &mut ids)?;
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        Ok(fs::metadata(self.canonicalize(*id))?.len())
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        // Objects written in place are tracked as dirty; replaced objects are already durable
+        // once committed.
+        let (ids, dirs) = self.pending_sync();
+        for id in ids {
+            self.sync_id(&id)?;
+        }
+        for dir in dirs {
+            File::open(dir)?.sync_all()?;
+        }
+        Ok(())
+    }
+
+    fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        // Committed replacements are durable already, so this only ever has in-place writes to
+        // flush.
+        if self.is_dirty(*id) {
+            File::open(self.canonicalize(*id))?.sync_all()?;
+            self.mark_clean(*id);
+        }
+        Ok(())
+    }
 }
@@ -0,0 +1,345 @@
+use crate::Storage;
+use embedded_io::{
+    blocking::{Read, Seek, Write},
+    Error as _, ErrorKind, Io, SeekFrom,
+};
+use std::{
+    collections::{HashMap, HashSet},
+    hash::Hash,
+};
+use thiserror::Error;
+
+/// Kinds of operations that faults can be injected into.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Op {
+    /// Opening an object for reading.
+    Read,
+    /// Anything that changes an object: opening it for writing, committing a replacement,
+    /// truncating or removing it.
+    Write,
+    /// Allocating an ID.
+    Alloc,
+    /// Deallocating an ID.
+    Dealloc,
+}
+
+/// A fault waiting for its operation to come around.
+struct Fault {
+    op: Op,
+    at: u64,
+    // For torn writes, how many bytes get through before the handle starts failing.
+    tear_after: Option<usize>,
+}
+
+/// Storage that fails or tears chosen operations, and that can simulate a crash by dropping
+/// every change made since the last sync.
+pub struct FaultyStorage<S: Storage> {
+    inner: S,
+    counts: HashMap<Op, u64>,
+    faults: Vec<Fault>,
+    // The contents of each object changed since the last sync, as of that sync.
+    unsynced: HashMap<S::Id, Option<Vec<u8>>>,
+    // Objects with a replacement staged since the last crash that hasn't been committed.
+    staged: HashSet<S::Id>,
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("injected fault")]
+    Injected,
+
+    #[error("I/O error while simulating a crash")]
+    Io,
+
+    #[error("no replacement staged since the last crash")]
+    NotStaged,
+
+    #[error(transparent)]
+    Inner(E),
+}
+
+/// A handle that passes through to the inner storage's handle, possibly tearing writes.
+pub struct Handle<H> {
+    inner: H,
+    tear_after: Option<usize>,
+}
+
+impl<S> FaultyStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            counts: HashMap::new(),
+            faults: vec![],
+            unsynced: HashMap::new(),
+            staged: HashSet::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns how many operations of kind `op` have been attempted.
+    pub fn count(&self, op: Op) -> u64 {
+        self.counts.get(&op).copied().unwrap_or_default()
+    }
+
+    /// Makes the `n`th operation of kind `op` from now fail, counting from 0.
+    pub fn fail_nth(&mut self, op: Op, n: u64) {
+        self.faults.push(Fault {
+            op,
+            at: self.count(op) + n,
+            tear_after: None,
+        });
+    }
+
+    /// Makes the write handle returned by the `n`th write from now accept only `bytes` bytes
+    /// before failing, counting from 0.
+    pub fn tear_nth_write(&mut self, n: u64, bytes: usize) {
+        self.faults.push(Fault {
+            op: Op::Write,
+            at: self.count(Op::Write) + n,
+            tear_after: Some(bytes),
+        });
+    }
+
+    /// Cancels any faults that haven't triggered yet.
+    pub fn clear_faults(&mut self) {
+        self.faults.clear();
+    }
+
+    /// Simulates a crash by restoring every object changed since the last sync to how it was
+    /// then, and dropping every replacement that wasn't committed. Pending faults are cleared.
+    pub fn crash(&mut self) -> Result<(), Error<S::Error>> {
+        self.faults.clear();
+        self.staged.clear();
+
+        for (id, contents) in self.unsynced.drain() {
+            match contents {
+                Some(contents) => {
+                    self.inner.truncate_id(&id, 0).map_err(Error::Inner)?;
+                    self.inner
+                        .write_handle(&id)
+                        .map_err(Error::Inner)?
+                        .write_all(&contents)
+                        .map_err(|_| Error::Io)?;
+                }
+                None => {
+                    if self.inner.size(&id).is_ok() {
+                        self.inner.remove_id(&id).map_err(Error::Inner)?;
+                    }
+                }
+            }
+        }
+
+        self.inner.sync().map_err(Error::Inner)
+    }
+
+    /// Counts an operation, returning the fault to inject into it, if any.
+    fn step(&mut self, op: Op) -> Result<Option<usize>, Error<S::Error>> {
+        let count = self.counts.entry(op).or_default();
+        let at = *count;
+        *count += 1;
+
+        match self.faults.iter().position(|f| f.op == op && f.at == at) {
+            Some(idx) => match self.faults.remove(idx).tear_after {
+                Some(bytes) => Ok(Some(bytes)),
+                None => Err(Error::Injected),
+            },
+            None => Ok(None),
+        }
+    }
+
+    /// Remembers what object `id` held as of the last sync, before it's first changed.
+    fn preserve(&mut self, id: &S::Id) -> Result<(), Error<S::Error>> {
+        if self.unsynced.contains_key(id) {
+            return Ok(());
+        }
+
+        // Storage has no size for an object that doesn't exist.
+        let contents = if let Ok(size) = self.inner.size(id) {
+            let mut contents = vec![0; size as usize];
+            self.inner
+                .read_handle(id)
+                .map_err(Error::Inner)?
+                .read_exact(&mut contents)
+                .map_err(|_| Error::Io)?;
+            Some(contents)
+        } else {
+            None
+        };
+
+        self.unsynced.insert(id.clone(), contents);
+
+        Ok(())
+    }
+}
+
+impl<S> Storage for FaultyStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    type Id = S::Id;
+    type Error = Error<S::Error>;
+    type ReadHandle<'a>
+        = Handle<S::ReadHandle<'a>>
+    where
+        S: 'a;
+    type WriteHandle<'a>
+        = Handle<S::WriteHandle<'a>>
+    where
+        S: 'a;
+    type RwHandle<'a>
+        = Handle<S::RwHandle<'a>>
+    where
+        S: 'a;
+
+    fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
+        self.step(Op::Alloc)?;
+        self.inner.alloc_id().map_err(Error::Inner)
+    }
+
+    fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        self.step(Op::Dealloc)?;
+        self.inner.dealloc_id(id).map_err(Error::Inner)
+    }
+
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.step(Op::Write)?;
+        self.preserve(id)?;
+        self.inner.remove_id(id).map_err(Error::Inner)
+    }
+
+    fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
+        self.step(Op::Write)?;
+        self.preserve(id)?;
+        self.inner.truncate_id(id, size).map_err(Error::Inner)
+    }
+
+    fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error> {
+        self.step(Op::Read)?;
+        Ok(Handle::new(
+            self.inner.read_handle(id).map_err(Error::Inner)?,
+            None,
+        ))
+    }
+
+    fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let tear_after = self.step(Op::Write)?;
+        self.preserve(id)?;
+        Ok(Handle::new(
+            self.inner.write_handle(id).map_err(Error::Inner)?,
+            tear_after,
+        ))
+    }
+
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        // The object itself only changes once the replacement is committed.
+        let tear_after = self.step(Op::Write)?;
+        self.staged.insert(id.clone());
+        Ok(Handle::new(
+            self.inner.replace_handle(id).map_err(Error::Inner)?,
+            tear_after,
+        ))
+    }
+
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.step(Op::Write)?;
+
+        // The inner storage may still hold a replacement staged before a crash, which a real
+        // crash would have lost.
+        if !self.staged.contains(id) {
+            return Err(Error::NotStaged);
+        }
+
+        self.preserve(id)?;
+        self.inner.commit_handle(id).map_err(Error::Inner)?;
+        self.staged.remove(id);
+        Ok(())
+    }
+
+    fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error> {
+        let tear_after = self.step(Op::Write)?;
+        self.preserve(id)?;
+        Ok(Handle::new(
+            self.inner.rw_handle(id).map_err(Error::Inner)?,
+            tear_after,
+        ))
+    }
+
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        self.inner.ids().map_err(Error::Inner)
+    }
+
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        self.inner.size(id).map_err(Error::Inner)
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.inner.sync().map_err(Error::Inner)?;
+        self.unsynced.clear();
+        Ok(())
+    }
+
+    fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.inner.sync_id(id).map_err(Error::Inner)?;
+        self.unsynced.remove(id);
+        Ok(())
+    }
+
+    fn defers_syncs(&self) -> bool {
+        self.inner.defers_syncs()
+    }
+}
+
+impl<H> Handle<H> {
+    fn new(inner: H, tear_after: Option<usize>) -> Self {
+        Self { inner, tear_after }
+    }
+}
+
+impl<H: Io> Io for Handle<H> {
+    type Error = ErrorKind;
+}
+
+impl<H: Read> Read for Handle<H> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.inner.read(buf).map_err(|err| err.kind())
+    }
+}
+
+impl<H: Write> Write for Handle<H> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let buf = match self.tear_after {
+            Some(0) => return Err(ErrorKind::Other),
+            Some(remaining) => &buf[..buf.len().min(remaining)],
+            None => buf,
+        };
+
+        let n = self.inner.write(buf).map_err(|err| err.kind())?;
+        if let Some(remaining) = &mut self.tear_after {
+            *remaining -= n;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush().map_err(|err| err.kind())
+    }
+}
+
+impl<H: Seek> Seek for Handle<H> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.inner.seek(pos).map_err(|err| err.kind())
+    }
+}
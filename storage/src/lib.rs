@@ -1,5 +1,15 @@
+#[cfg(feature = "async")]
+pub mod asynch;
+#[cfg(feature = "cache")]
+pub mod cache;
 #[cfg(feature = "dir")]
 pub mod dir;
+#[cfg(feature = "fault")]
+pub mod fault;
+#[cfg(feature = "mem")]
+pub mod mem;
+#[cfg(feature = "metrics")]
+pub mod metrics;
 
 use embedded_io::blocking::{Read, Seek, Write};
 use std::error::Error;
@@ -33,6 +43,9 @@ pub trait Storage {
     // FIXME: have this take a reference to id
     fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error>;
 
+    /// Removes object `id` from storage. Its ID stays allocated until it's deallocated.
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error>;
+
     /// Truncates an object `id` to `size` bytes.
     fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error>;
 
@@ -40,8 +53,41 @@ pub trait Storage {
     fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error>;
 
     /// Returns a handle to write data to object `id`.
+    ///
+    /// Writes overwrite the object in place, so bytes past the end of what is written are kept.
     fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error>;
 
+    /// Returns a handle to write the new contents of object `id`, discarding its old contents.
+    ///
+    /// The new contents only take effect once [`Storage::commit_handle`] is called; until then,
+    /// the object reads back as it was.
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error>;
+
+    /// Atomically swaps in the contents written through the last replace handle for object `id`.
+    ///
+    /// After a crash, the object holds either its old contents or all of its new ones. Which
+    /// one is only certain once the object is synced, though storage may commit durably, making
+    /// the sync a no-op.
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error>;
+
     /// Returns a handle to read from/write to object `id`.
     fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error>;
+
+    /// Returns the IDs of every object in storage.
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error>;
+
+    /// Returns the size of object `id` in bytes.
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error>;
+
+    /// Forces every write made so far to stable storage.
+    fn sync(&mut self) -> Result<(), Self::Error>;
+
+    /// Forces every write made so far to object `id` to stable storage.
+    fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error>;
+
+    /// Returns whether syncs are held back, so that a sync doesn't make anything durable until
+    /// some later point. Nothing can be kept in step with such storage, like a trusted counter.
+    fn defers_syncs(&self) -> bool {
+        false
+    }
 }
@@ -0,0 +1,180 @@
+use crate::Storage;
+use embedded_io::{
+    blocking::{Read, Seek, Write},
+    ErrorKind, Io, SeekFrom,
+};
+use std::collections::{BTreeSet, HashMap};
+use thiserror::Error;
+
+/// Storage that keeps every object in memory, for tests and fuzzing.
+#[derive(Default)]
+pub struct MemoryStorage {
+    objects: HashMap<u64, Vec<u8>>,
+    staged: HashMap<u64, Vec<u8>>,
+    // A set, so that deallocating an ID twice can't have it handed out twice.
+    free: BTreeSet<u64>,
+    next_id: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("no such object: {0}")]
+    NotFound(u64),
+
+    #[error("no replacement staged for object: {0}")]
+    NotStaged(u64),
+
+    #[error("couldn't allocate ID")]
+    Alloc,
+}
+
+/// A cursor over the bytes of an object held in memory, by this storage or by a cache.
+pub struct Handle<'a> {
+    data: &'a mut Vec<u8>,
+    pos: usize,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl Storage for MemoryStorage {
+    type Id = u64;
+    type Error = Error;
+    type ReadHandle<'a> = Handle<'a>;
+    type WriteHandle<'a> = Handle<'a>;
+    type RwHandle<'a> = Handle<'a>;
+
+    fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
+        // Like `DirectoryStorage`, deallocating an ID doesn't remove its object, so skip over IDs
+        // that still name one.
+        while let Some(id) = self.free.pop_last() {
+            if !self.objects.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+
+        loop {
+            let id = self.next_id;
+            self.next_id = self.next_id.checked_add(1).ok_or(Error::Alloc)?;
+            if !self.objects.contains_key(&id) {
+                return Ok(id);
+            }
+        }
+    }
+
+    fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        self.free.insert(id);
+        Ok(())
+    }
+
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.objects.remove(id).ok_or(Error::NotFound(*id))?;
+        Ok(())
+    }
+
+    fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
+        self.objects
+            .entry(*id)
+            .or_default()
+            .resize(size as usize, 0);
+        Ok(())
+    }
+
+    fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error> {
+        Ok(Handle::new(
+            self.objects.get_mut(id).ok_or(Error::NotFound(*id))?,
+        ))
+    }
+
+    fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        Ok(Handle::new(self.objects.entry(*id).or_default()))
+    }
+
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let data = self.staged.entry(*id).or_default();
+        data.clear();
+        Ok(Handle::new(data))
+    }
+
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        let data = self.staged.remove(id).ok_or(Error::NotStaged(*id))?;
+        self.objects.insert(*id, data);
+        Ok(())
+    }
+
+    fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error> {
+        Ok(Handle::new(self.objects.entry(*id).or_default()))
+    }
+
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        let mut ids: Vec<_> = self.objects.keys().copied().collect();
+        ids.sort_unstable();
+        Ok(ids)
+    }
+
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        Ok(self.objects.get(id).ok_or(Error::NotFound(*id))?.len() as u64)
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn sync_id(&mut self, _id: &Self::Id) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl<'a> Handle<'a> {
+    pub(crate) fn new(data: &'a mut Vec<u8>) -> Self {
+        Self { data, pos: 0 }
+    }
+}
+
+impl Io for Handle<'_> {
+    type Error = ErrorKind;
+}
+
+impl Read for Handle<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let available = self.data.get(self.pos..).unwrap_or_default();
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.pos += n;
+        Ok(n)
+    }
+}
+
+impl Write for Handle<'_> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        // Writing past the end zero-fills the gap, like a file would.
+        let end = self.pos + buf.len();
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        self.data[self.pos..end].copy_from_slice(buf);
+        self.pos = end;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl Seek for Handle<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        let pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(offset) => (self.data.len() as u64).checked_add_signed(offset),
+            SeekFrom::Current(offset) => (self.pos as u64).checked_add_signed(offset),
+        }
+        .ok_or(ErrorKind::Other)?;
+
+        self.pos = usize::try_from(pos).map_err(|_| ErrorKind::Other)?;
+        Ok(pos)
+    }
+}
@@ -0,0 +1,279 @@
+use crate::Storage;
+use embedded_io::{
+    blocking::{Read, Seek, Write},
+    Io, SeekFrom,
+};
+use std::{collections::HashMap, hash::Hash};
+
+/// I/O counts for a single object, or summed over several.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct Counters {
+    /// Number of handles opened to read the object, including read/write handles.
+    pub reads: u64,
+    /// Number of handles opened to write the object, including replace and read/write handles.
+    pub writes: u64,
+    /// Number of bytes read from the object.
+    pub bytes_read: u64,
+    /// Number of bytes written to the object.
+    pub bytes_written: u64,
+    /// Number of replacements of the object committed.
+    pub commits: u64,
+    /// Number of times the object was truncated.
+    pub truncates: u64,
+    /// Number of times the object was removed.
+    pub removes: u64,
+    /// Number of times the object's ID was allocated.
+    pub allocs: u64,
+    /// Number of times the object's ID was deallocated.
+    pub deallocs: u64,
+}
+
+/// The counters of every object touched, as of some point in time.
+#[derive(Debug, Clone)]
+pub struct Snapshot<Id> {
+    objects: HashMap<Id, Counters>,
+}
+
+/// Storage that counts the I/O done on each object.
+pub struct CountingStorage<S: Storage> {
+    inner: S,
+    objects: HashMap<S::Id, Counters>,
+}
+
+/// A handle that passes through to the inner storage's handle, counting the bytes it moves.
+pub struct Handle<'a, H> {
+    inner: H,
+    counters: &'a mut Counters,
+}
+
+impl Counters {
+    // Saturating, so that a snapshot taken before the counts were reset can't underflow.
+    fn since(&self, earlier: &Self) -> Self {
+        Self {
+            reads: self.reads.saturating_sub(earlier.reads),
+            writes: self.writes.saturating_sub(earlier.writes),
+            bytes_read: self.bytes_read.saturating_sub(earlier.bytes_read),
+            bytes_written: self.bytes_written.saturating_sub(earlier.bytes_written),
+            commits: self.commits.saturating_sub(earlier.commits),
+            truncates: self.truncates.saturating_sub(earlier.truncates),
+            removes: self.removes.saturating_sub(earlier.removes),
+            allocs: self.allocs.saturating_sub(earlier.allocs),
+            deallocs: self.deallocs.saturating_sub(earlier.deallocs),
+        }
+    }
+
+    fn add(&mut self, other: &Self) {
+        self.reads += other.reads;
+        self.writes += other.writes;
+        self.bytes_read += other.bytes_read;
+        self.bytes_written += other.bytes_written;
+        self.commits += other.commits;
+        self.truncates += other.truncates;
+        self.removes += other.removes;
+        self.allocs += other.allocs;
+        self.deallocs += other.deallocs;
+    }
+}
+
+impl<Id> Snapshot<Id>
+where
+    Id: Clone + Eq + Hash,
+{
+    /// Returns the counters for object `id`.
+    pub fn object(&self, id: &Id) -> Counters {
+        self.objects.get(id).copied().unwrap_or_default()
+    }
+
+    /// Returns the IDs of the objects touched.
+    pub fn ids(&self) -> impl Iterator<Item = &Id> {
+        self.objects.keys()
+    }
+
+    /// Returns the counters summed over every object.
+    pub fn total(&self) -> Counters {
+        let mut total = Counters::default();
+        for counters in self.objects.values() {
+            total.add(counters);
+        }
+        total
+    }
+
+    /// Returns the I/O done between an `earlier` snapshot and this one.
+    pub fn since(&self, earlier: &Self) -> Self {
+        Self {
+            objects: self
+                .objects
+                .iter()
+                .map(|(id, counters)| (id.clone(), counters.since(&earlier.object(id))))
+                .filter(|(_, counters)| *counters != Counters::default())
+                .collect(),
+        }
+    }
+}
+
+impl<S> CountingStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            objects: HashMap::new(),
+        }
+    }
+
+    pub fn inner(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+
+    /// Returns the I/O counted so far.
+    pub fn snapshot(&self) -> Snapshot<S::Id> {
+        Snapshot {
+            objects: self.objects.clone(),
+        }
+    }
+
+    /// Forgets the I/O counted so far.
+    pub fn reset(&mut self) {
+        self.objects.clear();
+    }
+}
+
+impl<S> Storage for CountingStorage<S>
+where
+    S: Storage,
+    S::Id: Clone + Eq + Hash,
+{
+    type Id = S::Id;
+    type Error = S::Error;
+    type ReadHandle<'a>
+        = Handle<'a, S::ReadHandle<'a>>
+    where
+        S: 'a;
+    type WriteHandle<'a>
+        = Handle<'a, S::WriteHandle<'a>>
+    where
+        S: 'a;
+    type RwHandle<'a>
+        = Handle<'a, S::RwHandle<'a>>
+    where
+        S: 'a;
+
+    fn alloc_id(&mut self) -> Result<Self::Id, Self::Error> {
+        let id = self.inner.alloc_id()?;
+        self.objects.entry(id.clone()).or_default().allocs += 1;
+        Ok(id)
+    }
+
+    fn dealloc_id(&mut self, id: Self::Id) -> Result<(), Self::Error> {
+        self.objects.entry(id.clone()).or_default().deallocs += 1;
+        self.inner.dealloc_id(id)
+    }
+
+    fn remove_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.objects.entry(id.clone()).or_default().removes += 1;
+        self.inner.remove_id(id)
+    }
+
+    fn truncate_id(&mut self, id: &Self::Id, size: u64) -> Result<(), Self::Error> {
+        self.objects.entry(id.clone()).or_default().truncates += 1;
+        self.inner.truncate_id(id, size)
+    }
+
+    fn read_handle(&mut self, id: &Self::Id) -> Result<Self::ReadHandle<'_>, Self::Error> {
+        let counters = self.objects.entry(id.clone()).or_default();
+        counters.reads += 1;
+        Ok(Handle {
+            inner: self.inner.read_handle(id)?,
+            counters,
+        })
+    }
+
+    fn write_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let counters = self.objects.entry(id.clone()).or_default();
+        counters.writes += 1;
+        Ok(Handle {
+            inner: self.inner.write_handle(id)?,
+            counters,
+        })
+    }
+
+    fn replace_handle(&mut self, id: &Self::Id) -> Result<Self::WriteHandle<'_>, Self::Error> {
+        let counters = self.objects.entry(id.clone()).or_default();
+        counters.writes += 1;
+        Ok(Handle {
+            inner: self.inner.replace_handle(id)?,
+            counters,
+        })
+    }
+
+    fn commit_handle(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.objects.entry(id.clone()).or_default().commits += 1;
+        self.inner.commit_handle(id)
+    }
+
+    fn rw_handle(&mut self, id: &Self::Id) -> Result<Self::RwHandle<'_>, Self::Error> {
+        let counters = self.objects.entry(id.clone()).or_default();
+        counters.reads += 1;
+        counters.writes += 1;
+        Ok(Handle {
+            inner: self.inner.rw_handle(id)?,
+            counters,
+        })
+    }
+
+    fn ids(&self) -> Result<Vec<Self::Id>, Self::Error> {
+        self.inner.ids()
+    }
+
+    fn size(&self, id: &Self::Id) -> Result<u64, Self::Error> {
+        self.inner.size(id)
+    }
+
+    fn sync(&mut self) -> Result<(), Self::Error> {
+        self.inner.sync()
+    }
+
+    fn sync_id(&mut self, id: &Self::Id) -> Result<(), Self::Error> {
+        self.inner.sync_id(id)
+    }
+
+    fn defers_syncs(&self) -> bool {
+        self.inner.defers_syncs()
+    }
+}
+
+impl<H: Io> Io for Handle<'_, H> {
+    type Error = H::Error;
+}
+
+impl<H: Read> Read for Handle<'_, H> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.read(buf)?;
+        self.counters.bytes_read += n as u64;
+        Ok(n)
+    }
+}
+
+impl<H: Write> Write for Handle<'_, H> {
+    fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        let n = self.inner.write(buf)?;
+        self.counters.bytes_written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+}
+
+impl<H: Seek> Seek for Handle<'_, H> {
+    fn seek(&mut self, pos: SeekFrom) -> Result<u64, Self::Error> {
+        self.inner.seek(pos)
+    }
+}